@@ -1,3 +1,7 @@
+pub mod clint;
+pub mod driver;
 pub mod pci;
+pub mod plic;
+pub mod syscon;
 pub mod uart;
 pub mod vga;
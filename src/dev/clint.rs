@@ -0,0 +1,260 @@
+//! Core Local Interruptor: the machine timer and software interrupts.
+//! https://github.com/riscv/riscv-aclint/blob/main/riscv-aclint.adoc
+//!
+//! Each hart has one `mtimecmp`, so the periodic tick and any one-shot
+//! deadlines are multiplexed onto it: whichever is due first gets armed.
+//! The registers belong to M-mode. The kernel reads the `time` CSR and
+//! arms the comparator through SBI; only the firmware pokes them directly.
+use crate::plt;
+use crate::sbi;
+use crate::srv::console::{self, Command};
+use crate::print;
+use crate::println;
+use crate::util::interrupt;
+
+const MSIP_OFFSET: usize = 0x0;
+const MTIMECMP_OFFSET: usize = 0x4000;
+const MTIME_OFFSET: usize = 0xBFF8;
+
+pub const MAX_HARTS: usize = 8;
+const MAX_ONESHOTS: usize = 8;
+const MICROS_PER_SECOND: u64 = 1_000_000;
+
+/// Handle for cancelling a one-shot timer: the hart it's on, its slot and
+/// which use of the slot it was, so a stale one can't cancel the next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId {
+    hart: usize,
+    slot: usize,
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerError {
+    // A periodic tick of 0 Hz.
+    InvalidRate,
+}
+
+#[derive(Clone, Copy)]
+struct Oneshot {
+    deadline: u64,
+    callback: fn(),
+}
+
+#[derive(Clone, Copy)]
+struct HartTimer {
+    // Ticks between periodic callbacks, 0 when the tick is off.
+    period: u64,
+    next_tick: u64,
+    tick: Option<fn()>,
+    oneshots: [Option<Oneshot>; MAX_ONESHOTS],
+    // Bumped each time a slot is taken.
+    generations: [u64; MAX_ONESHOTS],
+}
+
+const IDLE_TIMER: HartTimer = HartTimer {
+    period: 0,
+    next_tick: u64::MAX,
+    tick: None,
+    oneshots: [None; MAX_ONESHOTS],
+    generations: [0; MAX_ONESHOTS],
+};
+
+static mut TIMERS: [HartTimer; MAX_HARTS] = [IDLE_TIMER; MAX_HARTS];
+
+fn base() -> usize {
+    plt::get().clint_base
+}
+
+fn timer(hart: usize) -> &'static mut HartTimer {
+    unsafe { &mut *core::ptr::addr_of_mut!(TIMERS[hart]) }
+}
+
+/// Current value of the free running `mtime` counter.
+#[cfg(not(test))]
+pub fn mtime() -> u64 {
+    let time: u64;
+    unsafe {
+        core::arch::asm!("csrr {0}, time", out(reg) time);
+    }
+    time
+}
+
+// Host tests have no time CSR, see `interrupt::enable_global`.
+#[cfg(test)]
+pub fn mtime() -> u64 {
+    0
+}
+
+/// Program `hart`'s comparator. M-mode only.
+pub fn set_mtimecmp(hart: usize, value: u64) {
+    unsafe {
+        ((base() + MTIMECMP_OFFSET + 8 * hart) as *mut u64).write_volatile(value);
+    }
+}
+
+/// Raise (or clear) a machine software interrupt on `hart`. M-mode only.
+pub fn set_msip(hart: usize, pending: bool) {
+    unsafe {
+        ((base() + MSIP_OFFSET + 4 * hart) as *mut u32).write_volatile(pending as u32);
+    }
+}
+
+/// `mtime` ticks per second, from `/cpus/timebase-frequency`.
+pub fn frequency() -> u64 {
+    plt::get().timebase_frequency
+}
+
+pub fn micros_to_ticks(micros: u64) -> u64 {
+    (micros as u128 * frequency() as u128 / MICROS_PER_SECOND as u128) as u64
+}
+
+pub fn ticks_to_micros(ticks: u64) -> u64 {
+    (ticks as u128 * MICROS_PER_SECOND as u128 / frequency() as u128) as u64
+}
+
+/// Microseconds since the counter started.
+pub fn uptime_micros() -> u64 {
+    ticks_to_micros(mtime())
+}
+
+/// Spin for at least `micros` microseconds.
+pub fn delay_us(micros: u64) {
+    let end = mtime() + micros_to_ticks(micros);
+    while mtime() < end {}
+}
+
+/// Park this hart's timer so STIP stays quiet until a tick or one-shot is
+/// set up.
+pub fn init() {
+    init_hart();
+    let _ = console::register(Command {
+        name: "uptime",
+        usage: "uptime",
+        help: "time since boot",
+        handler: uptime_command,
+    });
+    println!("[CLINT]: {:#X}, timebase {} Hz", base(), frequency());
+}
+
+/// Per hart half of `init`.
+pub fn init_hart() {
+    let hart = interrupt::hart_id();
+    *timer(hart) = IDLE_TIMER;
+    sbi::set_timer(u64::MAX);
+}
+
+fn uptime_command(_args: &[&str]) {
+    let micros = uptime_micros();
+    println!("up {}.{:06} s", micros / 1_000_000, micros % 1_000_000);
+}
+
+/// Call `callback` `hz` times a second from the timer interrupt. Replaces
+/// any tick already running on this hart.
+pub fn start_periodic(hz: u64, callback: fn()) -> Result<(), TimerError> {
+    if hz == 0 {
+        return Err(TimerError::InvalidRate);
+    }
+    let hart = interrupt::hart_id();
+    interrupt::without_interrupts(|| {
+        let t = timer(hart);
+        t.period = (frequency() / hz).max(1);
+        t.next_tick = mtime() + t.period;
+        t.tick = Some(callback);
+        rearm(hart);
+    });
+    Ok(())
+}
+
+pub fn stop_periodic() {
+    let hart = interrupt::hart_id();
+    interrupt::without_interrupts(|| {
+        let t = timer(hart);
+        t.period = 0;
+        t.next_tick = u64::MAX;
+        t.tick = None;
+        rearm(hart);
+    });
+}
+
+/// Call `callback` once, from the timer interrupt, when `mtime` reaches
+/// `deadline`.
+pub fn oneshot_at(deadline: u64, callback: fn()) -> Option<TimerId> {
+    let hart = interrupt::hart_id();
+    interrupt::without_interrupts(|| {
+        let t = timer(hart);
+        let slot = t.oneshots.iter().position(|o| o.is_none())?;
+        t.oneshots[slot] = Some(Oneshot { deadline, callback });
+        t.generations[slot] += 1;
+        rearm(hart);
+        Some(TimerId {
+            hart,
+            slot,
+            generation: t.generations[slot],
+        })
+    })
+}
+
+/// Call `callback` once, `micros` microseconds from now.
+pub fn oneshot_after(micros: u64, callback: fn()) -> Option<TimerId> {
+    oneshot_at(mtime() + micros_to_ticks(micros), callback)
+}
+
+/// Cancel a one-shot that hasn't fired yet, from the hart that set it up.
+/// Returns false if it already had, or it's another hart's.
+pub fn cancel(id: TimerId) -> bool {
+    let hart = interrupt::hart_id();
+    if id.hart != hart {
+        return false;
+    }
+    interrupt::without_interrupts(|| {
+        let t = timer(hart);
+        if t.generations[id.slot] != id.generation {
+            return false;
+        }
+        let was_pending = t.oneshots[id.slot].take().is_some();
+        rearm(hart);
+        was_pending
+    })
+}
+
+// Point the timer at whichever event is due first. u64::MAX parks it, and
+// any SBI set_timer clears STIP.
+fn rearm(hart: usize) {
+    let t = timer(hart);
+    let mut next = t.next_tick;
+    for oneshot in t.oneshots.iter().flatten() {
+        next = next.min(oneshot.deadline);
+    }
+    sbi::set_timer(next);
+}
+
+/// Supervisor timer interrupt. Runs everything that is due and rearms.
+pub fn handle_interrupt() {
+    let hart = interrupt::hart_id();
+    let now = mtime();
+
+    // Collect what's due before calling anything, callbacks are free to set
+    // up new timers.
+    let mut due: [Option<fn()>; MAX_ONESHOTS + 1] = [None; MAX_ONESHOTS + 1];
+    let t = timer(hart);
+    for (slot, callback) in t.oneshots.iter_mut().zip(due.iter_mut()) {
+        if slot.is_some_and(|oneshot| oneshot.deadline <= now) {
+            *callback = slot.take().map(|oneshot| oneshot.callback);
+        }
+    }
+    if t.period != 0 && t.next_tick <= now {
+        // Step from the last deadline rather than from now so the tick
+        // doesn't drift, but skip ticks we missed entirely.
+        t.next_tick += t.period;
+        if t.next_tick <= now {
+            t.next_tick = now + t.period;
+        }
+        due[MAX_ONESHOTS] = t.tick;
+    }
+    rearm(hart);
+
+    for callback in due.iter().flatten() {
+        callback();
+    }
+}
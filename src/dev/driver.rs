@@ -0,0 +1,376 @@
+//! Device drivers in user space.
+//!
+//! The kernel keeps a list of devices, each a set of MMIO windows and PLIC
+//! interrupts, found from a PCI function's BARs or a device tree node's
+//! `reg` and `interrupts`. A device is driven by the kernel or by one user
+//! program at a time. `hand_over` gives the program caps for the windows,
+//! which it maps with cap_map, and for the interrupts, which `bind` turns
+//! into notifications on an endpoint of its choosing. `forward` masks the
+//! interrupt until the driver acks it, so a level-triggered source can't
+//! storm while the driver gets round to it. DMA buffers are regions, which
+//! are physically contiguous, and with no IOMMU the device is given their
+//! physical address.
+//!
+//! A kernel driver gives its device up when it's `release`d, e.g. by the
+//! shell's "devices release", and checks `in_kernel` to know to stop.
+//! `reclaim` takes a device back from user space, revoking its caps and
+//! unmapping its windows from every space they were mapped into, so the
+//! next driver has it to itself. That happens by itself when the driver's
+//! process ends.
+use crate::dev::pci::PCIDevice;
+use crate::dev::plic::{self, PlicError, MAX_IRQS};
+use crate::mm::paging::MapError;
+use crate::mm::region::Region;
+use crate::mm::space::{AddressSpace, Spaces};
+use crate::plt;
+use crate::print;
+use crate::println;
+use crate::srv::console::{self, Command};
+use crate::util::alloc::PAGE_SIZE;
+use crate::util::cap::{self, Cap, Object};
+use crate::util::ipc::Endpoint;
+use crate::util::lock::{IrqSpinLock, Mutex};
+use crate::util::process::Pid;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// The most one dma_alloc can ask for.
+pub const MAX_DMA_SIZE: usize = 4 << 20;
+
+// Standard PCI BAR bits.
+const BAR_IO: u32 = 1 << 0;
+const BAR_TYPE_64: u32 = 2 << 1;
+const BAR_TYPE_MASK: u32 = 3 << 1;
+const BAR_ADDRESS_MASK: u32 = !0xF;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverError {
+    NoSuchDevice,
+    // Not found, or its BARs haven't been given addresses.
+    NotPresent,
+    AlreadyRegistered,
+    // Someone else is driving it, or the kernel has a handler on the IRQ.
+    Busy,
+    InvalidIrq,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Kernel,
+    User,
+    Free,
+}
+
+/// What a device is at: (base, size) MMIO windows, whole pages, and IRQs.
+#[derive(Debug, Clone, Default)]
+pub struct Resources {
+    pub mmio: Vec<(usize, usize)>,
+    pub irqs: Vec<u32>,
+}
+
+struct Device {
+    name: &'static str,
+    resources: Resources,
+    owner: Owner,
+    // The caps a user driver's were derived from, revoked on `reclaim`.
+    roots: Vec<Arc<Cap>>,
+    // Where a user driver has mapped its windows.
+    spaces: Spaces,
+    // The process driving it, once it's been started.
+    driver: Option<Pid>,
+}
+
+// Where a user driver wants an interrupt delivered.
+struct Binding {
+    endpoint: Arc<Endpoint>,
+    bits: usize,
+}
+
+static DEVICES: Mutex<Vec<Device>> = Mutex::new(Vec::new());
+// Taken by `forward` in interrupt context.
+static BINDINGS: IrqSpinLock<BTreeMap<u32, Binding>> = IrqSpinLock::new(BTreeMap::new());
+
+pub fn init() {
+    let _ = console::register(Command {
+        name: "devices",
+        usage: "devices [release|reclaim <name>]",
+        help: "list devices and who drives them, or hand one over",
+        handler: devices_command,
+    });
+    // Free for a user-space driver from the start.
+    if let Ok(rtc) = device_tree("google,goldfish-rtc") {
+        let _ = register("rtc", rtc, Owner::Free);
+    }
+}
+
+// Widen [base, base + size) to whole pages.
+fn page_window(base: usize, size: usize) -> (usize, usize) {
+    let start = base & !(PAGE_SIZE - 1);
+    let end = (base + size).next_multiple_of(PAGE_SIZE);
+    (start, end - start)
+}
+
+/// The memory BARs and INTx IRQ of PCI device `slot` on `bus`, as they've
+/// been assigned.
+pub fn pci(bus: u8, slot: u8) -> Result<Resources, DriverError> {
+    let device = PCIDevice::get(bus, slot);
+    if device.header.vendor_id == 0xFFFF {
+        return Err(DriverError::NotPresent);
+    }
+    let mut resources = Resources::default();
+    let mut bar = 0;
+    while bar < 6 {
+        let value = device.bar_read(bar);
+        let wide = value & BAR_TYPE_MASK == BAR_TYPE_64;
+        let mut base = (value & BAR_ADDRESS_MASK) as usize;
+        if wide && bar < 5 {
+            base |= (device.bar_read(bar + 1) as usize) << 32;
+        }
+        if value & BAR_IO == 0 && base != 0 {
+            let size = device.get_bar_address_size(bar) as usize;
+            resources.mmio.push(page_window(base, size));
+        }
+        bar += if wide { 2 } else { 1 };
+    }
+    if resources.mmio.is_empty() {
+        return Err(DriverError::NotPresent);
+    }
+    resources.irqs.extend(device.irq());
+    Ok(resources)
+}
+
+/// The `reg` windows and `interrupts` of the first device tree node that's
+/// `compatible`.
+pub fn device_tree(compatible: &str) -> Result<Resources, DriverError> {
+    let fdt = plt::fdt().ok_or(DriverError::NotPresent)?;
+    let node = fdt
+        .find_compatible(compatible)
+        .ok_or(DriverError::NotPresent)?;
+    let resources = Resources {
+        mmio: node
+            .reg()
+            .map(|region| page_window(region.address as usize, region.size as usize))
+            .collect(),
+        irqs: node.interrupts().collect(),
+    };
+    Ok(resources)
+}
+
+/// Add a device to the list, driven by `owner`.
+pub fn register(name: &'static str, resources: Resources, owner: Owner) -> Result<(), DriverError> {
+    let mut devices = DEVICES.lock();
+    if devices.iter().any(|device| device.name == name) {
+        return Err(DriverError::AlreadyRegistered);
+    }
+    devices.push(Device {
+        name,
+        resources,
+        owner,
+        roots: Vec::new(),
+        spaces: Spaces::new(),
+        driver: None,
+    });
+    Ok(())
+}
+
+fn with_device<R, F>(name: &str, f: F) -> Result<R, DriverError>
+where
+    F: FnOnce(&mut Device) -> Result<R, DriverError>,
+{
+    let mut devices = DEVICES.lock();
+    let device = devices
+        .iter_mut()
+        .find(|device| device.name == name)
+        .ok_or(DriverError::NoSuchDevice)?;
+    f(device)
+}
+
+/// Whether the kernel is still the one to drive `name`.
+pub fn in_kernel(name: &str) -> bool {
+    with_device(name, |device| Ok(device.owner == Owner::Kernel)).unwrap_or(false)
+}
+
+/// Have the kernel's driver let go of `name`.
+pub fn release(name: &str) -> Result<(), DriverError> {
+    with_device(name, |device| match device.owner {
+        Owner::Kernel => {
+            device.owner = Owner::Free;
+            Ok(())
+        }
+        _ => Err(DriverError::Busy),
+    })
+}
+
+/// Caps for all of a free device, for a user program to drive it with. It's
+/// the program's until it's reclaimed.
+pub fn hand_over(name: &str) -> Result<Vec<Arc<Cap>>, DriverError> {
+    with_device(name, |device| {
+        if device.owner != Owner::Free {
+            return Err(DriverError::Busy);
+        }
+        let windows = device.resources.mmio.iter().map(|&(base, size)| {
+            let mmio = Object::Mmio { base, size };
+            Cap::new(mmio, cap::READ | cap::WRITE | cap::GRANT)
+        });
+        let irqs = device
+            .resources
+            .irqs
+            .iter()
+            .map(|&irq| Cap::new(Object::Irq(irq), cap::RECEIVE | cap::GRANT));
+        device.roots = windows.chain(irqs).collect();
+        device.owner = Owner::User;
+        let caps = device
+            .roots
+            .iter()
+            .filter_map(|root| root.derive(root.rights(), None).ok())
+            .collect();
+        Ok(caps)
+    })
+}
+
+/// Take `name` back from its user driver: its caps die, its windows are
+/// unmapped and its interrupts are unbound.
+pub fn reclaim(name: &str) -> Result<(), DriverError> {
+    let (resources, spaces) = with_device(name, |device| {
+        if device.owner != Owner::User {
+            return Err(DriverError::Busy);
+        }
+        for root in device.roots.drain(..) {
+            root.revoke();
+        }
+        device.owner = Owner::Free;
+        device.driver = None;
+        Ok((device.resources.clone(), device.spaces.take()))
+    })?;
+    for space in spaces {
+        let mut space = space.lock();
+        for &(base, size) in &resources.mmio {
+            space.unmap_device(base, size);
+        }
+    }
+    for irq in resources.irqs {
+        unbind(irq);
+    }
+    Ok(())
+}
+
+/// Note that process `pid` is the one driving `name`, handed over to it.
+pub fn assign(name: &str, pid: Pid) -> Result<(), DriverError> {
+    with_device(name, |device| {
+        if device.owner != Owner::User {
+            return Err(DriverError::Busy);
+        }
+        device.driver = Some(pid);
+        Ok(())
+    })
+}
+
+/// Process `pid` has ended: reclaim the devices it was driving.
+pub fn process_exit(pid: Pid) {
+    let names: Vec<&'static str> = DEVICES
+        .lock()
+        .iter()
+        .filter(|device| device.driver == Some(pid))
+        .map(|device| device.name)
+        .collect();
+    for name in names {
+        let _ = reclaim(name);
+    }
+}
+
+/// Note that `space` has mapped device memory at `pa`, so that `reclaim`
+/// can take it out again if it's part of a user driver's device. Call it
+/// without `space` locked.
+pub fn mapped_in(pa: usize, space: &Arc<Mutex<AddressSpace>>) {
+    let mut devices = DEVICES.lock();
+    let device = devices.iter_mut().find(|device| {
+        let mut windows = device.resources.mmio.iter();
+        device.owner == Owner::User
+            && windows.any(|&(base, size)| (base..base + size).contains(&pa))
+    });
+    if let Some(device) = device {
+        device.spaces.add(space);
+    }
+}
+
+/// Deliver `irq` to `endpoint` as a notification with `bits`, from now on.
+pub fn bind(irq: u32, endpoint: Arc<Endpoint>, bits: usize) -> Result<(), DriverError> {
+    if irq == 0 || irq as usize >= MAX_IRQS || bits == 0 {
+        return Err(DriverError::InvalidIrq);
+    }
+    let mut bindings = BINDINGS.lock();
+    if !bindings.contains_key(&irq) {
+        plic::register(irq, "user", forward).map_err(|e| match e {
+            PlicError::AlreadyRegistered => DriverError::Busy,
+            _ => DriverError::InvalidIrq,
+        })?;
+    }
+    bindings.insert(irq, Binding { endpoint, bits });
+    Ok(())
+}
+
+pub fn unbind(irq: u32) {
+    if BINDINGS.lock().remove(&irq).is_some() {
+        plic::unregister(irq);
+    }
+}
+
+/// The driver has dealt with `irq`, let it in again.
+pub fn ack(irq: u32) -> Result<(), DriverError> {
+    if !BINDINGS.lock().contains_key(&irq) {
+        return Err(DriverError::InvalidIrq);
+    }
+    plic::set_priority(irq, 1);
+    Ok(())
+}
+
+// PLIC handler for every bound interrupt.
+fn forward(irq: u32) {
+    // Masked for every hart until `ack`: only the driver can quiet the
+    // device, and it runs later.
+    plic::set_priority(irq, 0);
+    if let Some(binding) = BINDINGS.lock().get(&irq) {
+        binding.endpoint.notify(binding.bits);
+    }
+}
+
+/// Zeroed, physically contiguous memory for a device to DMA to and from.
+pub fn dma_alloc(size: usize) -> Result<Arc<Region>, MapError> {
+    if size > MAX_DMA_SIZE {
+        return Err(MapError::OutOfRange);
+    }
+    Region::new(size)
+}
+
+fn devices_command(args: &[&str]) {
+    let result = match args {
+        [] => {
+            println!("{:<8} {:<7} {}", "NAME", "OWNER", "RESOURCES");
+            for device in DEVICES.lock().iter() {
+                print!(
+                    "{:<8} {:<7}",
+                    device.name,
+                    alloc::format!("{:?}", device.owner)
+                );
+                for (base, size) in &device.resources.mmio {
+                    print!(" {:#X}+{:#X}", base, size);
+                }
+                for irq in &device.resources.irqs {
+                    print!(" irq {}", irq);
+                }
+                println!();
+            }
+            Ok(())
+        }
+        ["release", name] => release(name),
+        ["reclaim", name] => reclaim(name),
+        _ => {
+            println!("usage: devices [release|reclaim <name>]");
+            Ok(())
+        }
+    };
+    if let Err(e) = result {
+        println!("devices: {:?}", e);
+    }
+}
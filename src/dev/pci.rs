@@ -4,7 +4,7 @@ use core::fmt;
 use core::result::Result;
 use core::fmt::Display;
 use bitfield_struct::{bitfield};
-const PCI_BASE: u32 = 0x3000_0000;
+use crate::plt;
 #[derive(Debug)]
 pub enum PCIError
 {
@@ -236,6 +236,8 @@ pub struct PCICapabilitiesList {
 
 pub struct PCIDevice
 {
+    pub bus: u8,
+    pub slot: u8,
     pub base_address: *mut u32,
     pub header: PCICommonHeader,
     pub cardbus_cis_pointer: *mut u32,
@@ -260,6 +262,8 @@ impl PCIDevice
         {
             PCIDevice
             {
+                bus,
+                slot,
                 base_address: addr,
                 header,
                 cardbus_cis_pointer: addr.add(0xA),
@@ -292,6 +296,13 @@ impl PCIDevice
     unsafe fn get_bar_address(&self, index: usize) -> *mut u32 {
         self.base_address.add(4+index)
     }
+    /// PLIC IRQ this device's INTx pin is wired to, if it uses one.
+    pub fn irq(&self) -> Option<u32> {
+        if self.interrupt_pin == 0 {
+            return None;
+        }
+        intx_irq(self.bus, self.slot, self.interrupt_pin)
+    }
     pub fn get_bar_address_size(&self, index: usize) -> u32 {
         let original = self.bar_read(index);
         self.bar_write(index, 0xFFFF_FFFF);
@@ -303,21 +314,77 @@ impl PCIDevice
 }
 
 //static PCI function wall read word
-pub fn pci_device_address(bus: u8, slot: u8) -> u32 {
-    let lbus = bus as u32;
-    let lslot = slot as u32;
-    ((PCI_BASE) | 
+pub fn pci_device_address(bus: u8, slot: u8) -> usize {
+    let lbus = bus as usize;
+    let lslot = slot as usize;
+    (plt::get().pci_ecam_base) | 
     (lbus << 20) | 
-    (lslot << 15)) as u32
+    (lslot << 15)
 }
 
-pub fn pci_function_address(bus: u8, slot: u8, func: u8) -> u32 {
-    let lbus = bus as u32;
-    let lslot = slot as u32;
-    let lfunc = func as u32;
-    ((PCI_BASE) | 
+pub fn pci_function_address(bus: u8, slot: u8, func: u8) -> usize {
+    let lbus = bus as usize;
+    let lslot = slot as usize;
+    let lfunc = func as usize;
+    (plt::get().pci_ecam_base) | 
     (lbus << 20) | 
     (lslot << 15) | 
-    (lfunc << 12)) as u32
+    (lfunc << 12)
 }
 
+/// Resolve INTx `pin` (1 = INTA) of a device to a PLIC IRQ by walking the
+/// host bridge's `interrupt-map` from the device tree.
+pub fn intx_irq(bus: u8, slot: u8, pin: u8) -> Option<u32> {
+    let fdt = plt::fdt()?;
+    let host = fdt.find_compatible("pci-host-ecam-generic")?;
+    let address_cells = host.address_cells() as usize;
+    let interrupt_cells = host
+        .property("#interrupt-cells")
+        .and_then(|p| p.as_u32())
+        .unwrap_or(1) as usize;
+    let key_cells = address_cells + interrupt_cells;
+    if key_cells > 4 {
+        return None;
+    }
+
+    // phys.hi of the device's config space address, then the pin.
+    let mut key = [0u32; 4];
+    key[0] = ((bus as u32) << 16) | ((slot as u32) << 11);
+    key[address_cells] = pin as u32;
+
+    let mut mask = [0xFFFF_FFFFu32; 4];
+    if let Some(mask_prop) = host.property("interrupt-map-mask") {
+        for (i, cell) in mask_prop.cells().take(key_cells).enumerate() {
+            mask[i] = cell;
+        }
+    }
+
+    let mut cells = host.property("interrupt-map")?.cells();
+    loop {
+        let mut child = [0u32; 4];
+        for cell in child.iter_mut().take(key_cells) {
+            *cell = cells.next()?;
+        }
+        let parent = fdt.find_phandle(cells.next()?)?;
+        let parent_address_cells = parent
+            .property("#address-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(0);
+        let parent_interrupt_cells = parent
+            .property("#interrupt-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(1);
+        for _ in 0..parent_address_cells {
+            cells.next()?;
+        }
+        let irq = cells.next()?;
+        for _ in 1..parent_interrupt_cells {
+            cells.next()?;
+        }
+
+        let matches = (0..key_cells).all(|i| child[i] & mask[i] == key[i] & mask[i]);
+        if matches {
+            return Some(irq);
+        }
+    }
+}
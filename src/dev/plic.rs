@@ -0,0 +1,269 @@
+//! Platform-Level Interrupt Controller.
+//! https://github.com/riscv/riscv-plic-spec/blob/master/riscv-plic.adoc
+//!
+//! Drivers hook an IRQ with `register`, using the number from their device
+//! tree `interrupts` property, and the SEIP handler claims, dispatches and
+//! completes for them.
+use crate::dev::clint::MAX_HARTS;
+use crate::plt;
+use crate::srv::console::{self, Command};
+use crate::print;
+use crate::println;
+use crate::util::interrupt;
+use crate::util::lock::IrqSpinLock;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const PRIORITY_OFFSET: usize = 0x0;
+const PENDING_OFFSET: usize = 0x1000;
+const ENABLE_OFFSET: usize = 0x2000;
+const ENABLE_STRIDE: usize = 0x80;
+const CONTEXT_OFFSET: usize = 0x20_0000;
+const CONTEXT_STRIDE: usize = 0x1000;
+const THRESHOLD: usize = 0x0;
+const CLAIM_COMPLETE: usize = 0x4;
+
+// The spec allows 1024 sources, virt wires up 53 of them.
+pub const MAX_IRQS: usize = 128;
+pub const MAX_PRIORITY: u32 = 7;
+
+// Cause numbers that show up in the PLIC's interrupts-extended.
+const CAUSE_SEIP: u32 = 9;
+const CAUSE_MEIP: u32 = 11;
+
+#[derive(Debug)]
+pub enum PlicError {
+    InvalidIrq,
+    AlreadyRegistered,
+}
+
+#[derive(Clone, Copy)]
+struct Handler {
+    handler: fn(u32),
+    name: &'static str,
+}
+
+static HANDLERS: IrqSpinLock<[Option<Handler>; MAX_IRQS]> = IrqSpinLock::new([None; MAX_IRQS]);
+static COUNTS: [AtomicUsize; MAX_IRQS] = [const { AtomicUsize::new(0) }; MAX_IRQS];
+
+// PLIC context of each hart's M-mode and S-mode external interrupt line,
+// read from interrupts-extended. usize::MAX when the hart has none.
+static mut M_CONTEXTS: [usize; MAX_HARTS] = [usize::MAX; MAX_HARTS];
+static mut S_CONTEXTS: [usize; MAX_HARTS] = [usize::MAX; MAX_HARTS];
+
+fn base() -> usize {
+    plt::get().plic_base
+}
+
+fn reg(offset: usize) -> *mut u32 {
+    (base() + offset) as *mut u32
+}
+
+// The context external interrupts for this hart are routed to.
+fn context(hart: usize) -> usize {
+    unsafe { S_CONTEXTS[hart] }
+}
+
+/// Work out the context numbering from the device tree, mask every source
+/// and open this hart's threshold.
+pub fn init() {
+    find_contexts();
+    // Sources are numbered 1 to ndev, 0 meaning none.
+    for irq in 1..=plt::get().plic_ndev.min(MAX_IRQS as u32 - 1) {
+        set_priority(irq, 0);
+    }
+    init_hart();
+    let _ = console::register(Command {
+        name: "irqs",
+        usage: "irqs",
+        help: "registered external interrupts and their counts",
+        handler: irqs_command,
+    });
+    println!("[PLIC]: {:#X}, {} sources", base(), plt::get().plic_ndev);
+}
+
+fn irqs_command(_args: &[&str]) {
+    for_each_handler(|irq, name, count| {
+        println!("  {:>3} {:<12} {}", irq, name, count);
+    });
+}
+
+/// Per hart half of `init`: nothing enabled, accept any priority above 0.
+pub fn init_hart() {
+    let hart = interrupt::hart_id();
+    let ctx = context(hart);
+    if ctx == usize::MAX {
+        return;
+    }
+    for word in 0..MAX_IRQS / 32 {
+        unsafe {
+            reg(ENABLE_OFFSET + ENABLE_STRIDE * ctx + 4 * word).write_volatile(0);
+        }
+    }
+    set_threshold(hart, 0);
+}
+
+// Entry n of the PLIC's interrupts-extended is context n. Each entry points
+// at a cpu's interrupt-controller, so match those back to hart IDs.
+fn find_contexts() {
+    let fdt = match plt::fdt() {
+        Some(fdt) => fdt,
+        None => {
+            // virt's layout: M then S for each hart.
+            for hart in 0..MAX_HARTS {
+                unsafe {
+                    M_CONTEXTS[hart] = 2 * hart;
+                    S_CONTEXTS[hart] = 2 * hart + 1;
+                }
+            }
+            return;
+        }
+    };
+    let plic = match fdt.find_compatible("riscv,plic0") {
+        Some(plic) => plic,
+        None => return,
+    };
+    let cpus = match fdt.find_node("/cpus") {
+        Some(cpus) => cpus,
+        None => return,
+    };
+    for (ctx, (phandle, cause)) in plic.interrupts_extended().enumerate() {
+        let hart = cpus
+            .children()
+            .find(|cpu| cpu.children().any(|intc| intc.phandle() == Some(phandle)));
+        let hart = match hart.and_then(|cpu| cpu.reg().next()) {
+            Some(reg) => reg.address as usize,
+            None => continue,
+        };
+        if hart >= MAX_HARTS {
+            continue;
+        }
+        unsafe {
+            match cause {
+                CAUSE_MEIP => M_CONTEXTS[hart] = ctx,
+                CAUSE_SEIP => S_CONTEXTS[hart] = ctx,
+                _ => {}
+            }
+        }
+    }
+}
+
+pub fn set_priority(irq: u32, priority: u32) {
+    unsafe {
+        reg(PRIORITY_OFFSET + 4 * irq as usize).write_volatile(priority.min(MAX_PRIORITY));
+    }
+}
+
+pub fn get_priority(irq: u32) -> u32 {
+    unsafe { reg(PRIORITY_OFFSET + 4 * irq as usize).read_volatile() }
+}
+
+/// Interrupts at or below `threshold` are masked for `hart`.
+pub fn set_threshold(hart: usize, threshold: u32) {
+    unsafe {
+        reg(CONTEXT_OFFSET + CONTEXT_STRIDE * context(hart) + THRESHOLD)
+            .write_volatile(threshold.min(MAX_PRIORITY));
+    }
+}
+
+pub fn is_pending(irq: u32) -> bool {
+    let word = unsafe { reg(PENDING_OFFSET + 4 * (irq as usize / 32)).read_volatile() };
+    word & (1 << (irq % 32)) != 0
+}
+
+fn set_enabled(hart: usize, irq: u32, enabled: bool) {
+    let ctx = context(hart);
+    let word = reg(ENABLE_OFFSET + ENABLE_STRIDE * ctx + 4 * (irq as usize / 32));
+    unsafe {
+        let bits = word.read_volatile();
+        if enabled {
+            word.write_volatile(bits | (1 << (irq % 32)));
+        } else {
+            word.write_volatile(bits & !(1 << (irq % 32)));
+        }
+    }
+}
+
+/// Route `irq` to the calling hart.
+pub fn enable(irq: u32) {
+    set_enabled(interrupt::hart_id(), irq, true);
+}
+
+pub fn disable(irq: u32) {
+    set_enabled(interrupt::hart_id(), irq, false);
+}
+
+fn claim(hart: usize) -> u32 {
+    let ctx = context(hart);
+    unsafe { reg(CONTEXT_OFFSET + CONTEXT_STRIDE * ctx + CLAIM_COMPLETE).read_volatile() }
+}
+
+fn complete(hart: usize, irq: u32) {
+    let ctx = context(hart);
+    unsafe {
+        reg(CONTEXT_OFFSET + CONTEXT_STRIDE * ctx + CLAIM_COMPLETE).write_volatile(irq);
+    }
+}
+
+/// Attach `handler` to `irq`, give it a priority and enable it on this hart.
+/// The handler is called with the IRQ number from the external interrupt
+/// handler; the PLIC is completed for it afterwards.
+pub fn register(irq: u32, name: &'static str, handler: fn(u32)) -> Result<(), PlicError> {
+    if irq == 0 || irq as usize >= MAX_IRQS {
+        return Err(PlicError::InvalidIrq);
+    }
+    let mut handlers = HANDLERS.lock();
+    let slot = &mut handlers[irq as usize];
+    if slot.is_some() {
+        return Err(PlicError::AlreadyRegistered);
+    }
+    *slot = Some(Handler { handler, name });
+    set_priority(irq, 1);
+    enable(irq);
+    Ok(())
+}
+
+pub fn unregister(irq: u32) {
+    if irq == 0 || irq as usize >= MAX_IRQS {
+        return;
+    }
+    let mut handlers = HANDLERS.lock();
+    disable(irq);
+    set_priority(irq, 0);
+    handlers[irq as usize] = None;
+}
+
+/// Registered handlers as `(irq, name, times fired)`, for diagnostics.
+pub fn for_each_handler<F: FnMut(u32, &'static str, usize)>(mut f: F) {
+    // A copy, so `f` can print without holding the lock.
+    let handlers = *HANDLERS.lock();
+    for (irq, handler) in handlers.iter().enumerate() {
+        if let Some(handler) = handler {
+            f(irq as u32, handler.name, COUNTS[irq].load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Supervisor external interrupt. Keep claiming until the PLIC has nothing left
+/// for this hart.
+pub fn handle_interrupt() {
+    let hart = interrupt::hart_id();
+    loop {
+        let irq = claim(hart);
+        if irq == 0 {
+            break;
+        }
+        let handler = if (irq as usize) < MAX_IRQS {
+            HANDLERS.lock()[irq as usize]
+        } else {
+            None
+        };
+        match handler {
+            Some(handler) => {
+                COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
+                (handler.handler)(irq);
+            }
+            None => println!("[PLIC]: no handler for irq {}", irq),
+        }
+        complete(hart, irq);
+    }
+}
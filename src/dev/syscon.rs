@@ -0,0 +1,59 @@
+//! syscon-reboot / syscon-poweroff: write a magic value to a register of the
+//! node their `regmap` points at. On virt that's the SiFive test device.
+//!
+//! The kernel asks the SBI firmware first, since that's what owns the
+//! device under `-kernel`; the firmware itself uses `reset_direct`.
+use crate::plt;
+use crate::sbi;
+use crate::util::interrupt;
+
+// Used when the device tree doesn't describe the nodes.
+const VIRT_TEST_BASE: usize = 0x10_0000;
+const VIRT_REBOOT: u32 = 0x7777;
+const VIRT_POWEROFF: u32 = 0x5555;
+
+// Address and value for the syscon node with this compatible string.
+fn lookup(compatible: &str) -> Option<(usize, u32)> {
+    let fdt = plt::fdt()?;
+    let node = fdt.find_compatible(compatible)?;
+    let regmap = fdt.find_phandle(node.property("regmap")?.as_u32()?)?;
+    let base = regmap.reg().next()?.address as usize;
+    let offset = node.property("offset").and_then(|p| p.as_u32()).unwrap_or(0);
+    let value = node.property("value")?.as_u32()?;
+    Some((base + offset as usize, value))
+}
+
+fn trigger(compatible: &str, fallback: u32) -> ! {
+    let (addr, value) = lookup(compatible).unwrap_or((VIRT_TEST_BASE, fallback));
+    interrupt::disable_global();
+    unsafe {
+        (addr as *mut u32).write_volatile(value);
+    }
+    loop {
+        interrupt::wait_for_interrupt();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResetKind {
+    Reboot,
+    Poweroff,
+}
+
+/// Poke the syscon device ourselves.
+pub fn reset_direct(kind: ResetKind) -> ! {
+    match kind {
+        ResetKind::Reboot => trigger("syscon-reboot", VIRT_REBOOT),
+        ResetKind::Poweroff => trigger("syscon-poweroff", VIRT_POWEROFF),
+    }
+}
+
+pub fn reboot() -> ! {
+    sbi::system_reset(sbi::RESET_COLD_REBOOT, 0);
+    reset_direct(ResetKind::Reboot)
+}
+
+pub fn poweroff() -> ! {
+    sbi::system_reset(sbi::RESET_SHUTDOWN, 0);
+    reset_direct(ResetKind::Poweroff)
+}
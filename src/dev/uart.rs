@@ -1,53 +1,309 @@
+//! NS16550A UART.
+//! http://caro.su/msx/ocm_de1/16550.pdf
+//!
+//! Starts out polled so `print!` works from the first instruction. After
+//! `init` the console switches to interrupt driven mode, where received bytes
+//! land in an RX ring and writes queue in a TX ring that the THRE interrupt
+//! drains.
 use core::fmt::{Error, Write};
+use crate::dev::plic;
+use crate::plt;
+use crate::print;
+use crate::println;
+use crate::util::interrupt;
+use crate::util::lock::{IrqSpinLock, IrqSpinLockGuard};
+use crate::util::ring::RingBuffer;
+
+// Register offsets. DLL/DLM overlay RBR/THR and IER while LCR.DLAB is set.
+const RBR: usize = 0;
+const THR: usize = 0;
+const DLL: usize = 0;
+const IER: usize = 1;
+const DLM: usize = 1;
+const IIR: usize = 2;
+const FCR: usize = 2;
+const LCR: usize = 3;
+const MCR: usize = 4;
+const LSR: usize = 5;
+
+const IER_RX_AVAILABLE: u8 = 1 << 0;
+const IER_TX_EMPTY: u8 = 1 << 1;
+
+const IIR_NO_INTERRUPT: u8 = 1 << 0;
+
+const FCR_ENABLE: u8 = 1 << 0;
+const FCR_CLEAR_RX: u8 = 1 << 1;
+const FCR_CLEAR_TX: u8 = 1 << 2;
+// Interrupt once 8 bytes are waiting (or on timeout).
+const FCR_TRIGGER_8: u8 = 0b10 << 6;
+
+const LCR_8N1: u8 = 0b11;
+const LCR_DLAB: u8 = 1 << 7;
+
+const MCR_DTR: u8 = 1 << 0;
+const MCR_RTS: u8 = 1 << 1;
+// OUT2 gates the interrupt line on real 16550s.
+const MCR_OUT2: u8 = 1 << 3;
+
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_OVERRUN: u8 = 1 << 1;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+const FIFO_DEPTH: usize = 16;
+pub const DEFAULT_BAUD: u32 = 115_200;
+
+const RX_BUFFER_SIZE: usize = 256;
+const TX_BUFFER_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Polled,
+    Interrupt,
+}
 
 pub struct Uart {
     addr: *mut u8,
+    mode: Mode,
+    rx: RingBuffer<u8, RX_BUFFER_SIZE>,
+    tx: RingBuffer<u8, TX_BUFFER_SIZE>,
+    // Bytes lost because the RX ring or the hardware FIFO was full.
+    pub rx_dropped: usize,
+}
+
+// The console. Everything that prints goes through here, from any hart and
+// from interrupt handlers, so it masks interrupts while it's held.
+static CONSOLE: IrqSpinLock<Uart> = IrqSpinLock::new(Uart::new(0x1000_0000 as *mut u8));
+
+// Only ever touched through CONSOLE.
+unsafe impl Send for Uart {}
+
+/// The shared console UART, locked until the guard is dropped. A whole
+/// `println!` happens under one lock, so lines from different harts don't
+/// interleave.
+pub fn console() -> IrqSpinLockGuard<'static, Uart> {
+    CONSOLE.lock()
+}
+
+/// The console for the panic handler, taken even if someone holds it: it
+/// may be the code that panicked.
+pub fn panic_console() -> IrqSpinLockGuard<'static, Uart> {
+    unsafe {
+        CONSOLE.force_unlock();
+    }
+    CONSOLE.lock()
+}
+
+/// `print!`'s back end. The arguments are evaluated before the console is
+/// taken, so they're free to print themselves.
+pub fn print_fmt(args: core::fmt::Arguments) {
+    let _ = console().write_fmt(args);
+}
+
+/// A byte from the console if one is waiting.
+pub fn try_read() -> Option<u8> {
+    console().try_read()
+}
+
+/// Block until a byte arrives on the console. Sleeps between interrupts,
+/// without holding the console.
+pub fn read_char() -> u8 {
+    loop {
+        if let Some(byte) = try_read() {
+            return byte;
+        }
+        if console().mode() == Mode::Interrupt {
+            interrupt::wait_for_interrupt();
+        }
+    }
 }
 
 impl Uart {
     // Constructor
-    pub fn new(addr: *mut u8) -> Self {
+    pub const fn new(addr: *mut u8) -> Self {
         Self {
-            addr
+            addr,
+            mode: Mode::Polled,
+            rx: RingBuffer::new(),
+            tx: RingBuffer::new(),
+            rx_dropped: 0,
         }
     }
 
-    pub fn print_str(&self, s: &str) {
-        for c in s.chars() {
-            self.print_char(c);
-        }
+    fn read_reg(&self, reg: usize) -> u8 {
+        unsafe { self.addr.add(reg).read_volatile() }
     }
-    
-    pub fn print_char(&self, c: char) {
+
+    fn write_reg(&self, reg: usize, value: u8) {
         unsafe {
-            self.addr.write_volatile(c as u8);
+            self.addr.add(reg).write_volatile(value);
         }
     }
 
-    pub fn read_char(&self) -> u8 {
-        unsafe {
-            return self.addr.read_volatile();
+    /// Program the line for `baud` 8N1 from a `clock` Hz input clock, turn
+    /// the FIFOs on and leave interrupts off.
+    pub fn configure(&self, clock: u32, baud: u32) {
+        self.write_reg(IER, 0);
+
+        let divisor = (clock / (16 * baud)).max(1);
+        self.write_reg(LCR, LCR_DLAB);
+        self.write_reg(DLL, divisor as u8);
+        self.write_reg(DLM, (divisor >> 8) as u8);
+        self.write_reg(LCR, LCR_8N1);
+
+        self.write_reg(FCR, FCR_ENABLE | FCR_CLEAR_RX | FCR_CLEAR_TX | FCR_TRIGGER_8);
+        self.write_reg(MCR, MCR_DTR | MCR_RTS | MCR_OUT2);
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    /// Switch between polled and interrupt driven operation. Anything still
+    /// queued for transmit is flushed first when going back to polled.
+    pub fn set_mode(&mut self, mode: Mode) {
+        interrupt::without_interrupts(|| {
+            if mode == Mode::Polled {
+                self.write_reg(IER, 0);
+                self.flush();
+            } else {
+                self.write_reg(IER, IER_RX_AVAILABLE);
+            }
+            self.mode = mode;
+        });
+    }
+
+    fn tx_ready(&self) -> bool {
+        self.read_reg(LSR) & LSR_THR_EMPTY != 0
+    }
+
+    // LSR bit 0, data ready.
+    pub fn data_ready(&self) -> bool {
+        self.read_reg(LSR) & LSR_DATA_READY != 0
+    }
+
+    fn put_byte_polled(&self, byte: u8) {
+        while !self.tx_ready() {}
+        self.write_reg(THR, byte);
+    }
+
+    pub fn put_byte(&mut self, byte: u8) {
+        if self.mode == Mode::Polled {
+            self.put_byte_polled(byte);
+            return;
         }
+        interrupt::without_interrupts(|| {
+            // Nothing queued and the holding register is free, skip the ring.
+            if self.tx.is_empty() && self.tx_ready() {
+                self.write_reg(THR, byte);
+                return;
+            }
+            // Ring full: make room by hand rather than drop output.
+            while self.tx.is_full() {
+                let queued = self.tx.pop().unwrap_or(byte);
+                self.put_byte_polled(queued);
+            }
+            let _ = self.tx.push(byte);
+            self.write_reg(IER, IER_RX_AVAILABLE | IER_TX_EMPTY);
+        });
     }
 
-    pub fn enable_fifo(&self) {
-        // Enable FIFO
-        unsafe {
-            self.addr.add(2).write_volatile(1 << 0);
+    pub fn print_str(&mut self, s: &str) {
+        for byte in s.bytes() {
+            self.put_byte(byte);
         }
     }
 
+    pub fn print_char(&mut self, c: char) {
+        let mut buf = [0u8; 4];
+        self.print_str(c.encode_utf8(&mut buf));
+    }
+
+    /// A received byte if one is waiting, without blocking.
+    pub fn try_read(&mut self) -> Option<u8> {
+        match self.mode {
+            Mode::Polled => {
+                if self.data_ready() {
+                    Some(self.read_reg(RBR))
+                } else {
+                    None
+                }
+            }
+            Mode::Interrupt => interrupt::without_interrupts(|| self.rx.pop()),
+        }
+    }
+
+    /// Push everything queued out by hand. Safe to call with interrupts off,
+    /// e.g. from the panic handler.
+    pub fn flush(&mut self) {
+        interrupt::without_interrupts(|| {
+            while let Some(byte) = self.tx.pop() {
+                self.put_byte_polled(byte);
+            }
+        });
+    }
+
+    pub fn enable_fifo(&self) {
+        self.write_reg(FCR, FCR_ENABLE | FCR_TRIGGER_8);
+    }
+
     pub fn enable_interrupts(&self) {
-        unsafe {
-            self.addr.add(1).write_volatile(1 << 0);
+        self.write_reg(IER, IER_RX_AVAILABLE);
+    }
+
+    // Service the chip: fill the RX ring and feed the TX FIFO.
+    fn service(&mut self) {
+        while self.read_reg(IIR) & IIR_NO_INTERRUPT == 0 {
+            let lsr = self.read_reg(LSR);
+            if lsr & LSR_OVERRUN != 0 {
+                self.rx_dropped += 1;
+            }
+            if lsr & LSR_DATA_READY != 0 {
+                while self.data_ready() {
+                    let byte = self.read_reg(RBR);
+                    if self.rx.push(byte).is_err() {
+                        self.rx_dropped += 1;
+                    }
+                }
+            }
+            if lsr & LSR_THR_EMPTY != 0 {
+                for _ in 0..FIFO_DEPTH {
+                    match self.tx.pop() {
+                        Some(byte) => self.write_reg(THR, byte),
+                        None => break,
+                    }
+                }
+                if self.tx.is_empty() {
+                    self.write_reg(IER, IER_RX_AVAILABLE);
+                }
+            }
         }
     }
 }
 
+/// Bring the console UART up at the address and clock from the device tree
+/// and switch it to interrupt driven mode.
+pub fn init() {
+    let platform = plt::get();
+    {
+        let mut uart = console();
+        uart.set_mode(Mode::Polled);
+        uart.addr = platform.uart_base as *mut u8;
+        uart.configure(platform.uart_clock, DEFAULT_BAUD);
+    }
+    if let Err(e) = plic::register(platform.uart_irq, "uart", handle_interrupt) {
+        println!("[UART]: could not register irq {}: {:?}", platform.uart_irq, e);
+        return;
+    }
+    console().set_mode(Mode::Interrupt);
+}
+
+fn handle_interrupt(_irq: u32) {
+    console().service();
+}
+
 impl Write for Uart {
     fn write_str(&mut self, s: &str) -> core::fmt::Result {
         self.print_str(s);
         Ok(())
     }
 }
-
@@ -3,9 +3,12 @@
 pub mod registers;
 use core::convert::Infallible;
 
+use crate::plt;
 use crate::println;
 use crate::print;
+use crate::util::lock::SpinLock;
 
+use super::driver::{self, Owner};
 use super::pci::{PCIDevice, PCIError};
 use super::vga::registers::{*};
 use bitfield_struct::bitfield;
@@ -37,7 +40,10 @@ impl VGA {
         if pci.header.class_code != 0x03 {
             return Err(PCIError::InvalidDevice);
         }
-        if 0x4000_0000 > mem_start && mem_start > 0x8000_0000 {
+        let platform = plt::get();
+        if mem_start < platform.pci_mmio_base
+            || mem_start >= platform.pci_mmio_base + platform.pci_mmio_size
+        {
             return Err(PCIError::InvalidAddress);
         }
         unsafe 
@@ -81,6 +87,10 @@ impl VGA {
                 p.write_volatile(b);
             }
 
+            // Ours until the shell releases it to a user-space driver.
+            if let Ok(resources) = driver::pci(bus, slot) {
+                let _ = driver::register("vga", resources, Owner::Kernel);
+            }
             Ok(vga)
         }
     }
@@ -251,6 +261,23 @@ fn _color_component_to_safe_color(c: Rgb888) -> u8 {
     let b = TABLE[c.b() as usize];
     16 + r + g * 6 + b * 36
 }
+/// The framebuffer the display draws to, for handing to user space. One
+/// byte per pixel, an index into the palette, rows `width` bytes apart.
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferInfo {
+    pub base: usize,
+    pub size: usize,
+    pub width: u32,
+    pub height: u32,
+}
+
+static FRAMEBUFFER: SpinLock<Option<FramebufferInfo>> = SpinLock::new(None);
+
+/// The framebuffer, once a display has been set up.
+pub fn framebuffer() -> Option<FramebufferInfo> {
+    *FRAMEBUFFER.lock()
+}
+
 pub struct ModeXDisplay<> {
     vga: VGA,
     last_x: i32,
@@ -264,7 +291,17 @@ impl<'a> ModeXDisplay<> {
     #[inline]
     pub fn new(vga: VGA, width: u32, height: u32) -> Self {
         vga.set_resolution(width, height);
-        Self { vga, last_x: 0, last_y: 10, last_length: 0, width: width, height: height }
+        let display = Self { vga, last_x: 0, last_y: 10, last_length: 0, width: width, height: height };
+        display.publish();
+        display
+    }
+    fn publish(&self) {
+        *FRAMEBUFFER.lock() = Some(FramebufferInfo {
+            base: self.vga.fb as usize,
+            size: self.vga.fb_size,
+            width: self.width,
+            height: self.height,
+        });
     }
     pub fn swap_buffer(&mut self) {
         unsafe {
@@ -374,6 +411,7 @@ impl<'a> ModeXDisplay<> {
         self.vga.set_resolution(width, height);
         self.width = width;
         self.height = height;
+        self.publish();
     }
 }
 
@@ -1,5 +1,43 @@
 pub mod nes;
 
-pub struct NES {
-    
-}
\ No newline at end of file
+use nes::rom::{Rom, RomError};
+
+#[derive(Debug)]
+pub enum EmuError {
+    Rom(RomError),
+    UnknownRom,
+    // emu::nes::cpu has no 6502 core yet, so nothing can execute.
+    NoCpu,
+}
+
+// Cartridges linked into the kernel image, by name.
+static ROMS: &[(&str, &[u8])] = &[];
+
+pub struct NES<'a> {
+    pub rom: Rom<'a>,
+}
+
+impl<'a> NES<'a> {
+    pub fn new(rom: Rom<'a>) -> Self {
+        Self { rom }
+    }
+
+    pub fn run(&mut self) -> Result<(), EmuError> {
+        Err(EmuError::NoCpu)
+    }
+}
+
+pub fn roms() -> impl Iterator<Item = &'static str> {
+    ROMS.iter().map(|(name, _)| *name)
+}
+
+pub fn find_rom(name: &str) -> Option<&'static [u8]> {
+    ROMS.iter().find(|(n, _)| *n == name).map(|(_, data)| *data)
+}
+
+/// An iNES image sitting in memory at `addr`, sized from its header.
+pub unsafe fn rom_at(addr: usize) -> Result<&'static [u8], EmuError> {
+    let header = &*(addr as *const [u8; 16]);
+    let size = Rom::image_size(header).map_err(EmuError::Rom)?;
+    Ok(core::slice::from_raw_parts(addr as *const u8, size))
+}
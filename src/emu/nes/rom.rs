@@ -0,0 +1,79 @@
+//! iNES cartridge images.
+//! https://www.nesdev.org/wiki/INES
+
+const INES_MAGIC: &[u8; 4] = b"NES\x1A";
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_BANK_SIZE: usize = 16 * 1024;
+const CHR_BANK_SIZE: usize = 8 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RomError {
+    BadMagic,
+    Truncated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+pub struct Rom<'a> {
+    pub mapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub prg: &'a [u8],
+    pub chr: &'a [u8],
+}
+
+impl<'a> Rom<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Rom<'a>, RomError> {
+        let header = data.get(..HEADER_SIZE).ok_or(RomError::Truncated)?;
+        if &header[..4] != INES_MAGIC {
+            return Err(RomError::BadMagic);
+        }
+        let prg_size = header[4] as usize * PRG_BANK_SIZE;
+        let chr_size = header[5] as usize * CHR_BANK_SIZE;
+        let flags6 = header[6];
+        let flags7 = header[7];
+
+        let mirroring = if flags6 & 0b1000 != 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 1 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let mut off = HEADER_SIZE;
+        if flags6 & 0b100 != 0 {
+            off += TRAINER_SIZE;
+        }
+        let prg = data.get(off..off + prg_size).ok_or(RomError::Truncated)?;
+        off += prg_size;
+        let chr = data.get(off..off + chr_size).ok_or(RomError::Truncated)?;
+
+        Ok(Rom {
+            mapper: (flags7 & 0xF0) | (flags6 >> 4),
+            mirroring,
+            battery: flags6 & 0b10 != 0,
+            prg,
+            chr,
+        })
+    }
+
+    /// Size of a whole image from just its header, so one sitting at a raw
+    /// address (e.g. put there with QEMU's `-device loader`) can be sliced.
+    pub fn image_size(header: &[u8; HEADER_SIZE]) -> Result<usize, RomError> {
+        if &header[..4] != INES_MAGIC {
+            return Err(RomError::BadMagic);
+        }
+        let trainer = if header[6] & 0b100 != 0 { TRAINER_SIZE } else { 0 };
+        Ok(HEADER_SIZE
+            + trainer
+            + header[4] as usize * PRG_BANK_SIZE
+            + header[5] as usize * CHR_BANK_SIZE)
+    }
+}
@@ -1,30 +1,45 @@
 // Dylaedin Operating System
 // Caedin and Dylan
 // 10/1/2025
-#![no_std]
+#![cfg_attr(not(test), no_std)]
 #![allow(unused_variables)]
 #![allow(dead_code)]
-#![cfg(not(test))]
+// Host unit tests only build the parsers, not the kernel that uses them.
+#![cfg_attr(test, allow(unused_imports))]
+extern crate alloc;
 /*
     Mods
 */
 mod dev;
 mod emu;
+mod mm;
+mod plt;
+mod sbi;
+mod smp;
 mod srv;
 mod util;
 /*
    Idk Stuff Here ;)
 */
+use alloc::{boxed::Box, collections::BTreeMap, string::String, vec, vec::Vec};
+use core::sync::atomic::{AtomicUsize, Ordering};
 use core::{arch::asm, assert, panic::PanicInfo};
-use dev::uart::Uart;
-use dev::{pci, vga::*};
+use dev::{clint, pci, plic, uart, vga::*};
 use embedded_graphics::{pixelcolor::Rgb888, prelude::RgbColor};
 use srv::console::Console;
-use util::{alloc::Alloc, interrupt, thread::Thread};
+use util::{alloc::{Alloc, AllocError, PAGE_SIZE}, interrupt, thread::Thread};
 /*
     Globals
 */
 const TEST_STRING: &str = "TEST";
+// NTSC NES frame period, 1 / 60.0988 Hz.
+const FRAME_PERIOD_MICROS: u64 = 16_639;
+// CPU the frame loop is guaranteed every period.
+const FRAME_BUDGET_MICROS: u64 = 12_000;
+// How often the shell thread checks for input.
+const SHELL_POLL_MICROS: u64 = 10_000;
+// Bumped by the frame loop once per frame.
+static FRAMES: AtomicUsize = AtomicUsize::new(0);
 
 // ///////////////////////////////////
 // / RUST MACROS
@@ -33,8 +48,7 @@ const TEST_STRING: &str = "TEST";
 macro_rules! print
 {
 	($($args:tt)+) => ({
-		use core::fmt::Write;
-		let _ = write!(crate::dev::uart::Uart::new(0x1000_0000 as *mut u8), $($args)+);
+		crate::dev::uart::print_fmt(format_args!($($args)+));
 	});
 }
 #[macro_export]
@@ -54,8 +68,12 @@ macro_rules! println
 // ///////////////////////////////////
 // / LANGUAGE STRUCTURES / FUNCTIONS
 // ///////////////////////////////////
+#[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
+    // We may be inside a trap with interrupts off, so nothing would ever
+    // drain the TX ring. We may also be holding the console.
+    uart::panic_console().set_mode(uart::Mode::Polled);
     print!("Aborting: ");
     if let Some(_p) = info.location() {
         println!("line {}, file {}: {}", _p.line(), _p.file(), info.message());
@@ -69,32 +87,74 @@ fn panic(info: &PanicInfo) -> ! {
     }
 }
 
-#[no_mangle]
-fn get_dts() -> u64 {
-    let value: u64;
-    unsafe {
-        asm!(
-            "mv {0}, a2",
-            out(reg) value
-        );
+// Host tests link the kernel's Rust but not its assembly. Nothing they run
+// switches threads or calls the firmware, these only satisfy the linker.
+#[cfg(test)]
+mod host {
+    use crate::sbi::SbiRet;
+    use crate::util::thread::Context;
+
+    #[no_mangle]
+    extern "C" fn switch_context(_old: *mut Context, _new: *const Context) {
+        unreachable!("no context switches in host tests");
+    }
+
+    #[no_mangle]
+    extern "C" fn sbi_ecall(_: usize, _: usize, _: usize, _: usize, _: usize, _: usize) -> SbiRet {
+        unreachable!("no firmware in host tests");
     }
-    return value;
 }
 
 // Put all inits here.
+#[cfg(not(test))]
 #[no_mangle]
-fn k_init() {
+extern "C" fn k_init(hartid: usize, dtb: usize) {
+    // hart_id() and everything per hart read tp.
+    smp::init_hart(hartid);
+    // Everything else sizes itself from the platform, so this goes first.
+    if let Err(e) = plt::init(dtb) {
+        println!("Bad device tree at {:#X}: {:?}, using defaults", dtb, e);
+    }
+    sbi::init();
     Alloc::init();
+    srv::console::init();
+    mm::paging::init();
+    clint::init();
+    Thread::init();
+    plic::init();
     interrupt::init();
+    uart::init();
+    util::syscall::init();
+    util::exec::init();
+    util::ipc::init();
+    dev::driver::init();
+    srv::names::init();
+    util::process::init();
+    smp::start_secondaries();
+    interrupt::enable_global();
     kmain();
 }
 
+// Where each secondary hart lands from `secondary_entry`, on the boot stack
+// `smp::start_secondaries` gave it.
+#[cfg(not(test))]
+#[no_mangle]
+extern "C" fn k_init_hart(hartid: usize) {
+    smp::init_hart(hartid);
+    mm::paging::init_hart();
+    clint::init_hart();
+    plic::init_hart();
+    interrupt::init_hart();
+    Thread::init_hart();
+    smp::arrive();
+    interrupt::enable_global();
+    Thread::idle();
+}
+
+#[cfg(not(test))]
 #[no_mangle]
 fn kmain() {
-    // Getting the device tree from a register
-    let device_tree_addr: u64 = get_dts();
-    let kernel_uart: Uart = Uart::new(0x1000_0000 as *mut u8);
-    // let kconsole: Console = Console::new(kernel_uart);
+    let mut kconsole: Console = Console::new();
     println!("Hello, World!");
     //printsizeof PCIHeader0
     println!(
@@ -106,6 +166,10 @@ fn kmain() {
     println!("Device ID: {:#X}", pci.header.device_id);
     println!("Class Code: {:#X}", pci.header.class_code);
     println!("Subclass: {:#X}", pci.header.subclass);
+    match pci.irq() {
+        Some(irq) => println!("INTx IRQ: {}", irq),
+        None => println!("INTx IRQ: none"),
+    }
     //check the first outside the address range
     // pci.header.command().set_memory_space(true);
     unsafe {
@@ -118,13 +182,14 @@ fn kmain() {
 
     // Testing allocator
     println!("[TESTING]: Testing Allocator and Free");
+    let free_before = Alloc::free_blocks();
     let page = Alloc::get(2).expect("Page not allocated");
     let page2 = Alloc::get(2).expect("Page not allocated");
     let page3 = Alloc::get(2).expect("err");
     let page4 = Alloc::get(2).expect("err");
     let page5 = Alloc::get(2).expect("");
-    Alloc::free(page2);
-    Alloc::free(page4);
+    Alloc::free(page2).expect("FAIL");
+    Alloc::free(page4).expect("FAIL");
     let page6 = Alloc::get(1).expect("err");
     let page7 = Alloc::get(1).expect("err");
 
@@ -132,40 +197,63 @@ fn kmain() {
     let page9 = Alloc::get(1).expect("err");
     let page10 = Alloc::get(1).expect("err");
 
+    // Blocks are aligned to their size and never overlap.
+    let doubles = [page, page3, page5];
+    let singles = [page6, page7, page8, page9, page10];
+    for p in doubles {
+        assert!((p as usize).is_multiple_of(2 * PAGE_SIZE), "FAIL");
+    }
+    for (i, a) in doubles.iter().chain(singles.iter()).enumerate() {
+        for b in doubles.iter().chain(singles.iter()).skip(i + 1) {
+            let (a, b) = (*a as usize, *b as usize);
+            assert!(a != b, "FAIL");
+            if a < b {
+                assert!(b - a >= PAGE_SIZE, "FAIL");
+            } else {
+                assert!(a - b >= PAGE_SIZE, "FAIL");
+            }
+        }
+        assert!((*a as usize).is_multiple_of(PAGE_SIZE), "FAIL");
+    }
+    assert!(Alloc::stats().used == 3 * 2 + 5, "FAIL");
+
+    Alloc::free(page).expect("FAIL");
+    Alloc::free(page3).expect("FAIL");
+    Alloc::free(page5).expect("FAIL");
+    Alloc::free(page6).expect("FAIL");
+    Alloc::free(page7).expect("FAIL");
+    Alloc::free(page8).expect("FAIL");
+    Alloc::free(page9).expect("FAIL");
+    Alloc::free(page10).expect("FAIL");
+    // Double free, page10 may have merged into a bigger free block.
     assert!(
-        ((page5 as *const usize as usize) - (page7 as *const usize as usize)) / 4098 == 5,
-        "[FAIL]"
-    );
-    assert!(
-        ((page5 as *const usize as usize) - (page7 as *const usize as usize)) % 4098 == 0,
-        "FAIL"
-    );
-    assert!(
-        ((page9 as *const usize as usize) - (page7 as *const usize as usize)) / 4098 == 4,
-        "FAIL"
-    );
-    assert!(
-        ((page9 as *const usize as usize) - (page7 as *const usize as usize)) % 4098 == 0,
-        "FAIL"
-    );
-    assert!(
-        ((page10 as *const usize as usize) - (page as *const usize as usize)) / 4098 == 10,
-        "FAIL"
-    );
-    assert!(
-        ((page10 as *const usize as usize) - (page as *const usize as usize)) % 4098 == 0,
+        matches!(
+            Alloc::free(page10),
+            Err(AllocError::NotAllocated | AllocError::NotStart)
+        ),
         "FAIL"
     );
+    assert!(Alloc::stats().used == 0, "FAIL");
+    // Everything merged back into the blocks it came from.
+    assert!(Alloc::free_blocks() == free_before, "FAIL");
     println!("[PASS]");
 
-    Alloc::free(page);
-    Alloc::free(page3);
-    Alloc::free(page5);
-    Alloc::free(page6);
-    Alloc::free(page7);
-    Alloc::free(page8);
-    Alloc::free(page9);
-    Alloc::free(page10);
+    println!("[TESTING]: Testing Heap");
+    let mut numbers: Vec<u32> = Vec::new();
+    for i in 0..1000 {
+        numbers.push(i);
+    }
+    let big = vec![0xAAu8; 3 * 4096];
+    let mut names = BTreeMap::new();
+    names.insert(String::from("nes"), Box::new(numbers.len()));
+    assert!(numbers.iter().sum::<u32>() == 499_500, "FAIL");
+    assert!(big.iter().all(|b| *b == 0xAA), "FAIL");
+    assert!(**names.get("nes").expect("FAIL") == 1000, "FAIL");
+    drop(numbers);
+    drop(big);
+    drop(names);
+    assert!(util::heap::stats().in_use == 0, "FAIL");
+    println!("[PASS]");
 
     //print out address
     // for i in 0..6 {
@@ -173,7 +261,7 @@ fn kmain() {
     // }
     //try to find bochs version
 
-    let vga = dev::vga::VGA::new(0, 1, 0x4000_0000).unwrap();
+    let vga = dev::vga::VGA::new(0, 1, plt::get().pci_mmio_base).unwrap();
     // println!("Bochs version: {:#X}", vga.get_bochs_version());
     let mut display = ModeXDisplay::new(vga, 640, 480); //unsafe { Mode13Display::new(vga.fb) };
     display.rectangle(0, 0, 256, 240, Rgb888::BLUE);
@@ -192,20 +280,36 @@ fn kmain() {
     // // println!("Vendor ID: {:#X}", result);/
 
     // // kconsole.listen();
-    let max = 250000000 / 60;
-    let mut i = 0;
-    loop {
-        if i == 0 {
+    // The shell gets a thread of its own, kmain carries on as the frame loop.
+    let shell = Thread::spawn("shell", 1, move || {
+        kconsole.prompt();
+        loop {
+            kconsole.poll();
+            Thread::sleep(SHELL_POLL_MICROS);
+        }
+    });
+    if let Err(e) = shell {
+        println!("[THREAD]: couldn't start the shell: {:?}", e);
+    }
+    // One frame per period, ahead of the shell and drivers.
+    if let Err(e) = Thread::set_periodic(FRAME_PERIOD_MICROS, FRAME_BUDGET_MICROS) {
+        println!("[EDF]: frame loop has no deadline: {:?}", e);
+    }
+    // Until the display is released to a user-space driver.
+    while dev::driver::in_kernel("vga") {
+        let frame = FRAMES.fetch_add(1, Ordering::Relaxed);
+        if frame.is_multiple_of(2) {
             display.rectangle(0, 0, display.width, display.height, Rgb888::WHITE);
-        } else if i == max / 2 {
+        } else {
             display.rectangle(0, 0, display.width, display.height, Rgb888::BLUE);
         }
-        i += 1;
         // display.swap_buffer();
         // println!("Swap")
-        if i == max {
-            i = 0;
+        if Thread::wait_next_period().is_err() {
+            Thread::sleep(FRAME_PERIOD_MICROS);
         }
     }
-    //get bochs version
+    Thread::clear_periodic();
+    println!("[VGA]: released, the frame loop is done");
+    Thread::exit();
 }
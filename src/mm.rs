@@ -0,0 +1,4 @@
+//! Memory management.
+pub mod paging;
+pub mod region;
+pub mod space;
@@ -0,0 +1,410 @@
+//! Sv39 page tables.
+//!
+//! Three levels of 512 entries, each level translating 9 bits of a 39-bit
+//! virtual address. A leaf can sit at any level, giving 4 KiB, 2 MiB or
+//! 1 GiB pages. Table pages come from the page allocator.
+//!
+//! The kernel is identity mapped: text RX, rodata R, data, bss and the heap
+//! RW, MMIO RW and never executable. `satp` only translates S and U mode
+//! accesses, so the kernel table takes effect once we run in S-mode.
+use crate::plt;
+use crate::print;
+use crate::println;
+use crate::smp::ipi;
+use crate::srv::console::{self, parse_number, Command};
+use crate::util::alloc::{Alloc, PAGE_SIZE};
+use crate::util::once::Once;
+use core::arch::asm;
+
+// PTE bits.
+pub const VALID: usize = 1 << 0;
+pub const READ: usize = 1 << 1;
+pub const WRITE: usize = 1 << 2;
+pub const EXECUTE: usize = 1 << 3;
+pub const USER: usize = 1 << 4;
+pub const GLOBAL: usize = 1 << 5;
+pub const ACCESSED: usize = 1 << 6;
+pub const DIRTY: usize = 1 << 7;
+
+// Common leaf permissions. A and D are set up front so nothing faults on
+// hardware that doesn't update them itself.
+pub const KERNEL_RX: usize = READ | EXECUTE | GLOBAL | ACCESSED;
+pub const KERNEL_R: usize = READ | GLOBAL | ACCESSED;
+pub const KERNEL_RW: usize = READ | WRITE | GLOBAL | ACCESSED | DIRTY;
+pub const MMIO: usize = KERNEL_RW;
+
+const ENTRIES: usize = 512;
+const LEVELS: usize = 3;
+const PPN_SHIFT: usize = 10;
+const SATP_MODE_SV39: usize = 8 << 60;
+
+// MMIO sizes used when there is no device tree to read them from.
+const VIRT_CLINT_SIZE: usize = 0x1_0000;
+const VIRT_PLIC_SIZE: usize = 0x60_0000;
+const VIRT_TEST_BASE: usize = 0x10_0000;
+
+extern "C" {
+    static _text_start: usize;
+    static _text_end: usize;
+    static _rodata_start: usize;
+    static _rodata_end: usize;
+    static _data_start: usize;
+    static _data_end: usize;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    // Address or size not aligned to the page size asked for.
+    Misaligned,
+    // Something different is already mapped there.
+    AlreadyMapped,
+    // No memory for another table.
+    OutOfMemory,
+    // Nothing mapped there, or not with the access asked for.
+    NotMapped,
+    // Outside the part of the address space the caller may use.
+    OutOfRange,
+    // Permissions that make no sense for a page, e.g. none at all.
+    InvalidFlags,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct Entry(usize);
+
+impl Entry {
+    pub const EMPTY: Entry = Entry(0);
+
+    pub fn new(pa: usize, flags: usize) -> Entry {
+        Entry(((pa / PAGE_SIZE) << PPN_SHIFT) | flags | VALID)
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.0 & VALID != 0
+    }
+
+    // Any of R/W/X set means this is a leaf, otherwise it points at the
+    // next level.
+    pub fn is_leaf(&self) -> bool {
+        self.0 & (READ | WRITE | EXECUTE) != 0
+    }
+
+    pub fn flags(&self) -> usize {
+        self.0 & ((1 << PPN_SHIFT) - 1)
+    }
+
+    pub fn address(&self) -> usize {
+        (self.0 >> PPN_SHIFT) * PAGE_SIZE
+    }
+
+    fn table(&self) -> *mut PageTable {
+        self.address() as *mut PageTable
+    }
+}
+
+#[repr(C, align(4096))]
+pub struct PageTable {
+    pub entries: [Entry; ENTRIES],
+}
+
+/// Bytes covered by one entry at `level` (0 = 4 KiB leaves).
+pub const fn level_size(level: usize) -> usize {
+    PAGE_SIZE << (9 * level)
+}
+
+fn vpn(va: usize, level: usize) -> usize {
+    (va >> (12 + 9 * level)) & (ENTRIES - 1)
+}
+
+impl PageTable {
+    /// A zeroed table from the page allocator.
+    pub fn new() -> Option<&'static mut PageTable> {
+        let page = Alloc::zalloc(1)?;
+        Some(unsafe { &mut *(page as *mut PageTable) })
+    }
+
+    /// Map one page of `level_size(level)` bytes.
+    pub fn map(
+        &mut self,
+        va: usize,
+        pa: usize,
+        flags: usize,
+        level: usize,
+    ) -> Result<(), MapError> {
+        let size = level_size(level);
+        if !va.is_multiple_of(size) || !pa.is_multiple_of(size) {
+            return Err(MapError::Misaligned);
+        }
+        let mut table: *mut PageTable = self;
+        for l in (level + 1..LEVELS).rev() {
+            let entry = unsafe { &mut (*table).entries[vpn(va, l)] };
+            if !entry.is_valid() {
+                let next = PageTable::new().ok_or(MapError::OutOfMemory)?;
+                *entry = Entry::new(next as *mut PageTable as usize, 0);
+            } else if entry.is_leaf() {
+                return Err(MapError::AlreadyMapped);
+            }
+            table = entry.table();
+        }
+        let entry = unsafe { &mut (*table).entries[vpn(va, level)] };
+        let new = Entry::new(pa, flags);
+        if entry.is_valid() && *entry != new {
+            return Err(MapError::AlreadyMapped);
+        }
+        *entry = new;
+        Ok(())
+    }
+
+    /// Map [va, va + size) to [pa, pa + size) with the biggest pages that
+    /// fit. `size` is rounded up to a page.
+    pub fn map_range(
+        &mut self,
+        va: usize,
+        pa: usize,
+        size: usize,
+        flags: usize,
+    ) -> Result<(), MapError> {
+        if !va.is_multiple_of(PAGE_SIZE) || !pa.is_multiple_of(PAGE_SIZE) {
+            return Err(MapError::Misaligned);
+        }
+        let end = va + size.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let mut offset = 0;
+        while va + offset < end {
+            let (v, p) = (va + offset, pa + offset);
+            let level = (0..LEVELS)
+                .rev()
+                .find(|&l| {
+                    let size = level_size(l);
+                    v.is_multiple_of(size) && p.is_multiple_of(size) && v + size <= end
+                })
+                .unwrap_or(0);
+            self.map(v, p, flags, level)?;
+            offset += level_size(level);
+        }
+        Ok(())
+    }
+
+    /// Identity map `size` bytes from `addr`, widening to whole pages.
+    pub fn identity_map(&mut self, addr: usize, size: usize, flags: usize) -> Result<(), MapError> {
+        let start = addr & !(PAGE_SIZE - 1);
+        self.map_range(start, start, addr + size - start, flags)
+    }
+
+    // The leaf for `va` and the level it's at.
+    fn leaf(&self, va: usize) -> Option<(*mut Entry, usize)> {
+        let mut table = self as *const PageTable as *mut PageTable;
+        for level in (0..LEVELS).rev() {
+            let entry = unsafe { &mut (*table).entries[vpn(va, level)] };
+            if !entry.is_valid() {
+                return None;
+            }
+            if entry.is_leaf() {
+                return Some((entry, level));
+            }
+            table = entry.table();
+        }
+        None
+    }
+
+    /// Physical address `va` maps to.
+    pub fn translate(&self, va: usize) -> Option<usize> {
+        let (entry, level) = self.leaf(va)?;
+        let offset = va & (level_size(level) - 1);
+        Some(unsafe { (*entry).address() } + offset)
+    }
+
+    /// The leaf entry for `va`.
+    pub fn lookup(&self, va: usize) -> Option<Entry> {
+        self.leaf(va).map(|(entry, _)| unsafe { *entry })
+    }
+
+    /// Remove the mapping for the page holding `va`, returning the
+    /// physical address it pointed at. Empty tables are left in place.
+    pub fn unmap(&mut self, va: usize) -> Option<usize> {
+        let (entry, _) = self.leaf(va)?;
+        unsafe {
+            let pa = (*entry).address();
+            *entry = Entry::EMPTY;
+            Some(pa)
+        }
+    }
+
+    /// Free this table and every table below it. What the leaves point at
+    /// is the caller's business.
+    ///
+    /// # Safety
+    /// The table mustn't be in use on any hart, or used again.
+    pub unsafe fn destroy(&mut self) {
+        for entry in self.entries.iter() {
+            if entry.is_valid() && !entry.is_leaf() {
+                (*entry.table()).destroy();
+            }
+        }
+        let _ = Alloc::free(self as *mut PageTable);
+    }
+
+    /// Value for `satp` to run on this table.
+    pub fn satp(&self) -> usize {
+        SATP_MODE_SV39 | (self as *const PageTable as usize / PAGE_SIZE)
+    }
+}
+
+// Address of the kernel's table. Set once by `init`, read by every hart.
+static KERNEL_TABLE: Once<usize> = Once::new();
+
+/// The kernel's page table, once `init` has built it.
+pub fn kernel_table() -> Option<&'static mut PageTable> {
+    let addr = *KERNEL_TABLE.get()?;
+    Some(unsafe { &mut *(addr as *mut PageTable) })
+}
+
+/// `satp` for the kernel table, what kernel threads run on.
+pub fn kernel_satp() -> usize {
+    kernel_table().map_or(0, |table| table.satp())
+}
+
+fn symbol(sym: &usize) -> usize {
+    sym as *const usize as usize
+}
+
+/// Identity map the kernel image, RAM and MMIO into `table`.
+pub fn map_kernel(table: &mut PageTable) -> Result<(), MapError> {
+    let platform = plt::get();
+    let (text, text_end, rodata, rodata_end, data, data_end) = unsafe {
+        (
+            symbol(&_text_start),
+            symbol(&_text_end),
+            symbol(&_rodata_start),
+            symbol(&_rodata_end),
+            symbol(&_data_start),
+            symbol(&_data_end),
+        )
+    };
+    table.map_range(text, text, text_end - text, KERNEL_RX)?;
+    table.map_range(rodata, rodata, rodata_end - rodata, KERNEL_R)?;
+    table.map_range(data, data, data_end - data, KERNEL_RW)?;
+    // The rest of RAM: page allocator, boot stack and device tree.
+    let ram_end = platform.memory_base + platform.memory_size;
+    table.map_range(data_end, data_end, ram_end - data_end, KERNEL_RW)?;
+
+    map_mmio(table)
+}
+
+fn map_mmio(table: &mut PageTable) -> Result<(), MapError> {
+    let platform = plt::get();
+    match plt::fdt() {
+        // Every register block on the SoC bus.
+        Some(fdt) => {
+            if let Some(soc) = fdt.find_node("/soc") {
+                for device in soc.children() {
+                    for region in device.reg() {
+                        table.identity_map(region.address as usize, region.size as usize, MMIO)?;
+                    }
+                }
+            }
+        }
+        None => {
+            table.identity_map(platform.uart_base, PAGE_SIZE, MMIO)?;
+            table.identity_map(platform.clint_base, VIRT_CLINT_SIZE, MMIO)?;
+            table.identity_map(platform.plic_base, VIRT_PLIC_SIZE, MMIO)?;
+            table.identity_map(platform.pci_ecam_base, platform.pci_ecam_size, MMIO)?;
+            table.identity_map(VIRT_TEST_BASE, PAGE_SIZE, MMIO)?;
+        }
+    }
+    // Where BARs get assigned, the VGA framebuffer included.
+    table.identity_map(platform.pci_mmio_base, platform.pci_mmio_size, MMIO)
+}
+
+/// Switch translation to `satp` and flush the TLB.
+#[cfg(not(test))]
+pub fn activate(satp: usize) {
+    unsafe {
+        asm!("csrw satp, {0}", "sfence.vma", in(reg) satp);
+    }
+}
+
+// Host tests have no satp, see `interrupt::enable_global`.
+#[cfg(test)]
+pub fn activate(_satp: usize) {}
+
+fn flush_tlb() {
+    unsafe {
+        asm!("sfence.vma");
+    }
+}
+
+/// Flush the TLB of every online hart, after changing a table they may all
+/// have cached entries from.
+pub fn shootdown() {
+    ipi::call_all(flush_tlb);
+}
+
+/// Build the kernel page table and install it.
+pub fn init() {
+    let table = match PageTable::new() {
+        Some(table) => table,
+        None => panic!("[PAGING]: no memory for the kernel page table"),
+    };
+    if let Err(e) = map_kernel(table) {
+        panic!("[PAGING]: mapping the kernel failed: {:?}", e);
+    }
+    activate(table.satp());
+    let _ = KERNEL_TABLE.set(table as *mut PageTable as usize);
+    let _ = console::register(Command {
+        name: "vtop",
+        usage: "vtop <addr>",
+        help: "translate an address through the kernel page table",
+        handler: vtop_command,
+    });
+    println!(
+        "[PAGING]: Sv39 kernel table at {:#X}",
+        table as *mut PageTable as usize
+    );
+}
+
+/// Switch a secondary hart onto the kernel table `init` built.
+pub fn init_hart() {
+    if let Some(table) = kernel_table() {
+        activate(table.satp());
+    }
+}
+
+fn describe(flags: usize) -> [u8; 5] {
+    let bit = |mask: usize, c: u8| if flags & mask != 0 { c } else { b'-' };
+    [
+        bit(READ, b'r'),
+        bit(WRITE, b'w'),
+        bit(EXECUTE, b'x'),
+        bit(USER, b'u'),
+        bit(GLOBAL, b'g'),
+    ]
+}
+
+fn vtop_command(args: &[&str]) {
+    let va = match args.first().and_then(|a| parse_number(a)) {
+        Some(va) => va,
+        None => {
+            println!("usage: vtop <addr>");
+            return;
+        }
+    };
+    let table = match kernel_table() {
+        Some(table) => table,
+        None => {
+            println!("paging is not set up");
+            return;
+        }
+    };
+    match (table.translate(va), table.lookup(va)) {
+        (Some(pa), Some(entry)) => {
+            let flags = describe(entry.flags());
+            println!(
+                "{:#X} -> {:#X} {}",
+                va,
+                pa,
+                core::str::from_utf8(&flags).unwrap_or("?")
+            );
+        }
+        _ => println!("{:#X} is not mapped", va),
+    }
+}
@@ -0,0 +1,96 @@
+//! Physically contiguous memory that can outlive any one mapping of it.
+//!
+//! A `Region` is a run of zeroed frames from the page allocator, shared by
+//! `Arc`: every page an address space maps from it holds a reference, as
+//! does every capability for it, and the frames go back to the allocator
+//! with the last one. Contiguous so a device can DMA to it.
+//!
+//! Shared memory is a region mapped into more than one space. Each space
+//! that maps it is noted, so that `unmap_everywhere` can take it back out
+//! of all of them when its creator destroys it. After that nothing more may
+//! map it.
+use crate::mm::paging::MapError;
+use crate::mm::space::{AddressSpace, Spaces};
+use crate::util::alloc::{Alloc, PAGE_SIZE};
+use crate::util::lock::Mutex;
+use alloc::sync::Arc;
+
+pub struct Region {
+    base: usize,
+    size: usize,
+    // Made by shm_create, and so for it to destroy, rather than for DMA.
+    shared: bool,
+    // Spaces that have mapped some of it, None once it's been destroyed.
+    spaces: Mutex<Option<Spaces>>,
+}
+
+impl Region {
+    /// `size` bytes, rounded up to whole pages.
+    pub fn new(size: usize) -> Result<Arc<Region>, MapError> {
+        Region::alloc(size, false)
+    }
+
+    /// `size` bytes of shared memory, rounded up to whole pages.
+    pub fn shared(size: usize) -> Result<Arc<Region>, MapError> {
+        Region::alloc(size, true)
+    }
+
+    fn alloc(size: usize, shared: bool) -> Result<Arc<Region>, MapError> {
+        let pages = size.div_ceil(PAGE_SIZE);
+        if pages == 0 {
+            return Err(MapError::OutOfRange);
+        }
+        let base = Alloc::zalloc(pages).ok_or(MapError::OutOfMemory)? as usize;
+        Ok(Arc::new(Region {
+            base,
+            size: pages * PAGE_SIZE,
+            shared,
+            spaces: Mutex::new(Some(Spaces::new())),
+        }))
+    }
+
+    /// Physical address of the first byte.
+    pub fn base(&self) -> usize {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Whether it's shared memory, from `shared`.
+    pub fn is_shared(&self) -> bool {
+        self.shared
+    }
+
+    /// Note that `space` has mapped some of the region. Call it without
+    /// `space` locked. False if the region has been destroyed, which the
+    /// mapping missed, so the caller must unmap it again.
+    pub fn mapped_in(&self, space: &Arc<Mutex<AddressSpace>>) -> bool {
+        match self.spaces.lock().as_mut() {
+            Some(spaces) => {
+                spaces.add(space);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Unmap the region from every space it's mapped in, for good: it can't
+    /// be mapped again. The frames go back once nothing else refers to it.
+    pub fn unmap_everywhere(self: &Arc<Self>) {
+        let spaces = match self.spaces.lock().take() {
+            Some(mut spaces) => spaces.take(),
+            None => return,
+        };
+        for space in spaces {
+            space.lock().unmap_region(self);
+        }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        let _ = Alloc::free(self.base as *const u8);
+    }
+}
@@ -0,0 +1,468 @@
+//! User address spaces.
+//!
+//! Each one is an Sv39 table of its own with the kernel mapped just as in
+//! the kernel table, without the U bit so user code can't touch it, plus
+//! user pages in [USER_BASE, USER_END), clear of everything the kernel
+//! identity maps.
+//!
+//! The kernel never dereferences a user pointer. `copy_in` and `copy_out`
+//! walk the table, check every page is a user page with the access they
+//! need and go through the physical address, so a bad pointer from user
+//! space is an error rather than a fault.
+use crate::mm::paging::{self, MapError, PageTable, ACCESSED, DIRTY, EXECUTE, READ, USER, WRITE};
+use crate::mm::region::Region;
+use crate::util::alloc::{Alloc, PAGE_SIZE};
+use crate::util::lock::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+pub const USER_BASE: usize = 0x10_0000_0000;
+// The top of the lower half of Sv39, the most a user address can be.
+pub const USER_END: usize = 0x40_0000_0000;
+// Where `find_free` starts looking, leaving the bottom for program images.
+const MAP_BASE: usize = 0x20_0000_0000;
+
+// Where a page's frame came from, and so what happens to it when the page
+// goes.
+enum Backing {
+    // From the page allocator by `map`, freed with the page.
+    Owned,
+    // Device memory such as the framebuffer, which isn't ours to free.
+    Device,
+    // Part of a region, which lives on until nothing refers to it.
+    Region(Arc<Region>),
+}
+
+struct Page {
+    pa: usize,
+    backing: Backing,
+}
+
+impl Drop for Page {
+    fn drop(&mut self) {
+        if let Backing::Owned = self.backing {
+            let _ = Alloc::free(self.pa as *const u8);
+        }
+    }
+}
+
+/// Pages taken out of one space by `take`, on their way to another. What
+/// never arrives is freed.
+pub struct Frames {
+    // Each page with its PTE flags, in address order.
+    pages: Vec<(Page, usize)>,
+}
+
+impl Frames {
+    pub fn len(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pages.is_empty()
+    }
+
+    /// Physical addresses of the pages that are device memory.
+    pub fn device_pages(&self) -> Vec<usize> {
+        let device = self
+            .pages
+            .iter()
+            .filter(|(page, _)| matches!(page.backing, Backing::Device));
+        device.map(|(page, _)| page.pa).collect()
+    }
+
+    /// The regions the pages belong to, if any do.
+    pub fn regions(&self) -> Vec<Arc<Region>> {
+        let mut regions: Vec<Arc<Region>> = Vec::new();
+        for (page, _) in &self.pages {
+            if let Backing::Region(region) = &page.backing {
+                if !regions.iter().any(|r| Arc::ptr_eq(r, region)) {
+                    regions.push(region.clone());
+                }
+            }
+        }
+        regions
+    }
+}
+
+/// The spaces something has been mapped into, so that it can be taken back
+/// out of them. Some may have unmapped it since.
+#[derive(Default)]
+pub struct Spaces {
+    spaces: Vec<Weak<Mutex<AddressSpace>>>,
+}
+
+impl Spaces {
+    pub const fn new() -> Spaces {
+        Spaces { spaces: Vec::new() }
+    }
+
+    pub fn add(&mut self, space: &Arc<Mutex<AddressSpace>>) {
+        let space = Arc::downgrade(space);
+        self.spaces.retain(|s| s.strong_count() > 0);
+        if !self.spaces.iter().any(|s| s.ptr_eq(&space)) {
+            self.spaces.push(space);
+        }
+    }
+
+    /// Empty the set, handing back the spaces that are still about.
+    pub fn take(&mut self) -> Vec<Arc<Mutex<AddressSpace>>> {
+        let spaces = core::mem::take(&mut self.spaces);
+        spaces.iter().filter_map(Weak::upgrade).collect()
+    }
+}
+
+pub struct AddressSpace {
+    table: &'static mut PageTable,
+    // Every user page by virtual address.
+    pages: BTreeMap<usize, Page>,
+}
+
+// End of [va, va + len) once it's checked to be whole pages of user space.
+fn user_range(va: usize, len: usize) -> Result<usize, MapError> {
+    if !va.is_multiple_of(PAGE_SIZE) {
+        return Err(MapError::Misaligned);
+    }
+    let end = len
+        .checked_next_multiple_of(PAGE_SIZE)
+        .and_then(|len| va.checked_add(len))
+        .ok_or(MapError::OutOfRange)?;
+    if len == 0 || va < USER_BASE || end > USER_END {
+        return Err(MapError::OutOfRange);
+    }
+    Ok(end)
+}
+
+// PTE bits for a user page with `flags`, some of READ, WRITE and EXECUTE.
+fn leaf_flags(flags: usize) -> Result<usize, MapError> {
+    if flags == 0 || flags & !(READ | WRITE | EXECUTE) != 0 {
+        return Err(MapError::InvalidFlags);
+    }
+    // Write-only is a reserved encoding.
+    let flags = if flags & WRITE != 0 {
+        flags | READ
+    } else {
+        flags
+    };
+    Ok(flags | USER | ACCESSED | DIRTY)
+}
+
+impl AddressSpace {
+    /// An empty space, just the kernel.
+    pub fn new() -> Result<AddressSpace, MapError> {
+        let table = PageTable::new().ok_or(MapError::OutOfMemory)?;
+        if let Err(e) = paging::map_kernel(table) {
+            unsafe { table.destroy() };
+            return Err(e);
+        }
+        Ok(AddressSpace {
+            table,
+            pages: BTreeMap::new(),
+        })
+    }
+
+    /// Value for `satp` to run in this space.
+    pub fn satp(&self) -> usize {
+        self.table.satp()
+    }
+
+    fn insert(&mut self, va: usize, page: Page, flags: usize) -> Result<(), MapError> {
+        self.table.map(va, page.pa, flags, 0)?;
+        self.pages.insert(va, page);
+        Ok(())
+    }
+
+    /// Back [va, va + len) with fresh zeroed pages. `flags` is some of
+    /// READ, WRITE and EXECUTE. Nothing can be mapped there already.
+    pub fn map(&mut self, va: usize, len: usize, flags: usize) -> Result<(), MapError> {
+        let end = user_range(va, len)?;
+        let flags = leaf_flags(flags)?;
+        if self.pages.range(va..end).next().is_some() {
+            return Err(MapError::AlreadyMapped);
+        }
+        for page in (va..end).step_by(PAGE_SIZE) {
+            let result = match Alloc::zalloc(1) {
+                Some(frame) => {
+                    let frame = Page {
+                        pa: frame as usize,
+                        backing: Backing::Owned,
+                    };
+                    self.insert(page, frame, flags)
+                }
+                None => Err(MapError::OutOfMemory),
+            };
+            if let Err(e) = result {
+                if page > va {
+                    let _ = self.unmap(va, page - va);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Map [va, va + len) onto the physical range at `pa`, e.g. a device's
+    /// registers. The pages stay the device's when they're unmapped.
+    pub fn map_physical(
+        &mut self,
+        va: usize,
+        pa: usize,
+        len: usize,
+        flags: usize,
+    ) -> Result<(), MapError> {
+        self.map_backed(va, pa, len, flags, || Backing::Device)
+    }
+
+    /// Map `len` bytes of `region`, from `offset` on, at `va`. The region
+    /// stays alive for as long as any of it is mapped.
+    pub fn map_region(
+        &mut self,
+        va: usize,
+        region: &Arc<Region>,
+        offset: usize,
+        len: usize,
+        flags: usize,
+    ) -> Result<(), MapError> {
+        if offset
+            .checked_add(len)
+            .is_none_or(|end| end > region.size())
+        {
+            return Err(MapError::OutOfRange);
+        }
+        let pa = region.base() + offset;
+        self.map_backed(va, pa, len, flags, || Backing::Region(region.clone()))
+    }
+
+    fn map_backed<B: Fn() -> Backing>(
+        &mut self,
+        va: usize,
+        pa: usize,
+        len: usize,
+        flags: usize,
+        backing: B,
+    ) -> Result<(), MapError> {
+        let end = user_range(va, len)?;
+        let flags = leaf_flags(flags)?;
+        if !pa.is_multiple_of(PAGE_SIZE) {
+            return Err(MapError::Misaligned);
+        }
+        if self.pages.range(va..end).next().is_some() {
+            return Err(MapError::AlreadyMapped);
+        }
+        for page in (va..end).step_by(PAGE_SIZE) {
+            let frame = Page {
+                pa: pa + (page - va),
+                backing: backing(),
+            };
+            if let Err(e) = self.insert(page, frame, flags) {
+                if page > va {
+                    let _ = self.unmap(va, page - va);
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmap every page in [va, va + len), freeing the ones `map` made.
+    /// Fails if there was nothing there at all.
+    pub fn unmap(&mut self, va: usize, len: usize) -> Result<(), MapError> {
+        let end = user_range(va, len)?;
+        let gone: Vec<usize> = self.pages.range(va..end).map(|(&va, _)| va).collect();
+        if gone.is_empty() {
+            return Err(MapError::NotMapped);
+        }
+        let mut pages = Vec::new();
+        for va in gone {
+            self.table.unmap(va);
+            pages.extend(self.pages.remove(&va));
+        }
+        // Other threads in this space may have the pages in their TLBs.
+        // They have to be gone from there before the frames are reused.
+        paging::shootdown();
+        drop(pages);
+        Ok(())
+    }
+
+    /// Unmap every page of `region`, wherever it's mapped.
+    pub fn unmap_region(&mut self, region: &Arc<Region>) {
+        self.unmap_matching(
+            |page| matches!(&page.backing, Backing::Region(r) if Arc::ptr_eq(r, region)),
+        );
+    }
+
+    /// Unmap every page of device memory in [base, base + size).
+    pub fn unmap_device(&mut self, base: usize, size: usize) {
+        self.unmap_matching(|page| {
+            matches!(page.backing, Backing::Device) && (base..base + size).contains(&page.pa)
+        });
+    }
+
+    fn unmap_matching<F: Fn(&Page) -> bool>(&mut self, matches: F) {
+        let gone: Vec<usize> = self
+            .pages
+            .iter()
+            .filter(|(_, page)| matches(page))
+            .map(|(&va, _)| va)
+            .collect();
+        if gone.is_empty() {
+            return;
+        }
+        let mut pages = Vec::new();
+        for va in gone {
+            self.table.unmap(va);
+            pages.extend(self.pages.remove(&va));
+        }
+        paging::shootdown();
+        drop(pages);
+    }
+
+    /// Unmap every user page, for a process that's gone. The table itself
+    /// stays until the space is dropped.
+    pub fn clear(&mut self) {
+        for &va in self.pages.keys() {
+            self.table.unmap(va);
+        }
+        paging::shootdown();
+        self.pages.clear();
+    }
+
+    /// Take the pages of [va, va + len) out of this space, frames and all,
+    /// for `give` to put in another. Every page has to be mapped.
+    pub fn take(&mut self, va: usize, len: usize) -> Result<Frames, MapError> {
+        let end = user_range(va, len)?;
+        if self.pages.range(va..end).count() != (end - va) / PAGE_SIZE {
+            return Err(MapError::NotMapped);
+        }
+        let mut pages = Vec::new();
+        for page in (va..end).step_by(PAGE_SIZE) {
+            let flags = self.table.lookup(page).map_or(0, |entry| entry.flags());
+            self.table.unmap(page);
+            if let Some(frame) = self.pages.remove(&page) {
+                pages.push((frame, flags));
+            }
+        }
+        paging::shootdown();
+        Ok(Frames { pages })
+    }
+
+    /// Map `frames` at `va` with the permissions they had where they came
+    /// from, leaving `frames` empty. On failure they stay in `frames`.
+    pub fn give(&mut self, va: usize, frames: &mut Frames) -> Result<(), MapError> {
+        let end = user_range(va, frames.len())?;
+        if self.pages.range(va..end).next().is_some() {
+            return Err(MapError::AlreadyMapped);
+        }
+        let mut mapped = 0;
+        for (frame, flags) in &frames.pages {
+            if let Err(e) = self.table.map(va + mapped, frame.pa, *flags, 0) {
+                // Back out, leaving the pages where they were.
+                for page in (va..va + mapped).step_by(PAGE_SIZE) {
+                    self.table.unmap(page);
+                }
+                paging::shootdown();
+                return Err(e);
+            }
+            mapped += PAGE_SIZE;
+        }
+        for (i, (frame, _)) in frames.pages.drain(..).enumerate() {
+            self.pages.insert(va + i * PAGE_SIZE, frame);
+        }
+        Ok(())
+    }
+
+    /// The lowest free run of `len` bytes at or above `MAP_BASE`.
+    pub fn find_free(&self, len: usize) -> Option<usize> {
+        let len = len.checked_next_multiple_of(PAGE_SIZE)?;
+        let mut start = MAP_BASE;
+        for &va in self.pages.range(MAP_BASE..).map(|(va, _)| va) {
+            if va >= start + len {
+                break;
+            }
+            start = va + PAGE_SIZE;
+        }
+        (start.checked_add(len)? <= USER_END).then_some(start)
+    }
+
+    /// Whether every byte of [va, va + len) is in a user page with `need`
+    /// (some of READ, WRITE and EXECUTE) set.
+    pub fn check(&self, va: usize, len: usize, need: usize) -> bool {
+        self.walk(va, len, need, |_, _, _| {}).is_ok()
+    }
+
+    // Check every page of [va, va + len) is a user page with `need` set,
+    // then call `f(pa, offset, n)` for each piece of the range that lies in
+    // one page, `offset` being how far into the range the piece starts.
+    fn walk<F: FnMut(usize, usize, usize)>(
+        &self,
+        va: usize,
+        len: usize,
+        need: usize,
+        mut f: F,
+    ) -> Result<(), MapError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let end = va.checked_add(len).ok_or(MapError::OutOfRange)?;
+        if va < USER_BASE || end > USER_END {
+            return Err(MapError::OutOfRange);
+        }
+        let need = need | USER;
+        let mut page = va & !(PAGE_SIZE - 1);
+        while page < end {
+            match self.table.lookup(page) {
+                Some(entry) if entry.flags() & need == need => {}
+                _ => return Err(MapError::NotMapped),
+            }
+            page += PAGE_SIZE;
+        }
+        let mut offset = 0;
+        while offset < len {
+            let at = va + offset;
+            let n = (PAGE_SIZE - at % PAGE_SIZE).min(len - offset);
+            let pa = self.table.translate(at).ok_or(MapError::NotMapped)?;
+            f(pa, offset, n);
+            offset += n;
+        }
+        Ok(())
+    }
+
+    /// Copy `buf.len()` bytes in from user memory at `va`, which has to be
+    /// readable.
+    pub fn copy_in(&self, va: usize, buf: &mut [u8]) -> Result<(), MapError> {
+        let dst = buf.as_mut_ptr();
+        self.walk(va, buf.len(), READ, |pa, offset, n| unsafe {
+            core::ptr::copy_nonoverlapping(pa as *const u8, dst.add(offset), n);
+        })
+    }
+
+    /// Copy `data` out to user memory at `va`, which has to be writable.
+    pub fn copy_out(&self, va: usize, data: &[u8]) -> Result<(), MapError> {
+        self.walk(va, data.len(), WRITE, |pa, offset, n| unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr().add(offset), pa as *mut u8, n);
+        })
+    }
+
+    /// Copy `data` into user pages at `va` whatever their permissions, for
+    /// putting a program's code in place.
+    pub fn load(&self, va: usize, data: &[u8]) -> Result<(), MapError> {
+        self.walk(va, data.len(), 0, |pa, offset, n| unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr().add(offset), pa as *mut u8, n);
+        })
+    }
+
+    /// Bytes of user memory mapped.
+    pub fn size(&self) -> usize {
+        self.pages.len() * PAGE_SIZE
+    }
+}
+
+impl Drop for AddressSpace {
+    // By now no hart runs on the table: every thread in the space has gone,
+    // and switching away from one loaded another satp and flushed the TLB.
+    // The pages go after this, freeing what they own.
+    fn drop(&mut self) {
+        unsafe { self.table.destroy() };
+    }
+}
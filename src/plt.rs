@@ -0,0 +1,143 @@
+//! Platform description, filled in from the device tree at boot.
+//! Defaults are QEMU virt's so printing works before `init` has run.
+use crate::util::fdt::{Fdt, FdtError};
+
+// PCI phys.hi space codes (bits 24-25).
+const PCI_SPACE_MMIO32: u32 = 0x2;
+
+#[derive(Clone, Copy)]
+pub struct Platform {
+    pub fdt_addr: usize,
+    pub fdt_size: usize,
+
+    pub memory_base: usize,
+    pub memory_size: usize,
+
+    pub uart_base: usize,
+    pub uart_irq: u32,
+    // Input clock the baud divisor is worked out from.
+    pub uart_clock: u32,
+
+    pub clint_base: usize,
+    pub plic_base: usize,
+    // Number of interrupt sources the PLIC has (riscv,ndev).
+    pub plic_ndev: u32,
+    // mtime ticks per second.
+    pub timebase_frequency: u64,
+
+    pub pci_ecam_base: usize,
+    pub pci_ecam_size: usize,
+    // CPU address of the 32-bit PCI memory window BARs get placed in.
+    pub pci_mmio_base: usize,
+    pub pci_mmio_size: usize,
+}
+
+impl Platform {
+    const fn virt() -> Self {
+        Self {
+            fdt_addr: 0,
+            fdt_size: 0,
+            memory_base: 0x8000_0000,
+            memory_size: 128 * 1024 * 1024,
+            uart_base: 0x1000_0000,
+            uart_irq: 10,
+            uart_clock: 3_686_400,
+            clint_base: 0x200_0000,
+            plic_base: 0xC00_0000,
+            plic_ndev: 0x35,
+            timebase_frequency: 10_000_000,
+            pci_ecam_base: 0x3000_0000,
+            pci_ecam_size: 0x1000_0000,
+            pci_mmio_base: 0x4000_0000,
+            pci_mmio_size: 0x4000_0000,
+        }
+    }
+
+    // Overwrite every field the tree knows about.
+    fn discover(&mut self, fdt: &Fdt) {
+        self.fdt_addr = fdt.addr();
+        self.fdt_size = fdt.total_size();
+
+        if let Some(memory) = fdt.find_node("/memory") {
+            if let Some(region) = memory.reg().next() {
+                self.memory_base = region.address as usize;
+                self.memory_size = region.size as usize;
+            }
+        }
+
+        let uart = fdt.stdout().or_else(|| fdt.find_compatible("ns16550a"));
+        if let Some(uart) = uart {
+            if let Some(region) = uart.reg().next() {
+                self.uart_base = region.address as usize;
+            }
+            if let Some(irq) = uart.interrupts().next() {
+                self.uart_irq = irq;
+            }
+            if let Some(clock) = uart.property("clock-frequency").and_then(|p| p.as_u32()) {
+                self.uart_clock = clock;
+            }
+        }
+
+        if let Some(clint) = fdt.find_compatible("riscv,clint0") {
+            if let Some(region) = clint.reg().next() {
+                self.clint_base = region.address as usize;
+            }
+        }
+        if let Some(plic) = fdt.find_compatible("riscv,plic0") {
+            if let Some(region) = plic.reg().next() {
+                self.plic_base = region.address as usize;
+            }
+            if let Some(ndev) = plic.property("riscv,ndev").and_then(|p| p.as_u32()) {
+                self.plic_ndev = ndev;
+            }
+        }
+        let timebase = fdt
+            .find_node("/cpus")
+            .and_then(|cpus| cpus.property("timebase-frequency"))
+            .and_then(|p| p.as_u64());
+        if let Some(timebase) = timebase {
+            self.timebase_frequency = timebase;
+        }
+
+        if let Some(pci) = fdt.find_compatible("pci-host-ecam-generic") {
+            if let Some(region) = pci.reg().next() {
+                self.pci_ecam_base = region.address as usize;
+                self.pci_ecam_size = region.size as usize;
+            }
+            let mmio = pci
+                .ranges()
+                .find(|r| (r.child_flags >> 24) & 0x3 == PCI_SPACE_MMIO32);
+            if let Some(mmio) = mmio {
+                self.pci_mmio_base = mmio.parent_address as usize;
+                self.pci_mmio_size = mmio.size as usize;
+            }
+        }
+    }
+}
+
+static mut PLATFORM: Platform = Platform::virt();
+
+/// Parse the device tree at `dtb` and record what we find. On failure the
+/// virt defaults stay in place and the error is handed back for logging.
+pub fn init(dtb: usize) -> Result<(), FdtError> {
+    let fdt = unsafe { Fdt::from_addr(dtb)? };
+    unsafe {
+        let mut platform = PLATFORM;
+        platform.discover(&fdt);
+        PLATFORM = platform;
+    }
+    Ok(())
+}
+
+pub fn get() -> Platform {
+    unsafe { PLATFORM }
+}
+
+/// The boot device tree, if `init` found one.
+pub fn fdt() -> Option<Fdt<'static>> {
+    let addr = get().fdt_addr;
+    if addr == 0 {
+        return None;
+    }
+    unsafe { Fdt::from_addr(addr).ok() }
+}
@@ -0,0 +1,186 @@
+//! Supervisor Binary Interface.
+//! https://github.com/riscv-non-isa/riscv-sbi-doc
+//!
+//! The kernel runs in S-mode and asks M-mode for the things it can't do
+//! itself (the timer comparator, IPIs, reset) with `ecall`. Under `-bios`
+//! the M-mode side is our own `firmware`; under `-kernel` it's whatever SBI
+//! implementation QEMU loaded, usually OpenSBI.
+pub mod firmware;
+
+use crate::print;
+use crate::println;
+
+// Extension IDs.
+pub const EXT_LEGACY_SET_TIMER: usize = 0x00;
+pub const EXT_LEGACY_PUTCHAR: usize = 0x01;
+pub const EXT_LEGACY_GETCHAR: usize = 0x02;
+pub const EXT_LEGACY_SHUTDOWN: usize = 0x08;
+pub const EXT_BASE: usize = 0x10;
+pub const EXT_TIME: usize = 0x5449_4D45;
+pub const EXT_SRST: usize = 0x5352_5354;
+pub const EXT_DBCN: usize = 0x4442_434E;
+pub const EXT_HSM: usize = 0x0048_534D;
+pub const EXT_IPI: usize = 0x0073_5049;
+
+// Base extension functions.
+pub const BASE_SPEC_VERSION: usize = 0;
+pub const BASE_IMPL_ID: usize = 1;
+pub const BASE_IMPL_VERSION: usize = 2;
+pub const BASE_PROBE_EXTENSION: usize = 3;
+pub const BASE_MVENDORID: usize = 4;
+pub const BASE_MARCHID: usize = 5;
+pub const BASE_MIMPID: usize = 6;
+
+pub const TIME_SET_TIMER: usize = 0;
+pub const SRST_SYSTEM_RESET: usize = 0;
+pub const DBCN_WRITE: usize = 0;
+pub const DBCN_READ: usize = 1;
+pub const DBCN_WRITE_BYTE: usize = 2;
+pub const HSM_HART_START: usize = 0;
+pub const HSM_HART_STOP: usize = 1;
+pub const HSM_HART_GET_STATUS: usize = 2;
+pub const IPI_SEND_IPI: usize = 0;
+
+// HSM hart states.
+pub const HART_STARTED: usize = 0;
+pub const HART_STOPPED: usize = 1;
+pub const HART_START_PENDING: usize = 2;
+
+// SRST reset types.
+pub const RESET_SHUTDOWN: usize = 0;
+pub const RESET_COLD_REBOOT: usize = 1;
+pub const RESET_WARM_REBOOT: usize = 2;
+
+// Error codes.
+pub const SUCCESS: isize = 0;
+pub const ERR_FAILED: isize = -1;
+pub const ERR_NOT_SUPPORTED: isize = -2;
+pub const ERR_INVALID_PARAM: isize = -3;
+pub const ERR_ALREADY_AVAILABLE: isize = -6;
+
+/// What every SBI call returns, in a0 and a1.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct SbiRet {
+    pub error: isize,
+    pub value: usize,
+}
+
+impl SbiRet {
+    pub const fn ok(value: usize) -> SbiRet {
+        SbiRet {
+            error: SUCCESS,
+            value,
+        }
+    }
+
+    pub const fn err(error: isize) -> SbiRet {
+        SbiRet { error, value: 0 }
+    }
+
+    pub fn is_ok(&self) -> bool {
+        self.error == SUCCESS
+    }
+}
+
+extern "C" {
+    // asm/sbi.S: moves the arguments into place and does the ecall.
+    fn sbi_ecall(eid: usize, fid: usize, a0: usize, a1: usize, a2: usize, a3: usize) -> SbiRet;
+}
+
+pub fn call(eid: usize, fid: usize, args: [usize; 4]) -> SbiRet {
+    unsafe { sbi_ecall(eid, fid, args[0], args[1], args[2], args[3]) }
+}
+
+pub fn spec_version() -> (usize, usize) {
+    let version = call(EXT_BASE, BASE_SPEC_VERSION, [0; 4]).value;
+    ((version >> 24) & 0x7F, version & 0xFF_FFFF)
+}
+
+pub fn impl_id() -> usize {
+    call(EXT_BASE, BASE_IMPL_ID, [0; 4]).value
+}
+
+pub fn impl_name(id: usize) -> &'static str {
+    match id {
+        0 => "BBL",
+        1 => "OpenSBI",
+        2 => "Xvisor",
+        3 => "KVM",
+        4 => "RustSBI",
+        5 => "Diosix",
+        6 => "Coffer",
+        firmware::IMPL_ID => "nesOS firmware",
+        _ => "unknown",
+    }
+}
+
+pub fn probe(eid: usize) -> bool {
+    let ret = call(EXT_BASE, BASE_PROBE_EXTENSION, [eid, 0, 0, 0]);
+    ret.is_ok() && ret.value != 0
+}
+
+/// Raise a supervisor timer interrupt once `time` reaches `stime`. Also
+/// clears a pending one, so u64::MAX turns the timer off.
+pub fn set_timer(stime: u64) {
+    let ret = call(EXT_TIME, TIME_SET_TIMER, [stime as usize, 0, 0, 0]);
+    if !ret.is_ok() {
+        call(EXT_LEGACY_SET_TIMER, 0, [stime as usize, 0, 0, 0]);
+    }
+}
+
+pub fn console_putchar(byte: u8) {
+    let ret = call(EXT_DBCN, DBCN_WRITE_BYTE, [byte as usize, 0, 0, 0]);
+    if !ret.is_ok() {
+        call(EXT_LEGACY_PUTCHAR, 0, [byte as usize, 0, 0, 0]);
+    }
+}
+
+pub fn console_getchar() -> Option<u8> {
+    let ret = call(EXT_LEGACY_GETCHAR, 0, [0; 4]);
+    if ret.error < 0 {
+        None
+    } else {
+        Some(ret.error as u8)
+    }
+}
+
+/// Start `hartid` in S-mode at `start`, with a0 = hartid and a1 = `opaque`.
+pub fn hart_start(hartid: usize, start: usize, opaque: usize) -> SbiRet {
+    call(EXT_HSM, HSM_HART_START, [hartid, start, opaque, 0])
+}
+
+/// `HART_STARTED`, `HART_STOPPED`, ... or None if there's no such hart.
+pub fn hart_status(hartid: usize) -> Option<usize> {
+    let ret = call(EXT_HSM, HSM_HART_GET_STATUS, [hartid, 0, 0, 0]);
+    if ret.is_ok() {
+        Some(ret.value)
+    } else {
+        None
+    }
+}
+
+/// Raise a supervisor software interrupt on every hart in `hart_mask`, bit
+/// 0 being `hart_mask_base`. A base of usize::MAX means all harts.
+pub fn send_ipi(hart_mask: usize, hart_mask_base: usize) -> SbiRet {
+    call(EXT_IPI, IPI_SEND_IPI, [hart_mask, hart_mask_base, 0, 0])
+}
+
+/// Only returns if the reset failed.
+pub fn system_reset(reset_type: usize, reason: usize) -> SbiRet {
+    call(EXT_SRST, SRST_SYSTEM_RESET, [reset_type, reason, 0, 0])
+}
+
+/// Say who we're running on.
+pub fn init() {
+    let (major, minor) = spec_version();
+    let id = impl_id();
+    println!(
+        "[SBI]: spec v{}.{}, {} ({}) version {:#X}",
+        major,
+        minor,
+        impl_name(id),
+        id,
+        call(EXT_BASE, BASE_IMPL_VERSION, [0; 4]).value
+    );
+}
@@ -0,0 +1,391 @@
+//! Our own M-mode SBI implementation, for booting with `-bios`.
+//!
+//! `_start` calls `m_init` while still in M-mode, which delegates the
+//! supervisor interrupts and most exceptions to S-mode, opens PMP so S-mode
+//! can reach all of memory and points mtvec at `m_trap_vector`. After that
+//! M-mode only runs when the kernel makes an SBI call, the machine timer
+//! fires or another hart sends an IPI: those are forwarded as supervisor
+//! timer and software interrupts.
+//!
+//! The other harts wait in `m_secondary` until the kernel starts them with
+//! HSM `hart_start`, the same way it would under OpenSBI.
+use super::*;
+use crate::dev::clint::{self, MAX_HARTS};
+use crate::dev::syscon::{self, ResetKind};
+use crate::plt;
+use core::arch::asm;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// What we answer to `sbi_get_impl_id`. Outside the range the spec hands out.
+pub const IMPL_ID: usize = 0x4E45;
+const IMPL_VERSION: usize = 1;
+const SPEC_VERSION: usize = 2 << 24;
+
+// mcause
+const MCAUSE_INTERRUPT: usize = 1 << 63;
+const IRQ_M_SOFT: usize = 3;
+const IRQ_M_TIMER: usize = 7;
+const EXC_SUPERVISOR_ECALL: usize = 9;
+
+// mip / mie bits.
+const MIP_SSIP: usize = 1 << 1;
+const MIP_STIP: usize = 1 << 5;
+const MIE_MSIE: usize = 1 << 3;
+const MIE_MTIE: usize = 1 << 7;
+
+// Handed to S-mode: SSIP, STIP and SEIP.
+const MIDELEG: usize = (1 << 1) | (1 << 5) | (1 << 9);
+// Every exception up to U-mode ecall, and the page faults. Ecalls from
+// S-mode stay here, they're the SBI.
+const MEDELEG: usize = 0x1FF | (1 << 12) | (1 << 13) | (1 << 15);
+
+// PMP entry 0: NAPOT over the whole address space, RWX.
+const PMP_ALL: usize = usize::MAX >> 10;
+const PMPCFG_NAPOT_RWX: usize = 0x1F;
+
+// Room for core::fmt's frames as well as the trap. startup.S has a copy.
+const M_STACK_SIZE: usize = 16 * 1024;
+
+// The console UART's registers, a 16550's: receive and transmit holding,
+// and line status with its data ready and transmit empty bits.
+const UART_RBR: usize = 0;
+const UART_THR: usize = 0;
+const UART_LSR: usize = 5;
+const LSR_DATA_READY: u8 = 1 << 0;
+const LSR_THR_EMPTY: u8 = 1 << 5;
+
+// Registers as `m_trap_vector` saved them, x0 - x31.
+type Regs = [usize; 32];
+const A0: usize = 10;
+const A1: usize = 11;
+const A6: usize = 16;
+const A7: usize = 17;
+
+#[repr(C, align(16))]
+#[derive(Clone, Copy)]
+struct Stack([u8; M_STACK_SIZE]);
+
+// M-mode trap stacks, one per hart. mscratch holds the top of ours.
+// Secondary harts also run `m_secondary` on theirs; startup.S finds them by
+// name.
+#[no_mangle]
+static mut M_STACKS: [Stack; MAX_HARTS] = [Stack([0; M_STACK_SIZE]); MAX_HARTS];
+
+// HSM state of each hart, and where hart_start asked it to go.
+static HART_STATE: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(HART_STOPPED) }; MAX_HARTS];
+static START_ADDR: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+static START_OPAQUE: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+extern "C" {
+    fn m_trap_vector();
+}
+
+fn mhartid() -> usize {
+    let id: usize;
+    unsafe {
+        asm!("csrr {0}, mhartid", out(reg) id);
+    }
+    id
+}
+
+// The console device, polled. The kernel's console may be interrupt driven
+// with bytes sitting in its TX ring; we don't touch that, and don't use its
+// `Uart`, whose rings would take up most of an M stack.
+struct Console(*mut u8);
+
+impl Console {
+    fn read_reg(&self, reg: usize) -> u8 {
+        unsafe { self.0.add(reg).read_volatile() }
+    }
+
+    fn put_byte(&mut self, byte: u8) {
+        while self.read_reg(UART_LSR) & LSR_THR_EMPTY == 0 {}
+        unsafe { self.0.add(UART_THR).write_volatile(byte) };
+    }
+
+    fn try_read(&mut self) -> Option<u8> {
+        if self.read_reg(UART_LSR) & LSR_DATA_READY != 0 {
+            Some(self.read_reg(UART_RBR))
+        } else {
+            None
+        }
+    }
+}
+
+impl Write for Console {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            self.put_byte(byte);
+        }
+        Ok(())
+    }
+}
+
+fn uart() -> Console {
+    Console(plt::get().uart_base as *mut u8)
+}
+
+/// Set up M-mode for this hart before dropping to S-mode.
+#[cfg(not(test))]
+#[no_mangle]
+extern "C" fn m_init(hartid: usize, dtb: usize) {
+    if hartid == 0 {
+        let _ = plt::init(dtb);
+        HART_STATE[hartid].store(HART_STARTED, Ordering::Relaxed);
+    }
+    let stack_top = unsafe { core::ptr::addr_of!(M_STACKS[hartid]) as usize + M_STACK_SIZE };
+    unsafe {
+        asm!(
+            "csrw mscratch, {stack}",
+            "csrw mtvec, {vector}",
+            "csrw medeleg, {medeleg}",
+            "csrw mideleg, {mideleg}",
+            // Let S-mode read time, cycle and instret.
+            "csrw mcounteren, {counters}",
+            "csrw pmpaddr0, {pmpaddr}",
+            "csrw pmpcfg0, {pmpcfg}",
+            // IPIs arrive as msip; the timer is off until set_timer.
+            "csrw mie, {mie}",
+            stack = in(reg) stack_top,
+            vector = in(reg) m_trap_vector as *const () as usize,
+            medeleg = in(reg) MEDELEG,
+            mideleg = in(reg) MIDELEG,
+            counters = in(reg) 0b111,
+            pmpaddr = in(reg) PMP_ALL,
+            pmpcfg = in(reg) PMPCFG_NAPOT_RWX,
+            mie = in(reg) MIE_MSIE,
+        );
+    }
+    clint::set_mtimecmp(hartid, u64::MAX);
+    if hartid == 0 {
+        let _ = writeln!(
+            uart(),
+            "[SBI]: firmware on hart {}, medeleg {:#X}, mideleg {:#X}\r",
+            hartid,
+            MEDELEG,
+            MIDELEG
+        );
+    }
+}
+
+/// Where startup.S sends every hart but 0, once hart 0 has set up memory.
+/// Waits for hart_start, then drops to S-mode wherever it said.
+#[cfg(not(test))]
+#[no_mangle]
+extern "C" fn m_secondary(hartid: usize, dtb: usize) -> ! {
+    m_init(hartid, dtb);
+    let start = loop {
+        let start = START_ADDR[hartid].load(Ordering::Acquire);
+        if start != 0 {
+            break start;
+        }
+        core::hint::spin_loop();
+    };
+    let opaque = START_OPAQUE[hartid].load(Ordering::Relaxed);
+    HART_STATE[hartid].store(HART_STARTED, Ordering::Relaxed);
+    unsafe {
+        asm!(
+            // mepc first, `start` may have been given t0.
+            "csrw mepc, {start}",
+            // MPP = S, FS = initial.
+            "li t0, 3 << 11",
+            "csrc mstatus, t0",
+            "li t0, (1 << 11) | (1 << 13)",
+            "csrs mstatus, t0",
+            "mret",
+            start = in(reg) start,
+            in("a0") hartid,
+            in("a1") opaque,
+            options(noreturn)
+        );
+    }
+}
+
+#[cfg(not(test))]
+#[no_mangle]
+extern "C" fn m_trap_handler(regs: &mut Regs, mcause: usize, mepc: usize) {
+    if mcause == MCAUSE_INTERRUPT | IRQ_M_TIMER {
+        // Pass it down. The kernel's next set_timer clears STIP again.
+        unsafe {
+            asm!("csrc mie, {0}", "csrs mip, {1}", in(reg) MIE_MTIE, in(reg) MIP_STIP);
+        }
+        return;
+    }
+    if mcause == MCAUSE_INTERRUPT | IRQ_M_SOFT {
+        // Acknowledge the msip an IPI set and hand it on as SSIP.
+        clint::set_msip(mhartid(), false);
+        unsafe {
+            asm!("csrs mip, {0}", in(reg) MIP_SSIP);
+        }
+        return;
+    }
+    if mcause == EXC_SUPERVISOR_ECALL {
+        let ret = handle_ecall(regs[A7], regs[A6], [regs[A0], regs[A1], regs[12], regs[13]]);
+        regs[A0] = ret.error as usize;
+        regs[A1] = ret.value;
+        unsafe {
+            asm!("csrw mepc, {0}", in(reg) mepc + 4);
+        }
+        return;
+    }
+    let mtval: usize;
+    unsafe {
+        asm!("csrr {0}, mtval", out(reg) mtval);
+    }
+    let _ = writeln!(
+        uart(),
+        "\r\n[SBI]: unexpected trap on hart {}: mcause {:#X} mepc {:#X} mtval {:#X}\r",
+        mhartid(),
+        mcause,
+        mepc,
+        mtval
+    );
+    loop {
+        unsafe {
+            asm!("wfi");
+        }
+    }
+}
+
+fn handle_ecall(eid: usize, fid: usize, args: [usize; 4]) -> SbiRet {
+    match eid {
+        EXT_BASE => base(fid, args[0]),
+        EXT_TIME if fid == TIME_SET_TIMER => {
+            set_timer(args[0] as u64);
+            SbiRet::ok(0)
+        }
+        EXT_LEGACY_SET_TIMER => {
+            set_timer(args[0] as u64);
+            SbiRet::ok(0)
+        }
+        EXT_LEGACY_PUTCHAR => {
+            uart().put_byte(args[0] as u8);
+            SbiRet::ok(0)
+        }
+        // The legacy call returns the byte, or -1, in a0.
+        EXT_LEGACY_GETCHAR => match uart().try_read() {
+            Some(byte) => SbiRet::err(byte as isize),
+            None => SbiRet::err(-1),
+        },
+        EXT_LEGACY_SHUTDOWN => syscon::reset_direct(ResetKind::Poweroff),
+        EXT_SRST if fid == SRST_SYSTEM_RESET => match args[0] {
+            RESET_SHUTDOWN => syscon::reset_direct(ResetKind::Poweroff),
+            RESET_COLD_REBOOT | RESET_WARM_REBOOT => syscon::reset_direct(ResetKind::Reboot),
+            _ => SbiRet::err(ERR_INVALID_PARAM),
+        },
+        EXT_DBCN => console(fid, args),
+        EXT_HSM => hsm(fid, args),
+        EXT_IPI if fid == IPI_SEND_IPI => send_ipi(args[0], args[1]),
+        _ => SbiRet::err(ERR_NOT_SUPPORTED),
+    }
+}
+
+fn base(fid: usize, arg: usize) -> SbiRet {
+    match fid {
+        BASE_SPEC_VERSION => SbiRet::ok(SPEC_VERSION),
+        BASE_IMPL_ID => SbiRet::ok(IMPL_ID),
+        BASE_IMPL_VERSION => SbiRet::ok(IMPL_VERSION),
+        BASE_PROBE_EXTENSION => {
+            let supported = matches!(
+                arg,
+                EXT_BASE
+                    | EXT_TIME
+                    | EXT_SRST
+                    | EXT_DBCN
+                    | EXT_HSM
+                    | EXT_IPI
+                    | EXT_LEGACY_SET_TIMER
+                    | EXT_LEGACY_PUTCHAR
+                    | EXT_LEGACY_GETCHAR
+                    | EXT_LEGACY_SHUTDOWN
+            );
+            SbiRet::ok(supported as usize)
+        }
+        // We don't report the machine IDs.
+        BASE_MVENDORID | BASE_MARCHID | BASE_MIMPID => SbiRet::ok(0),
+        _ => SbiRet::err(ERR_NOT_SUPPORTED),
+    }
+}
+
+fn set_timer(stime: u64) {
+    clint::set_mtimecmp(mhartid(), stime);
+    unsafe {
+        asm!("csrc mip, {0}", "csrs mie, {1}", in(reg) MIP_STIP, in(reg) MIE_MTIE);
+    }
+}
+
+// Only harts that are up: the CLINT has no msip for ones that don't exist.
+fn send_ipi(mask: usize, base: usize) -> SbiRet {
+    for (hart, state) in HART_STATE.iter().enumerate() {
+        let wanted = if base == usize::MAX {
+            true
+        } else {
+            hart >= base && hart - base < usize::BITS as usize && mask & (1 << (hart - base)) != 0
+        };
+        if wanted && state.load(Ordering::Relaxed) == HART_STARTED {
+            clint::set_msip(hart, true);
+        }
+    }
+    SbiRet::ok(0)
+}
+
+fn hsm(fid: usize, args: [usize; 4]) -> SbiRet {
+    let hartid = args[0];
+    if hartid >= MAX_HARTS {
+        return SbiRet::err(ERR_INVALID_PARAM);
+    }
+    match fid {
+        HSM_HART_START => {
+            let state = &HART_STATE[hartid];
+            if state
+                .compare_exchange(
+                    HART_STOPPED,
+                    HART_START_PENDING,
+                    Ordering::AcqRel,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+            {
+                return SbiRet::err(ERR_ALREADY_AVAILABLE);
+            }
+            START_OPAQUE[hartid].store(args[2], Ordering::Relaxed);
+            START_ADDR[hartid].store(args[1], Ordering::Release);
+            SbiRet::ok(0)
+        }
+        HSM_HART_GET_STATUS => SbiRet::ok(HART_STATE[hartid].load(Ordering::Relaxed)),
+        _ => SbiRet::err(ERR_NOT_SUPPORTED),
+    }
+}
+
+// Debug console. Addresses are physical, which is fine while the kernel is
+// identity mapped.
+fn console(fid: usize, args: [usize; 4]) -> SbiRet {
+    let mut uart = uart();
+    match fid {
+        DBCN_WRITE => {
+            let bytes = unsafe { core::slice::from_raw_parts(args[1] as *const u8, args[0]) };
+            for &byte in bytes {
+                uart.put_byte(byte);
+            }
+            SbiRet::ok(bytes.len())
+        }
+        DBCN_READ => {
+            let buffer = unsafe { core::slice::from_raw_parts_mut(args[1] as *mut u8, args[0]) };
+            let mut read = 0;
+            while read < buffer.len() {
+                match uart.try_read() {
+                    Some(byte) => buffer[read] = byte,
+                    None => break,
+                }
+                read += 1;
+            }
+            SbiRet::ok(read)
+        }
+        DBCN_WRITE_BYTE => {
+            uart.put_byte(args[0] as u8);
+            SbiRet::ok(0)
+        }
+        _ => SbiRet::err(ERR_NOT_SUPPORTED),
+    }
+}
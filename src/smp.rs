@@ -0,0 +1,229 @@
+//! Bringing up the other harts.
+//!
+//! The boot hart runs all of `k_init`. Once the drivers are up,
+//! `start_secondaries` gives every other enabled hart in /cpus a boot stack
+//! and starts it at `secondary_entry` through SBI HSM. Each one runs
+//! `k_init_hart`, checks in at the boot barrier and waits there until the
+//! boot hart has heard from everyone. After that it becomes an idle thread
+//! and takes work from the shared run queues like any other hart.
+//!
+//! `tp` points at the running hart's `Hart` from its first line of Rust on.
+//! Nothing else uses tp and the trap path leaves it alone.
+pub mod ipi;
+
+use crate::dev::clint::{self, MAX_HARTS};
+use crate::plt;
+use crate::print;
+use crate::println;
+use crate::sbi;
+use crate::srv::console::{self, Command};
+use crate::util::alloc::{Alloc, PAGE_SIZE};
+use crate::util::thread::Thread;
+use core::arch::asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+// A secondary's boot stack. It carries on as the stack of that hart's idle
+// thread, so traps taken while the hart is idle land on it too.
+const BOOT_STACK_PAGES: usize = 4;
+// How long the boot hart waits for the others to check in.
+const ONLINE_TIMEOUT_MICROS: u64 = 1_000_000;
+
+extern "C" {
+    fn secondary_entry();
+}
+
+/// Per hart data. `tp` holds the address of the running hart's.
+pub struct Hart {
+    id: usize,
+    // Top of the boot stack `start_secondaries` handed it, 0 for the boot
+    // hart, which stays on the linker's stack.
+    boot_stack: AtomicUsize,
+    online: AtomicBool,
+    // mtime when it reached the boot barrier.
+    online_at: AtomicU64,
+}
+
+impl Hart {
+    const fn new() -> Self {
+        Self {
+            id: 0,
+            boot_stack: AtomicUsize::new(0),
+            online: AtomicBool::new(false),
+            online_at: AtomicU64::new(0),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+}
+
+static mut HARTS: [Hart; MAX_HARTS] = [const { Hart::new() }; MAX_HARTS];
+static BOOT_HART: AtomicUsize = AtomicUsize::new(0);
+static ONLINE: AtomicUsize = AtomicUsize::new(0);
+// Set by the boot hart once everyone it started has checked in (or it gave
+// up waiting).
+static RELEASED: AtomicBool = AtomicBool::new(false);
+
+fn hart(id: usize) -> &'static mut Hart {
+    unsafe { &mut *core::ptr::addr_of_mut!(HARTS[id]) }
+}
+
+/// Point tp at `hartid`'s `Hart`. The first thing every hart does in Rust.
+pub fn init_hart(hartid: usize) {
+    let hart = hart(hartid);
+    hart.id = hartid;
+    unsafe {
+        asm!("mv tp, {0}", in(reg) hart as *mut Hart);
+    }
+}
+
+/// The hart we're running on.
+#[cfg(not(test))]
+pub fn this_hart() -> &'static Hart {
+    let ptr: usize;
+    unsafe {
+        asm!("mv {0}, tp", out(reg) ptr);
+        &*(ptr as *const Hart)
+    }
+}
+
+// Host tests have no tp, see `interrupt::enable_global`.
+#[cfg(test)]
+pub fn this_hart() -> &'static Hart {
+    hart(0)
+}
+
+pub fn is_online(hart: usize) -> bool {
+    hart < MAX_HARTS && self::hart(hart).is_online()
+}
+
+pub fn boot_hart() -> usize {
+    BOOT_HART.load(Ordering::Relaxed)
+}
+
+/// Harts that have made it to the boot barrier, the boot hart included.
+pub fn online_count() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+fn mark_online(hart: &Hart) {
+    hart.online_at.store(clint::mtime(), Ordering::Relaxed);
+    hart.online.store(true, Ordering::Release);
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+}
+
+// Hart IDs of the enabled cpus in the device tree.
+fn for_each_cpu<F: FnMut(usize)>(mut f: F) {
+    let cpus = match plt::fdt().and_then(|fdt| fdt.find_node("/cpus")) {
+        Some(cpus) => cpus,
+        None => return,
+    };
+    for cpu in cpus.children() {
+        if cpu.device_type() != Some("cpu") || !cpu.is_enabled() {
+            continue;
+        }
+        if let Some(reg) = cpu.reg().next() {
+            f(reg.address as usize);
+        }
+    }
+}
+
+// Give `id` a boot stack and ask SBI to start it at `secondary_entry`.
+fn start(id: usize) -> bool {
+    let stack = match Alloc::get(BOOT_STACK_PAGES) {
+        Some(stack) => stack,
+        None => {
+            println!("[SMP]: no memory for hart {}'s stack", id);
+            return false;
+        }
+    };
+    let top = stack as usize + BOOT_STACK_PAGES * PAGE_SIZE;
+    hart(id).boot_stack.store(top, Ordering::Relaxed);
+    let ret = sbi::hart_start(id, secondary_entry as *const () as usize, top);
+    if !ret.is_ok() {
+        println!("[SMP]: hart {} didn't start: error {}", id, ret.error);
+        hart(id).boot_stack.store(0, Ordering::Relaxed);
+        let _ = Alloc::free(stack);
+        return false;
+    }
+    true
+}
+
+/// Start every other hart the device tree lists and wait for them to check
+/// in, then let them all go.
+pub fn start_secondaries() {
+    let boot = this_hart().id;
+    BOOT_HART.store(boot, Ordering::Relaxed);
+    mark_online(this_hart());
+    let _ = console::register(Command {
+        name: "hartinfo",
+        usage: "hartinfo",
+        help: "harts, whether they're online and what they're running",
+        handler: hartinfo_command,
+    });
+    ipi::init();
+    // No point starting harts we couldn't wake up.
+    if !sbi::probe(sbi::EXT_HSM) || !sbi::probe(sbi::EXT_IPI) {
+        println!(
+            "[SMP]: no HSM or IPI extension, running on hart {} alone",
+            boot
+        );
+        return;
+    }
+    let mut started = 0;
+    for_each_cpu(|id| {
+        if id != boot && id < MAX_HARTS && start(id) {
+            started += 1;
+        }
+    });
+    let deadline = clint::mtime() + clint::micros_to_ticks(ONLINE_TIMEOUT_MICROS);
+    while online_count() < started + 1 && clint::mtime() < deadline {
+        core::hint::spin_loop();
+    }
+    RELEASED.store(true, Ordering::Release);
+    println!("[SMP]: {} of {} harts online", online_count(), started + 1);
+}
+
+/// A secondary hart's check-in at the boot barrier. Returns once the boot
+/// hart lets everyone go.
+pub fn arrive() {
+    mark_online(this_hart());
+    while !RELEASED.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
+}
+
+fn hartinfo_command(_args: &[&str]) {
+    println!(
+        "{:>4} {:<9} {:<12} {:>18} {:>10}",
+        "HART", "STATE", "THREAD", "BOOT STACK", "UP AT ms"
+    );
+    for id in 0..MAX_HARTS {
+        let hart = hart(id);
+        let stack = hart.boot_stack.load(Ordering::Relaxed);
+        if !hart.is_online() && stack == 0 {
+            continue;
+        }
+        let state = if hart.is_online() {
+            "online"
+        } else {
+            "starting"
+        };
+        let thread = Thread::running_on(id).unwrap_or("-");
+        let up_at = clint::ticks_to_micros(hart.online_at.load(Ordering::Relaxed)) / 1000;
+        println!(
+            "{:>4} {:<9} {:<12} {:>#18X} {:>10}{}",
+            id,
+            state,
+            thread,
+            stack,
+            up_at,
+            if id == boot_hart() { " boot" } else { "" }
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Inter-processor interrupts.
+//!
+//! An IPI is an SBI `send_ipi`, which lands on the target as a supervisor
+//! software interrupt. What it's for sits in that hart's pending bits: a
+//! reschedule request, or calls waiting in its queue. Either way the
+//! interrupt also wakes a hart that's asleep in `wfi`.
+use crate::dev::clint::{self, MAX_HARTS};
+use crate::print;
+use crate::println;
+use crate::sbi;
+use crate::smp;
+use crate::srv::console::{self, Command};
+use crate::util::interrupt;
+use crate::util::lock::RawSpinLock;
+use crate::util::thread;
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+// Pending bits.
+const RESCHEDULE: usize = 1 << 0;
+const CALL: usize = 1 << 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiError {
+    NoSuchHart,
+    // Not (yet) through the boot barrier.
+    Offline,
+}
+
+type Call = Box<dyn FnOnce() + Send>;
+
+static PENDING: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+// Each queue is guarded by its own lock, taken with interrupts off.
+static QUEUE_LOCKS: [RawSpinLock; MAX_HARTS] = [const { RawSpinLock::new() }; MAX_HARTS];
+static mut QUEUES: [VecDeque<Call>; MAX_HARTS] = [const { VecDeque::new() }; MAX_HARTS];
+// IPIs taken and calls run, per hart.
+static RECEIVED: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+static CALLS: [AtomicUsize; MAX_HARTS] = [const { AtomicUsize::new(0) }; MAX_HARTS];
+
+fn queue(hart: usize) -> &'static mut VecDeque<Call> {
+    unsafe { &mut *core::ptr::addr_of_mut!(QUEUES[hart]) }
+}
+
+fn check(hart: usize) -> Result<(), IpiError> {
+    if hart >= MAX_HARTS {
+        Err(IpiError::NoSuchHart)
+    } else if !smp::is_online(hart) {
+        Err(IpiError::Offline)
+    } else {
+        Ok(())
+    }
+}
+
+pub fn init() {
+    let _ = console::register(Command {
+        name: "ipi",
+        usage: "ipi [hart]",
+        help: "IPI counts, or time a call on another hart",
+        handler: ipi_command,
+    });
+}
+
+/// Interrupt every hart in `hart_mask`, bit n being hart n.
+pub fn send_ipi(hart_mask: usize) {
+    if hart_mask == 0 {
+        return;
+    }
+    let ret = sbi::send_ipi(hart_mask, 0);
+    if !ret.is_ok() {
+        println!(
+            "[IPI]: send to {:#X} failed: error {}",
+            hart_mask, ret.error
+        );
+    }
+}
+
+fn post(hart: usize, bits: usize) {
+    // Bits that were already pending have an IPI on the way.
+    if PENDING[hart].fetch_or(bits, Ordering::AcqRel) & bits != bits {
+        send_ipi(1 << hart);
+    }
+}
+
+/// Get `hart` to run its scheduler, e.g. because there's a thread ready and
+/// it's idle.
+pub fn reschedule(hart: usize) {
+    if check(hart).is_ok() {
+        post(hart, RESCHEDULE);
+    }
+}
+
+/// Run `f` on `hart` from its software interrupt handler, without waiting
+/// for it. On this hart it runs straight away.
+pub fn call_on<F: FnOnce() + Send + 'static>(hart: usize, f: F) -> Result<(), IpiError> {
+    check(hart)?;
+    if hart == interrupt::hart_id() {
+        interrupt::without_interrupts(f);
+        return Ok(());
+    }
+    QUEUE_LOCKS[hart].with(|| queue(hart).push_back(Box::new(f)));
+    post(hart, CALL);
+    Ok(())
+}
+
+/// Run `f` on every online hart in `hart_mask`, this one included if it's
+/// in there, and wait until they've all finished. Returns how many it ran on.
+pub fn call_sync(hart_mask: usize, f: fn()) -> usize {
+    // Threads are only preempted on the way out of an interrupt, so with
+    // them off we stay on the hart `me` is for.
+    interrupt::without_interrupts(|| {
+        let me = interrupt::hart_id();
+        let done = Arc::new(AtomicUsize::new(0));
+        let mut remote = 0;
+        for hart in 0..MAX_HARTS {
+            if hart == me || hart_mask & (1 << hart) == 0 {
+                continue;
+            }
+            let done = done.clone();
+            let queued = call_on(hart, move || {
+                f();
+                done.fetch_add(1, Ordering::Release);
+            });
+            if queued.is_ok() {
+                remote += 1;
+            }
+        }
+        let mut ran = remote;
+        if hart_mask & (1 << me) != 0 {
+            f();
+            ran += 1;
+        }
+        // Keep serving our own queue, or two harts calling each other
+        // would wait forever.
+        while done.load(Ordering::Acquire) < remote {
+            run_calls(me);
+            core::hint::spin_loop();
+        }
+        ran
+    })
+}
+
+/// `call_sync` on every online hart.
+pub fn call_all(f: fn()) -> usize {
+    call_sync(usize::MAX >> (usize::BITS as usize - MAX_HARTS), f)
+}
+
+fn run_calls(hart: usize) {
+    if PENDING[hart].fetch_and(!CALL, Ordering::AcqRel) & CALL == 0 {
+        return;
+    }
+    loop {
+        let call = QUEUE_LOCKS[hart].with(|| queue(hart).pop_front());
+        match call {
+            Some(call) => {
+                CALLS[hart].fetch_add(1, Ordering::Relaxed);
+                call();
+            }
+            None => break,
+        }
+    }
+}
+
+/// Supervisor software interrupt, after sip.SSIP has been cleared.
+pub fn handle_interrupt() {
+    let hart = interrupt::hart_id();
+    RECEIVED[hart].fetch_add(1, Ordering::Relaxed);
+    if PENDING[hart].fetch_and(!RESCHEDULE, Ordering::AcqRel) & RESCHEDULE != 0 {
+        thread::request_resched();
+    }
+    run_calls(hart);
+}
+
+fn ipi_command(args: &[&str]) {
+    let target = match args.first() {
+        Some(arg) => match arg.parse::<usize>() {
+            Ok(hart) => hart,
+            Err(_) => {
+                println!("usage: ipi [hart]");
+                return;
+            }
+        },
+        None => {
+            println!("{:>4} {:>10} {:>10}", "HART", "IPIS", "CALLS");
+            for hart in (0..MAX_HARTS).filter(|&hart| smp::is_online(hart)) {
+                println!(
+                    "{:>4} {:>10} {:>10}",
+                    hart,
+                    RECEIVED[hart].load(Ordering::Relaxed),
+                    CALLS[hart].load(Ordering::Relaxed)
+                );
+            }
+            return;
+        }
+    };
+    if let Err(e) = check(target) {
+        println!("hart {}: {:?}", target, e);
+        return;
+    }
+    let start = clint::mtime();
+    call_sync(1 << target, || {});
+    println!(
+        "hart {} answered in {} us",
+        target,
+        clint::ticks_to_micros(clint::mtime() - start)
+    );
+}
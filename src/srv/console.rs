@@ -1,35 +1,315 @@
-use crate::dev::uart::Uart;
+//! Kernel shell on the console UART.
+//!
+//! Line editing understands backspace, the left/right arrows, up/down for
+//! history and Ctrl-C to drop the line. Commands live in a table that any
+//! module can add to with `register`; the built-ins are in `commands`.
+// The table's function pointers would pull the hardware code into host test
+// builds.
+#[cfg(not(test))]
+pub mod commands;
+
+use crate::dev::uart;
 use crate::print;
 use crate::println;
 
+const LINE_SIZE: usize = 128;
+const HISTORY_SIZE: usize = 8;
+const MAX_COMMANDS: usize = 32;
+const MAX_ARGS: usize = 16;
+const PROMPT: &str = "> ";
+
+const CTRL_C: u8 = 3;
+const BACKSPACE: u8 = 8;
+const DELETE: u8 = 127;
+const ESCAPE: u8 = 0x1B;
+
+#[derive(Debug)]
+pub enum ConsoleError {
+    TableFull,
+    AlreadyRegistered,
+}
+
+/// A shell command. `handler` gets the arguments after the command name.
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub help: &'static str,
+    pub handler: fn(&[&str]),
+}
+
+static mut COMMANDS: [Option<Command>; MAX_COMMANDS] = [None; MAX_COMMANDS];
+
+/// Add a command to the shell.
+pub fn register(command: Command) -> Result<(), ConsoleError> {
+    let commands = unsafe { &mut *core::ptr::addr_of_mut!(COMMANDS) };
+    if commands.iter().flatten().any(|c| c.name == command.name) {
+        return Err(ConsoleError::AlreadyRegistered);
+    }
+    let slot = commands
+        .iter_mut()
+        .find(|c| c.is_none())
+        .ok_or(ConsoleError::TableFull)?;
+    *slot = Some(command);
+    Ok(())
+}
+
+pub fn find(name: &str) -> Option<Command> {
+    for_each_command().find(|c| c.name == name)
+}
+
+pub fn for_each_command() -> impl Iterator<Item = Command> {
+    let commands = unsafe { &*core::ptr::addr_of!(COMMANDS) };
+    commands.iter().flatten().copied()
+}
+
+/// Register the built-in commands.
+#[cfg(not(test))]
+pub fn init() {
+    for command in commands::BUILTINS {
+        if let Err(e) = register(*command) {
+            println!("[CONSOLE]: could not add {}: {:?}", command.name, e);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Escape {
+    None,
+    // Got ESC
+    Start,
+    // Got ESC [
+    Csi,
+}
+
+#[derive(Clone, Copy)]
+struct Line {
+    buf: [u8; LINE_SIZE],
+    len: usize,
+}
+
+impl Line {
+    const EMPTY: Line = Line {
+        buf: [0; LINE_SIZE],
+        len: 0,
+    };
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
 pub struct Console {
-    uart: Uart
+    line: Line,
+    cursor: usize,
+    escape: Escape,
+    history: [Line; HISTORY_SIZE],
+    // Total lines ever saved; the newest is at (history_count - 1) % size.
+    history_count: usize,
+    // How far back up/down has gone, 0 is the line being edited.
+    history_pos: usize,
 }
 
 impl Console {
-    pub fn new(uart: Uart) -> Self {
-        uart.enable_fifo();
-        uart.enable_interrupts();
+    pub const fn new() -> Self {
         Self {
-            uart
+            line: Line::EMPTY,
+            cursor: 0,
+            escape: Escape::None,
+            history: [Line::EMPTY; HISTORY_SIZE],
+            history_count: 0,
+            history_pos: 0,
+        }
+    }
+
+    pub fn prompt(&self) {
+        print!("{}", PROMPT);
+    }
+
+    /// Handle whatever input is waiting without blocking.
+    pub fn poll(&mut self) {
+        while let Some(c) = uart::try_read() {
+            self.input(c);
         }
     }
 
-    pub fn listen(&self) -> ! {
+    pub fn listen(&mut self) -> ! {
+        self.prompt();
         loop {
-            let c: u8 = self.uart.read_char();
-            match c {
-                8 => {
-                    // Backspace
-                    print!("{}{}{}", 8 as char, ' ', 8 as char);
-                },
-                10 | 13 => {
-                    println!();
-                }
-                _ => {
-                    print!("{}", c as char);
+            let c: u8 = uart::read_char();
+            self.input(c);
+        }
+    }
+
+    fn input(&mut self, c: u8) {
+        match self.escape {
+            Escape::Start => {
+                self.escape = if c == b'[' { Escape::Csi } else { Escape::None };
+                return;
+            }
+            Escape::Csi => {
+                self.escape = Escape::None;
+                match c {
+                    b'A' => self.history_up(),
+                    b'B' => self.history_down(),
+                    b'C' => self.cursor_right(),
+                    b'D' => self.cursor_left(),
+                    _ => {}
                 }
+                return;
             }
+            Escape::None => {}
+        }
+
+        match c {
+            ESCAPE => self.escape = Escape::Start,
+            BACKSPACE | DELETE => self.backspace(),
+            CTRL_C => {
+                println!("^C");
+                self.clear_line();
+                self.prompt();
+            }
+            10 | 13 => {
+                println!();
+                self.submit();
+                self.prompt();
+            }
+            32..=126 => self.insert(c),
+            _ => {}
         }
     }
-}
\ No newline at end of file
+
+    fn insert(&mut self, c: u8) {
+        if self.line.len == LINE_SIZE {
+            return;
+        }
+        self.line.buf.copy_within(self.cursor..self.line.len, self.cursor + 1);
+        self.line.buf[self.cursor] = c;
+        self.line.len += 1;
+        self.cursor += 1;
+        // Redraw from the new character to the end and walk back.
+        self.redraw_tail(0);
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor == 0 {
+            return;
+        }
+        self.line.buf.copy_within(self.cursor..self.line.len, self.cursor - 1);
+        self.line.len -= 1;
+        self.cursor -= 1;
+        print!("{}", BACKSPACE as char);
+        self.redraw_tail(1);
+    }
+
+    // Print from one before the cursor to the end of the line, blank out
+    // `erase` stale characters after it, and put the cursor back.
+    fn redraw_tail(&self, erase: usize) {
+        let start = if erase == 0 { self.cursor - 1 } else { self.cursor };
+        let tail = core::str::from_utf8(&self.line.buf[start..self.line.len]).unwrap_or("");
+        print!("{}", tail);
+        for _ in 0..erase {
+            print!(" ");
+        }
+        let back = self.line.len - self.cursor + erase;
+        if back > 0 {
+            print!("\x1b[{}D", back);
+        }
+    }
+
+    fn cursor_left(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            print!("\x1b[D");
+        }
+    }
+
+    fn cursor_right(&mut self) {
+        if self.cursor < self.line.len {
+            self.cursor += 1;
+            print!("\x1b[C");
+        }
+    }
+
+    fn clear_line(&mut self) {
+        self.line.len = 0;
+        self.cursor = 0;
+        self.history_pos = 0;
+    }
+
+    // Wipe what's on screen and show `line` instead.
+    fn replace_line(&mut self, line: Line) {
+        if self.cursor > 0 {
+            print!("\x1b[{}D", self.cursor);
+        }
+        print!("\x1b[K{}", line.as_str());
+        self.line = line;
+        self.cursor = line.len;
+    }
+
+    fn history_entry(&self, back: usize) -> Line {
+        self.history[(self.history_count - back) % HISTORY_SIZE]
+    }
+
+    fn history_up(&mut self) {
+        let available = self.history_count.min(HISTORY_SIZE);
+        if self.history_pos == available {
+            return;
+        }
+        self.history_pos += 1;
+        self.replace_line(self.history_entry(self.history_pos));
+    }
+
+    fn history_down(&mut self) {
+        if self.history_pos == 0 {
+            return;
+        }
+        self.history_pos -= 1;
+        let line = if self.history_pos == 0 {
+            Line::EMPTY
+        } else {
+            self.history_entry(self.history_pos)
+        };
+        self.replace_line(line);
+    }
+
+    fn submit(&mut self) {
+        let line = self.line;
+        self.clear_line();
+        if line.as_str().trim().is_empty() {
+            return;
+        }
+        self.history[self.history_count % HISTORY_SIZE] = line;
+        self.history_count += 1;
+        execute(line.as_str());
+    }
+}
+
+/// Parse `0x` prefixed hex or plain decimal.
+pub fn parse_number(s: &str) -> Option<usize> {
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Split `line` on whitespace and run the command it names.
+pub fn execute(line: &str) {
+    let mut args = [""; MAX_ARGS];
+    let mut argc = 0;
+    for word in line.split_whitespace() {
+        if argc == MAX_ARGS {
+            println!("too many arguments");
+            return;
+        }
+        args[argc] = word;
+        argc += 1;
+    }
+    if argc == 0 {
+        return;
+    }
+    match find(args[0]) {
+        Some(command) => (command.handler)(&args[1..argc]),
+        None => println!("{}: command not found, try help", args[0]),
+    }
+}
@@ -0,0 +1,321 @@
+//! Built-in shell commands.
+use super::{for_each_command, parse_number, Command};
+use crate::dev::pci::{PCICommonHeader, PCIDevice};
+use crate::dev::syscon;
+use crate::emu::{self, nes::rom::Rom, NES};
+use crate::plt;
+use crate::print;
+use crate::println;
+use crate::util::alloc::{Alloc, PAGE_SIZE};
+use crate::util::fdt::Node;
+use crate::util::heap;
+
+pub static BUILTINS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help [command]",
+        help: "list commands, or describe one",
+        handler: help,
+    },
+    Command {
+        name: "lspci",
+        usage: "lspci",
+        help: "list devices on PCI bus 0",
+        handler: lspci,
+    },
+    Command {
+        name: "meminfo",
+        usage: "meminfo",
+        help: "memory layout and page allocator usage",
+        handler: meminfo,
+    },
+    Command {
+        name: "peek",
+        usage: "peek <addr> [b|h|w|d]",
+        help: "read memory (default width d, 64 bits)",
+        handler: peek,
+    },
+    Command {
+        name: "poke",
+        usage: "poke <addr> <value> [b|h|w|d]",
+        help: "write memory (default width d, 64 bits)",
+        handler: poke,
+    },
+    Command {
+        name: "dt",
+        usage: "dt [path]",
+        help: "dump the device tree, or the subtree at path",
+        handler: dt,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        help: "reset the machine",
+        handler: reboot,
+    },
+    Command {
+        name: "poweroff",
+        usage: "poweroff",
+        help: "turn the machine off",
+        handler: poweroff,
+    },
+    Command {
+        name: "run",
+        usage: "run <rom|addr>",
+        help: "start the NES emulator on a built-in ROM or an iNES image in memory",
+        handler: run,
+    },
+];
+
+fn help(args: &[&str]) {
+    if let Some(name) = args.first() {
+        match super::find(name) {
+            Some(command) => println!("{}\n  {}", command.usage, command.help),
+            None => println!("{}: no such command", name),
+        }
+        return;
+    }
+    for command in for_each_command() {
+        println!("  {:<32} {}", command.usage, command.help);
+    }
+}
+
+fn lspci(_args: &[&str]) {
+    for slot in 0..32 {
+        let header = PCICommonHeader::get(0, slot);
+        if header.vendor_id == 0xFFFF {
+            continue;
+        }
+        let irq = PCIDevice::get(0, slot).irq();
+        print!(
+            "00:{:02X}.0 {:04X}:{:04X} class {:02X}{:02X}",
+            slot, header.vendor_id, header.device_id, header.class_code, header.subclass
+        );
+        match irq {
+            Some(irq) => println!(" irq {}", irq),
+            None => println!(),
+        }
+    }
+}
+
+fn meminfo(_args: &[&str]) {
+    let platform = plt::get();
+    let (heap_start, heap_end) = Alloc::heap_range();
+    let pages = Alloc::stats();
+    println!(
+        "RAM   {:#X} - {:#X} ({} KiB)",
+        platform.memory_base,
+        platform.memory_base + platform.memory_size,
+        platform.memory_size / 1024
+    );
+    println!("heap  {:#X} - {:#X}", heap_start, heap_end);
+    let free = pages.total - pages.used - pages.reserved;
+    println!(
+        "pages {} total, {} used in {} allocations, {} reserved, {} free ({} KiB)",
+        pages.total,
+        pages.used,
+        pages.allocations,
+        pages.reserved,
+        free,
+        free * PAGE_SIZE / 1024
+    );
+    print!("free blocks by order:");
+    for count in Alloc::free_blocks() {
+        print!(" {}", count);
+    }
+    println!();
+    if pages.bad_frees > 0 {
+        println!("      {} bad frees", pages.bad_frees);
+    }
+    let heap = heap::stats();
+    println!(
+        "kheap {} bytes in use, {} class pages, {} large pages, {} allocs, {} frees, {} failed",
+        heap.in_use, heap.class_pages, heap.large_pages, heap.allocs, heap.frees, heap.failures
+    );
+    if platform.fdt_addr != 0 {
+        println!(
+            "dtb   {:#X} - {:#X}",
+            platform.fdt_addr,
+            platform.fdt_addr + platform.fdt_size
+        );
+    }
+}
+
+// Access width from the optional b/h/w/d argument.
+fn width(arg: Option<&&str>) -> Option<usize> {
+    match arg.copied() {
+        None | Some("d") => Some(8),
+        Some("w") => Some(4),
+        Some("h") => Some(2),
+        Some("b") => Some(1),
+        _ => None,
+    }
+}
+
+fn peek(args: &[&str]) {
+    let (addr, width) = match (
+        args.first().and_then(|a| parse_number(a)),
+        width(args.get(1)),
+    ) {
+        (Some(addr), Some(width)) => (addr, width),
+        _ => {
+            println!("usage: peek <addr> [b|h|w|d]");
+            return;
+        }
+    };
+    if addr % width != 0 {
+        println!("{:#X} is not {} byte aligned", addr, width);
+        return;
+    }
+    let value = unsafe {
+        match width {
+            1 => (addr as *const u8).read_volatile() as u64,
+            2 => (addr as *const u16).read_volatile() as u64,
+            4 => (addr as *const u32).read_volatile() as u64,
+            _ => (addr as *const u64).read_volatile(),
+        }
+    };
+    println!("{:#X}: {:#0w$X}", addr, value, w = width * 2 + 2);
+}
+
+fn poke(args: &[&str]) {
+    let addr = args.first().and_then(|a| parse_number(a));
+    let value = args.get(1).and_then(|a| parse_number(a));
+    let (addr, value, width) = match (addr, value, width(args.get(2))) {
+        (Some(addr), Some(value), Some(width)) => (addr, value, width),
+        _ => {
+            println!("usage: poke <addr> <value> [b|h|w|d]");
+            return;
+        }
+    };
+    if addr % width != 0 {
+        println!("{:#X} is not {} byte aligned", addr, width);
+        return;
+    }
+    unsafe {
+        match width {
+            1 => (addr as *mut u8).write_volatile(value as u8),
+            2 => (addr as *mut u16).write_volatile(value as u16),
+            4 => (addr as *mut u32).write_volatile(value as u32),
+            _ => (addr as *mut u64).write_volatile(value as u64),
+        }
+    }
+}
+
+fn dt(args: &[&str]) {
+    let fdt = match plt::fdt() {
+        Some(fdt) => fdt,
+        None => {
+            println!("no device tree");
+            return;
+        }
+    };
+    let path = args.first().copied().unwrap_or("/");
+    match fdt.find_node(path) {
+        Some(node) => dump_node(&node, 0),
+        None => println!("{}: no such node", path),
+    }
+}
+
+fn dump_node(node: &Node, depth: usize) {
+    let name = if node.name.is_empty() { "/" } else { node.name };
+    println!("{:indent$}{} {{", "", name, indent = depth * 2);
+    for prop in node.properties() {
+        print!("{:indent$}{}", "", prop.name, indent = depth * 2 + 2);
+        print_value(prop.value);
+    }
+    for child in node.children() {
+        dump_node(&child, depth + 1);
+    }
+    println!("{:indent$}}};", "", indent = depth * 2);
+}
+
+// dtc style: strings, <cells> or [bytes].
+fn print_value(value: &[u8]) {
+    if value.is_empty() {
+        println!(";");
+        return;
+    }
+    let printable = value.iter().all(|&b| b == 0 || (32..127).contains(&b));
+    let is_strings = printable
+        && value[0] != 0
+        && value.last() == Some(&0)
+        && !value.windows(2).any(|w| w == [0, 0]);
+    if is_strings {
+        print!(" = ");
+        let mut strings = value[..value.len() - 1].split(|&b| b == 0).peekable();
+        while let Some(s) = strings.next() {
+            print!("\"{}\"", core::str::from_utf8(s).unwrap_or("?"));
+            if strings.peek().is_some() {
+                print!(", ");
+            }
+        }
+        println!(";");
+    } else if value.len().is_multiple_of(4) {
+        print!(" = <");
+        for (i, cell) in value.chunks(4).enumerate() {
+            let cell = u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]);
+            if i > 0 {
+                print!(" ");
+            }
+            print!("{:#X}", cell);
+        }
+        println!(">;");
+    } else {
+        print!(" = [");
+        for (i, b) in value.iter().enumerate() {
+            if i > 0 {
+                print!(" ");
+            }
+            print!("{:02X}", b);
+        }
+        println!("];");
+    }
+}
+
+fn reboot(_args: &[&str]) {
+    println!("Rebooting...");
+    syscon::reboot();
+}
+
+fn poweroff(_args: &[&str]) {
+    println!("Powering off...");
+    syscon::poweroff();
+}
+
+fn run(args: &[&str]) {
+    let name = match args.first() {
+        Some(name) => *name,
+        None => {
+            println!("usage: run <rom|addr>");
+            print!("built-in ROMs:");
+            for rom in emu::roms() {
+                print!(" {}", rom);
+            }
+            println!();
+            return;
+        }
+    };
+    let image = match parse_number(name) {
+        Some(addr) => unsafe { emu::rom_at(addr) },
+        None => emu::find_rom(name).ok_or(emu::EmuError::UnknownRom),
+    };
+    let rom = match image.and_then(|data| Rom::parse(data).map_err(emu::EmuError::Rom)) {
+        Ok(rom) => rom,
+        Err(e) => {
+            println!("{}: {:?}", name, e);
+            return;
+        }
+    };
+    println!(
+        "{}: mapper {}, {} KiB PRG, {} KiB CHR, {:?} mirroring",
+        name,
+        rom.mapper,
+        rom.prg.len() / 1024,
+        rom.chr.len() / 1024,
+        rom.mirroring
+    );
+    if let Err(e) = NES::new(rom).run() {
+        println!("emulator stopped: {:?}", e);
+    }
+}
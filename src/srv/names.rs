@@ -0,0 +1,178 @@
+//! The name server: services by name.
+//!
+//! A service registers an endpoint under a name such as "display" or
+//! "input" and clients look the name up to get a cap to send to it, so
+//! nobody needs to be handed a service's endpoint at start. A name belongs
+//! to whoever registered it last, which is how a restarted service takes
+//! over from the old one; the caps looked up for the old one are revoked
+//! then, and when it's removed, which happens by itself when the process
+//! that registered it ends. While that process lives, nobody else may
+//! register the name.
+//!
+//! A client that wants to know when that happens watches the name: its
+//! endpoint gets a notification with the bits it asked for every time the
+//! name is registered or removed, and it looks the name up again.
+use crate::print;
+use crate::println;
+use crate::srv::console::{self, Command};
+use crate::util::cap::{self, Cap, CapError, Object};
+use crate::util::ipc::Endpoint;
+use crate::util::lock::Mutex;
+use crate::util::process::Pid;
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+
+pub const MAX_NAME: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NameError {
+    // Empty, too long or not printable ASCII.
+    BadName,
+    NotFound,
+    // Removing a name for an endpoint it isn't registered to, or taking
+    // over one another process has registered.
+    NotOwner,
+    Cap(CapError),
+}
+
+impl From<CapError> for NameError {
+    fn from(e: CapError) -> Self {
+        NameError::Cap(e)
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    // The cap every lookup is derived from, revoked when the name moves on.
+    service: Option<Arc<Cap>>,
+    // The process that registered it, if it wasn't the kernel.
+    owner: Option<Pid>,
+    // How many times the name's been registered.
+    generation: usize,
+    watchers: Vec<(Weak<Endpoint>, usize)>,
+}
+
+impl Entry {
+    fn endpoint(&self) -> Option<&Arc<Endpoint>> {
+        match self.service.as_ref()?.object() {
+            Object::Endpoint(endpoint) => Some(endpoint),
+            _ => None,
+        }
+    }
+
+    // Revoke the old service's caps and tell the watchers.
+    fn changed(&mut self, service: Option<Arc<Cap>>) {
+        if let Some(old) = core::mem::replace(&mut self.service, service) {
+            old.revoke();
+        }
+        self.watchers
+            .retain(|(watcher, bits)| match watcher.upgrade() {
+                Some(endpoint) => {
+                    endpoint.notify(*bits);
+                    true
+                }
+                None => false,
+            });
+    }
+}
+
+static NAMES: Mutex<BTreeMap<String, Entry>> = Mutex::new(BTreeMap::new());
+
+pub fn init() {
+    let _ = console::register(Command {
+        name: "names",
+        usage: "names",
+        help: "list the services registered with the name server",
+        handler: names_command,
+    });
+}
+
+fn check(name: &str) -> Result<(), NameError> {
+    let printable = name.bytes().all(|b| b.is_ascii_graphic());
+    if name.is_empty() || name.len() > MAX_NAME || !printable {
+        return Err(NameError::BadName);
+    }
+    Ok(())
+}
+
+/// Make `name` refer to `endpoint`, in place of what it did before, on
+/// behalf of process `owner`. Fails if another process registered it and
+/// hasn't ended.
+pub fn register(name: &str, endpoint: Arc<Endpoint>, owner: Option<Pid>) -> Result<(), NameError> {
+    check(name)?;
+    let service = Cap::new(Object::Endpoint(endpoint), cap::SEND | cap::GRANT);
+    let mut names = NAMES.lock();
+    let entry = names.entry(String::from(name)).or_default();
+    // `process_exit` clears the owner, so one that's set is still running.
+    if entry.owner.is_some() && entry.owner != owner {
+        return Err(NameError::NotOwner);
+    }
+    entry.owner = owner;
+    entry.generation += 1;
+    entry.changed(Some(service));
+    Ok(())
+}
+
+/// Stop `name` referring to `endpoint`. Watchers still hear about it being
+/// registered again.
+pub fn remove(name: &str, endpoint: &Arc<Endpoint>) -> Result<(), NameError> {
+    let mut names = NAMES.lock();
+    let entry = names.get_mut(name).ok_or(NameError::NotFound)?;
+    match entry.endpoint() {
+        Some(current) if Arc::ptr_eq(current, endpoint) => {}
+        Some(_) => return Err(NameError::NotOwner),
+        None => return Err(NameError::NotFound),
+    }
+    entry.owner = None;
+    entry.changed(None);
+    Ok(())
+}
+
+/// Remove the names process `pid` registered, now that it's gone.
+pub fn process_exit(pid: Pid) {
+    for entry in NAMES.lock().values_mut() {
+        if entry.owner == Some(pid) && entry.service.is_some() {
+            entry.owner = None;
+            entry.changed(None);
+        }
+    }
+}
+
+/// A cap to send to the service called `name`, good until it's replaced.
+pub fn lookup(name: &str) -> Result<Arc<Cap>, NameError> {
+    let names = NAMES.lock();
+    let service = names
+        .get(name)
+        .and_then(|entry| entry.service.as_ref())
+        .ok_or(NameError::NotFound)?;
+    Ok(service.derive(service.rights(), None)?)
+}
+
+/// Notify `endpoint` with `bits` whenever `name` is registered or removed.
+/// The name needn't be registered yet.
+pub fn watch(name: &str, endpoint: &Arc<Endpoint>, bits: usize) -> Result<(), NameError> {
+    check(name)?;
+    let mut names = NAMES.lock();
+    let entry = names.entry(String::from(name)).or_default();
+    entry
+        .watchers
+        .retain(|(watcher, _)| watcher.strong_count() > 0);
+    entry.watchers.push((Arc::downgrade(endpoint), bits));
+    Ok(())
+}
+
+fn names_command(_args: &[&str]) {
+    println!(
+        "{:<16} {:>8} {:>10} {:>8}",
+        "NAME", "ENDPOINT", "GENERATION", "WATCHERS"
+    );
+    for (name, entry) in NAMES.lock().iter() {
+        match entry.endpoint() {
+            Some(endpoint) => print!("{:<16} {:>8}", name, endpoint.id()),
+            None => print!("{:<16} {:>8}", name, "-"),
+        }
+        println!(" {:>10} {:>8}", entry.generation, entry.watchers.len());
+    }
+}
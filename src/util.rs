@@ -1,4 +1,16 @@
 pub mod alloc;
+pub mod cap;
+pub mod elf;
+pub mod exec;
+pub mod fdt;
+pub mod heap;
 pub mod thread;
 pub mod interrupt;
+pub mod ipc;
+pub mod lock;
+pub mod once;
+pub mod process;
+pub mod ring;
+pub mod syscall;
+pub mod trap;
 pub mod std;
\ No newline at end of file
@@ -1,128 +1,244 @@
+//! Physical page frame allocator.
+//!
+//! Hands out 4 KiB aligned frames from the RAM the device tree describes.
+//! The frames are managed by a buddy allocator whose per-page bookkeeping
+//! lives out of band at the start of the heap, so every frame handed out is
+//! a whole page and requests are rounded up to a power of two pages.
+pub mod buddy;
+
+use crate::plt;
 use crate::print;
 use crate::println;
-use bitfield_struct::bitfield;
+use crate::util::lock::{IrqSpinLock, IrqSpinLockGuard};
+use buddy::Buddy;
 use core::option::Option;
-use core::ptr::null_mut;
-use core::{assert, mem};
 
-const PAGE_SIZE: usize = 4096 + 2;
+pub const PAGE_SIZE: usize = 4096;
+// Room left for the boot stack, which grows down from `_stack_start`.
+const KERNEL_STACK_SIZE: usize = 0x1_0000;
+// Boot stack, device tree and /memreserve/ entries.
+const MAX_RESERVED: usize = 8;
 
 extern "C" {
     static _heap_start: usize;
-    static _heap_end: usize;
+    static _stack_start: usize;
 }
 
-static mut KMEM_HEAD: *const usize = null_mut();
-static mut KMEM_END: *const usize = null_mut();
-static mut KMEM_NUM_PAGES: usize = 0;
+// Everything the allocator knows, behind one lock.
+struct Frames {
+    buddy: Buddy,
+    // First and one past the last frame managed.
+    head: usize,
+    end: usize,
+    stats: PageStats,
+}
+
+static FRAMES: IrqSpinLock<Frames> = IrqSpinLock::new(Frames {
+    buddy: Buddy::empty(),
+    head: 0,
+    end: 0,
+    stats: PageStats {
+        total: 0,
+        used: 0,
+        reserved: 0,
+        allocations: 0,
+        bad_frees: 0,
+    },
+});
+
+// The buddy allocator's metadata is raw pointers into the heap, which only
+// FRAMES hands out.
+unsafe impl Send for Frames {}
 
 pub struct Alloc;
 
-#[bitfield(u16)]
-pub struct Page {
-    taken: bool,
-    last: bool,
-    #[bits(14)]
-    num_reserved: usize,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    // Not a page address this allocator hands out.
+    OutOfRange,
+    Misaligned,
+    // Already free, i.e. a double free.
+    NotAllocated,
+    // Inside an allocation rather than at its first frame.
+    NotStart,
+    // Never handed out: the boot stack, the device tree and /memreserve/.
+    Reserved,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct PageStats {
+    pub total: usize,
+    /// Frames in live allocations, not counting reserved ones.
+    pub used: usize,
+    pub reserved: usize,
+    /// Live allocations.
+    pub allocations: usize,
+    /// Rejected frees, double frees included.
+    pub bad_frees: usize,
+}
+
+fn align_up(addr: usize) -> usize {
+    (addr + PAGE_SIZE - 1) & !(PAGE_SIZE - 1)
+}
+
+fn align_down(addr: usize) -> usize {
+    addr & !(PAGE_SIZE - 1)
+}
+
+fn frames() -> IrqSpinLockGuard<'static, Frames> {
+    FRAMES.lock()
 }
 
 impl Alloc {
     pub fn init() {
-        unsafe {
-            let heap_size =
-                (&_heap_end as *const usize as usize) - (&_heap_start as *const usize as usize);
-
-            // Initialize static members.
-            KMEM_HEAD = Self::get_heap_start();
-            KMEM_END = Self::get_heap_end();
-            KMEM_NUM_PAGES = heap_size / PAGE_SIZE;
-
-            // Initializing pages.
-            let mut temp_page = KMEM_HEAD as *mut Page;
-            for i in 0..KMEM_NUM_PAGES {
-                // Initialize fields.
-                (*temp_page).set_taken(false);
-                (*temp_page).set_num_reserved(0);
-
-                // If at last page
-                if i == KMEM_NUM_PAGES - 1 {
-                    (*temp_page).set_last(true);
-                } else {
-                    (*temp_page).set_last(false);
+        let heap_start = Self::get_heap_start();
+        let heap_end = Self::get_heap_end();
+        // Enough bookkeeping for every frame up to the end, then the frames
+        // start at the next page boundary after it.
+        let frame_count = (heap_end - heap_start) / PAGE_SIZE;
+        let head = align_up(heap_start + Buddy::meta_size(frame_count));
+        let pages = (heap_end - head) / PAGE_SIZE;
+
+        let mut reserved = [(0, 0); MAX_RESERVED];
+        let mut count = 0;
+        let stack_start = unsafe { &_stack_start as *const usize as usize };
+        reserved[count] = (stack_start - KERNEL_STACK_SIZE, stack_start);
+        count += 1;
+        let platform = plt::get();
+        reserved[count] = (platform.fdt_addr, platform.fdt_addr + platform.fdt_size);
+        count += 1;
+        if let Some(fdt) = plt::fdt() {
+            for r in fdt.reservations() {
+                if count == MAX_RESERVED {
+                    println!("[ALLOC]: too many /memreserve/ entries");
+                    break;
                 }
-
-                // Go to the next page.
-                temp_page = temp_page.offset(PAGE_SIZE as isize);
+                reserved[count] = (r.address as usize, (r.address + r.size) as usize);
+                count += 1;
             }
-
-            assert!(
-                temp_page > KMEM_END as *mut Page,
-                "Did not initialize all pages"
-            );
         }
-    }
 
-    pub fn get(num_requested: usize) -> Option<*mut Page> {
-        let mut page;
+        let mut frames = frames();
+        frames.head = head;
+        frames.end = heap_end;
         unsafe {
-            page = KMEM_HEAD as *mut Page;
-            let mut temp_page = page;
-            let mut num_pages_curr = 1;
-            while !(*temp_page).last() {
-                // If the current page is taken jump to the next section.
-                if (*page).taken() {
-                    let num_pages_to_jump = (*page).num_reserved();
-                    page = page.offset((PAGE_SIZE * num_pages_to_jump / 2) as isize);
-                    temp_page = page;
-                    continue;
-                }
-
-                // If the lead page is on a taken page move page then increment page after.
-                if (*temp_page).taken() {
-                    page = temp_page;
-                    num_pages_curr = 1;
-                    continue;
-                }
-
-                // If neither the head or tail are taken then you have a valid block.
-                if num_pages_curr == num_requested {
-                    (*page).set_taken(true);
-                    (*page).set_num_reserved(num_requested);
-                    return Some(page.offset(1));
-                }
+            frames.buddy.init(head, pages, heap_start as *mut u8);
+        }
 
-                // Increment the tail.
-                temp_page = temp_page.offset((PAGE_SIZE / 2) as isize);
-                num_pages_curr += 1;
+        // Release every run of frames that misses the reserved ranges.
+        let is_reserved = |frame: usize| {
+            reserved[..count]
+                .iter()
+                .any(|&(start, end)| frame < end && start < frame + PAGE_SIZE)
+        };
+        let mut run_start = head;
+        let mut frame = head;
+        while frame < heap_end {
+            if is_reserved(frame) {
+                frames.buddy.release(run_start, frame);
+                run_start = frame + PAGE_SIZE;
             }
+            frame += PAGE_SIZE;
         }
-        // No space.
-        return None;
+        frames.buddy.release(run_start, heap_end);
+
+        let reserved = pages - frames.buddy.free_page_count();
+        frames.stats = PageStats {
+            total: pages,
+            used: 0,
+            reserved,
+            allocations: 0,
+            bad_frees: 0,
+        };
+        drop(frames);
+        println!(
+            "[ALLOC]: {} pages at {:#X} - {:#X}, {} reserved",
+            pages, head, heap_end, reserved
+        );
     }
 
-    pub fn free<T>(ptr: *const T) {
-        unsafe {
-            let mut page = ptr as *mut Page;
-            page = page.offset(-1);
-
-            let num_pages_to_free = (*page).num_reserved();
-            for _ in 0..num_pages_to_free {
-                (*page).set_taken(false);
-                (*page).set_num_reserved(0);
-                page = page.offset((PAGE_SIZE / 2) as isize);
-            }
+    /// At least `num_requested` contiguous frames, rounded up to a power of
+    /// two and aligned to that size. The memory is not zeroed.
+    pub fn get(num_requested: usize) -> Option<*mut u8> {
+        if num_requested == 0 {
+            return None;
         }
+        Self::alloc_pages(buddy::order_for(num_requested))
     }
 
-    fn get_heap_start() -> *const usize {
+    /// A block of 2^order frames aligned to its size.
+    pub fn alloc_pages(order: usize) -> Option<*mut u8> {
+        let mut frames = frames();
+        let addr = frames.buddy.alloc_pages(order)?;
+        frames.stats.used += 1 << order;
+        frames.stats.allocations += 1;
+        Some(addr as *mut u8)
+    }
+
+    /// Like `get`, but the frames are zeroed.
+    pub fn zalloc(num_requested: usize) -> Option<*mut u8> {
+        let page = Self::get(num_requested)?;
+        let order = frames().buddy.order_of(page as usize)?;
         unsafe {
-            return &_heap_start as *const usize;
+            page.write_bytes(0, PAGE_SIZE << order);
         }
+        Some(page)
     }
 
-    fn get_heap_end() -> *const usize {
-        unsafe {
-            return &_heap_end as *const usize;
+    /// Give back an allocation from `get` or `alloc_pages`. Frees that don't
+    /// match one are refused and counted rather than corrupting anything.
+    pub fn free<T>(ptr: *const T) -> Result<(), AllocError> {
+        let result = {
+            let mut frames = frames();
+            let result = frames.buddy.free_pages(ptr as usize);
+            match result {
+                Ok(order) => {
+                    frames.stats.used -= 1 << order;
+                    frames.stats.allocations -= 1;
+                }
+                Err(_) => frames.stats.bad_frees += 1,
+            }
+            result
+        };
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                println!("[ALLOC]: bad free of {:#X}: {:?}", ptr as usize, e);
+                Err(e)
+            }
         }
     }
+
+    /// Same as `free`, named to pair with `alloc_pages`.
+    pub fn free_pages<T>(ptr: *const T) -> Result<(), AllocError> {
+        Self::free(ptr)
+    }
+
+    /// Free blocks of each order, smallest first.
+    pub fn free_blocks() -> [usize; buddy::MAX_ORDER + 1] {
+        let frames = frames();
+        core::array::from_fn(|order| frames.buddy.free_blocks(order))
+    }
+
+    pub fn stats() -> PageStats {
+        frames().stats
+    }
+
+    /// Start and end address of the frames being managed.
+    pub fn heap_range() -> (usize, usize) {
+        let frames = frames();
+        (frames.head, frames.end)
+    }
+
+    fn get_heap_start() -> usize {
+        unsafe { &_heap_start as *const usize as usize }
+    }
+
+    // The heap runs from the end of the kernel image to the end of RAM as
+    // reported by the device tree. Whatever else lives up there is reserved
+    // in `init`.
+    fn get_heap_end() -> usize {
+        let platform = plt::get();
+        align_down(platform.memory_base + platform.memory_size)
+    }
 }
@@ -0,0 +1,436 @@
+//! Binary buddy allocator over a range of page frames.
+//!
+//! Blocks are 2^order pages and aligned to their own size in physical
+//! memory, so a block's buddy is found by flipping one address bit. Free
+//! blocks sit on per-order doubly linked lists threaded through the free
+//! pages themselves; the per-page state is a separate byte array.
+use super::{AllocError, PAGE_SIZE};
+use core::ptr::null_mut;
+
+/// Largest block is 2^MAX_ORDER pages (4 MiB).
+pub const MAX_ORDER: usize = 10;
+
+// Per-page state. Only the first page of a block is marked; the rest are 0.
+const FREE: u8 = 1 << 7;
+const ALLOCATED: u8 = 1 << 6;
+const RESERVED: u8 = 1 << 5;
+const ORDER_MASK: u8 = 0x1F;
+
+struct FreeNode {
+    next: *mut FreeNode,
+    prev: *mut FreeNode,
+}
+
+pub struct Buddy {
+    base: usize,
+    pages: usize,
+    meta: *mut u8,
+    free_lists: [*mut FreeNode; MAX_ORDER + 1],
+    free_blocks: [usize; MAX_ORDER + 1],
+    free_pages: usize,
+}
+
+/// Smallest order whose blocks hold `pages` pages.
+pub fn order_for(pages: usize) -> usize {
+    pages.max(1).next_power_of_two().trailing_zeros() as usize
+}
+
+impl Buddy {
+    pub const fn empty() -> Self {
+        Self {
+            base: 0,
+            pages: 0,
+            meta: null_mut(),
+            free_lists: [null_mut(); MAX_ORDER + 1],
+            free_blocks: [0; MAX_ORDER + 1],
+            free_pages: 0,
+        }
+    }
+
+    /// Bytes of bookkeeping `init` needs for `pages` frames.
+    pub const fn meta_size(pages: usize) -> usize {
+        pages
+    }
+
+    /// Manage the `pages` frames from page aligned `base`, keeping state in
+    /// the `meta_size(pages)` bytes at `meta`. Every frame starts out
+    /// reserved; hand them over with `release`.
+    ///
+    /// # Safety
+    /// The frames and `meta` must be valid, unused memory for as long as
+    /// the allocator is.
+    pub unsafe fn init(&mut self, base: usize, pages: usize, meta: *mut u8) {
+        *self = Self::empty();
+        self.base = base;
+        self.pages = pages;
+        self.meta = meta;
+        meta.write_bytes(RESERVED, pages);
+    }
+
+    /// Make the reserved frames in [start, end) available.
+    pub fn release(&mut self, start: usize, end: usize) {
+        let mut addr = start.max(self.base);
+        let end = end.min(self.end());
+        while addr < end {
+            if self.state(addr) == RESERVED {
+                self.set_state(addr, 0);
+                self.free_pages += 1;
+                self.insert(addr, 0);
+            }
+            addr += PAGE_SIZE;
+        }
+    }
+
+    /// A block of 2^order pages, aligned to its size.
+    pub fn alloc_pages(&mut self, order: usize) -> Option<usize> {
+        if order > MAX_ORDER {
+            return None;
+        }
+        let mut k = (order..=MAX_ORDER).find(|&k| !self.free_lists[k].is_null())?;
+        let addr = self.free_lists[k] as usize;
+        self.unlink(addr, k);
+        // Split, keeping the lower half and freeing the upper.
+        while k > order {
+            k -= 1;
+            let upper = addr + (PAGE_SIZE << k);
+            self.push(upper, k);
+        }
+        self.set_state(addr, ALLOCATED | order as u8);
+        self.free_pages -= 1 << order;
+        Some(addr)
+    }
+
+    /// Give back a block from `alloc_pages`, merging it with its buddies.
+    /// Returns the block's order.
+    pub fn free_pages(&mut self, addr: usize) -> Result<usize, AllocError> {
+        if !self.contains(addr) {
+            return Err(AllocError::OutOfRange);
+        }
+        if !addr.is_multiple_of(PAGE_SIZE) {
+            return Err(AllocError::Misaligned);
+        }
+        let state = self.state(addr);
+        let order = (state & ORDER_MASK) as usize;
+        match state & !ORDER_MASK {
+            ALLOCATED => {}
+            RESERVED => return Err(AllocError::Reserved),
+            FREE => return Err(AllocError::NotAllocated),
+            _ => return Err(AllocError::NotStart),
+        }
+        self.set_state(addr, 0);
+        self.free_pages += 1 << order;
+        self.insert(addr, order);
+        Ok(order)
+    }
+
+    /// Order of the allocated block starting at `addr`.
+    pub fn order_of(&self, addr: usize) -> Option<usize> {
+        if !self.contains(addr) || !addr.is_multiple_of(PAGE_SIZE) {
+            return None;
+        }
+        let state = self.state(addr);
+        if state & ALLOCATED == 0 {
+            return None;
+        }
+        Some((state & ORDER_MASK) as usize)
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.base && addr < self.end()
+    }
+
+    pub fn total_pages(&self) -> usize {
+        self.pages
+    }
+
+    pub fn free_page_count(&self) -> usize {
+        self.free_pages
+    }
+
+    /// Number of free blocks of `order`.
+    pub fn free_blocks(&self, order: usize) -> usize {
+        self.free_blocks[order]
+    }
+
+    fn end(&self) -> usize {
+        self.base + self.pages * PAGE_SIZE
+    }
+
+    fn state(&self, addr: usize) -> u8 {
+        unsafe { *self.meta.add((addr - self.base) / PAGE_SIZE) }
+    }
+
+    fn set_state(&mut self, addr: usize, state: u8) {
+        unsafe {
+            *self.meta.add((addr - self.base) / PAGE_SIZE) = state;
+        }
+    }
+
+    // Free a block, merging upwards while its buddy is free and whole.
+    fn insert(&mut self, mut addr: usize, mut order: usize) {
+        while order < MAX_ORDER {
+            let buddy = addr ^ (PAGE_SIZE << order);
+            if !self.contains(buddy) || self.state(buddy) != FREE | order as u8 {
+                break;
+            }
+            self.unlink(buddy, order);
+            self.set_state(buddy, 0);
+            addr = addr.min(buddy);
+            order += 1;
+        }
+        self.push(addr, order);
+    }
+
+    fn push(&mut self, addr: usize, order: usize) {
+        let node = addr as *mut FreeNode;
+        let head = self.free_lists[order];
+        unsafe {
+            (*node).prev = null_mut();
+            (*node).next = head;
+            if !head.is_null() {
+                (*head).prev = node;
+            }
+        }
+        self.free_lists[order] = node;
+        self.free_blocks[order] += 1;
+        self.set_state(addr, FREE | order as u8);
+    }
+
+    fn unlink(&mut self, addr: usize, order: usize) {
+        let node = addr as *mut FreeNode;
+        unsafe {
+            let next = (*node).next;
+            let prev = (*node).prev;
+            if prev.is_null() {
+                self.free_lists[order] = next;
+            } else {
+                (*prev).next = next;
+            }
+            if !next.is_null() {
+                (*next).prev = prev;
+            }
+        }
+        self.free_blocks[order] -= 1;
+        self.set_state(addr, 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::{alloc, dealloc, Layout};
+    use std::vec;
+    use std::vec::Vec;
+
+    const MAX_BLOCK: usize = PAGE_SIZE << MAX_ORDER;
+
+    // Host memory standing in for RAM, aligned like a max order block so
+    // the expected addresses are easy to work out.
+    struct Region {
+        mem: *mut u8,
+        layout: Layout,
+        meta: Vec<u8>,
+        buddy: Buddy,
+    }
+
+    impl Region {
+        // `pages` frames starting `skip` pages into an aligned region.
+        fn new(skip: usize, pages: usize) -> Region {
+            let bytes = ((skip + pages) * PAGE_SIZE).next_multiple_of(MAX_BLOCK);
+            let layout = Layout::from_size_align(bytes, MAX_BLOCK).unwrap();
+            let mem = unsafe { alloc(layout) };
+            assert!(!mem.is_null());
+            let mut region = Region {
+                mem,
+                layout,
+                meta: vec![0; Buddy::meta_size(pages)],
+                buddy: Buddy::empty(),
+            };
+            let base = region.addr(skip);
+            unsafe {
+                region.buddy.init(base, pages, region.meta.as_mut_ptr());
+            }
+            region.buddy.release(base, base + pages * PAGE_SIZE);
+            region
+        }
+
+        fn addr(&self, page: usize) -> usize {
+            self.mem as usize + page * PAGE_SIZE
+        }
+    }
+
+    impl Drop for Region {
+        fn drop(&mut self) {
+            unsafe { dealloc(self.mem, self.layout) }
+        }
+    }
+
+    fn overlaps(a: (usize, usize), b: (usize, usize)) -> bool {
+        a.0 < b.0 + b.1 && b.0 < a.0 + a.1
+    }
+
+    #[test]
+    fn order_for_pages() {
+        assert_eq!(order_for(0), 0);
+        assert_eq!(order_for(1), 0);
+        assert_eq!(order_for(2), 1);
+        assert_eq!(order_for(3), 2);
+        assert_eq!(order_for(1024), 10);
+    }
+
+    #[test]
+    fn release_coalesces_into_max_blocks() {
+        let r = Region::new(0, 2 << MAX_ORDER);
+        assert_eq!(r.buddy.free_blocks(MAX_ORDER), 2);
+        assert_eq!(r.buddy.free_page_count(), 2 << MAX_ORDER);
+        for order in 0..MAX_ORDER {
+            assert_eq!(r.buddy.free_blocks(order), 0);
+        }
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let mut r = Region::new(0, 1 << MAX_ORDER);
+        let page = r.buddy.alloc_pages(0).unwrap();
+        assert_eq!(page, r.addr(0));
+        // One free block of every smaller order is left over from splitting.
+        for order in 0..MAX_ORDER {
+            assert_eq!(r.buddy.free_blocks(order), 1, "order {}", order);
+        }
+        assert_eq!(r.buddy.free_blocks(MAX_ORDER), 0);
+        assert_eq!(r.buddy.free_pages(page), Ok(0));
+        assert_eq!(r.buddy.free_blocks(MAX_ORDER), 1);
+        for order in 0..MAX_ORDER {
+            assert_eq!(r.buddy.free_blocks(order), 0);
+        }
+    }
+
+    #[test]
+    fn blocks_are_aligned_to_their_size() {
+        // Start three pages into an aligned region so nothing lines up by
+        // accident.
+        let mut r = Region::new(3, 700);
+        let mut blocks = Vec::new();
+        for order in [0, 3, 1, 5, 2, 7, 0, 4] {
+            let addr = r.buddy.alloc_pages(order).unwrap();
+            assert_eq!(addr % (PAGE_SIZE << order), 0, "order {}", order);
+            assert_eq!(r.buddy.order_of(addr), Some(order));
+            blocks.push((addr, PAGE_SIZE << order));
+        }
+        for (i, a) in blocks.iter().enumerate() {
+            assert!(r.buddy.contains(a.0) && r.buddy.contains(a.0 + a.1 - 1));
+            for b in &blocks[i + 1..] {
+                assert!(!overlaps(*a, *b));
+            }
+        }
+        for (addr, _) in blocks {
+            r.buddy.free_pages(addr).unwrap();
+        }
+        assert_eq!(r.buddy.free_page_count(), 700);
+    }
+
+    // The same pattern as the allocator test in kmain: two page blocks with
+    // holes punched in them, then filled with single pages.
+    #[test]
+    fn fragmentation_pattern() {
+        let mut r = Region::new(0, 1 << MAX_ORDER);
+        let page = r.buddy.alloc_pages(1).unwrap();
+        let page2 = r.buddy.alloc_pages(1).unwrap();
+        let page3 = r.buddy.alloc_pages(1).unwrap();
+        let page4 = r.buddy.alloc_pages(1).unwrap();
+        let page5 = r.buddy.alloc_pages(1).unwrap();
+        assert_eq!(
+            [page, page2, page3, page4, page5],
+            [r.addr(0), r.addr(2), r.addr(4), r.addr(6), r.addr(8)]
+        );
+        r.buddy.free_pages(page2).unwrap();
+        r.buddy.free_pages(page4).unwrap();
+
+        // The holes get split before anything bigger is touched.
+        let page6 = r.buddy.alloc_pages(0).unwrap();
+        let page7 = r.buddy.alloc_pages(0).unwrap();
+        let page8 = r.buddy.alloc_pages(0).unwrap();
+        let page9 = r.buddy.alloc_pages(0).unwrap();
+        let page10 = r.buddy.alloc_pages(0).unwrap();
+        let mut singles = [page6, page7, page8, page9];
+        singles.sort();
+        assert_eq!(singles, [r.addr(2), r.addr(3), r.addr(6), r.addr(7)]);
+        assert_eq!(page10, r.addr(10));
+
+        for p in [page, page3, page5, page6, page7, page8, page9, page10] {
+            r.buddy.free_pages(p).unwrap();
+        }
+        assert_eq!(r.buddy.free_blocks(MAX_ORDER), 1);
+        assert_eq!(r.buddy.free_page_count(), 1 << MAX_ORDER);
+    }
+
+    #[test]
+    fn checkerboard_does_not_merge_until_buddies_free() {
+        let mut r = Region::new(0, 64);
+        let pages: Vec<usize> = (0..64).map(|_| r.buddy.alloc_pages(0).unwrap()).collect();
+        assert_eq!(r.buddy.alloc_pages(0), None);
+        for p in pages.iter().step_by(2) {
+            r.buddy.free_pages(*p).unwrap();
+        }
+        assert_eq!(r.buddy.free_blocks(0), 32);
+        // No two free pages are buddies, so nothing bigger exists.
+        assert_eq!(r.buddy.alloc_pages(1), None);
+        for p in pages.iter().skip(1).step_by(2) {
+            r.buddy.free_pages(*p).unwrap();
+        }
+        assert_eq!(r.buddy.free_blocks(0), 0);
+        assert_eq!(r.buddy.free_blocks(6), 1);
+    }
+
+    #[test]
+    fn exhaustion() {
+        let mut r = Region::new(0, 37);
+        let mut count = 0;
+        while r.buddy.alloc_pages(0).is_some() {
+            count += 1;
+        }
+        assert_eq!(count, 37);
+        assert_eq!(r.buddy.free_page_count(), 0);
+        assert_eq!(r.buddy.alloc_pages(MAX_ORDER + 1), None);
+    }
+
+    #[test]
+    fn reserved_frames_are_skipped() {
+        let mut meta = vec![0u8; 16];
+        let layout = Layout::from_size_align(16 * PAGE_SIZE, MAX_BLOCK).unwrap();
+        let mem = unsafe { alloc(layout) } as usize;
+        let mut buddy = Buddy::empty();
+        unsafe {
+            buddy.init(mem, 16, meta.as_mut_ptr());
+        }
+        // Page 5 stays reserved.
+        buddy.release(mem, mem + 5 * PAGE_SIZE);
+        buddy.release(mem + 6 * PAGE_SIZE, mem + 16 * PAGE_SIZE);
+        assert_eq!(buddy.free_page_count(), 15);
+        assert_eq!(buddy.free_pages(mem + 5 * PAGE_SIZE), Err(AllocError::Reserved));
+        let mut got = Vec::new();
+        while let Some(addr) = buddy.alloc_pages(0) {
+            got.push(addr);
+        }
+        assert!(!got.contains(&(mem + 5 * PAGE_SIZE)));
+        // 8 pages would have to straddle the hole.
+        for addr in got {
+            buddy.free_pages(addr).unwrap();
+        }
+        assert_eq!(buddy.free_blocks(3), 1);
+        assert_eq!(buddy.alloc_pages(4), None);
+        unsafe { dealloc(mem as *mut u8, layout) }
+    }
+
+    #[test]
+    fn bad_frees() {
+        let mut r = Region::new(0, 16);
+        let block = r.buddy.alloc_pages(2).unwrap();
+        assert_eq!(r.buddy.free_pages(block + PAGE_SIZE), Err(AllocError::NotStart));
+        assert_eq!(r.buddy.free_pages(block + 8), Err(AllocError::Misaligned));
+        assert_eq!(r.buddy.free_pages(r.addr(16)), Err(AllocError::OutOfRange));
+        assert_eq!(r.buddy.free_pages(block), Ok(2));
+        assert_eq!(r.buddy.free_pages(block), Err(AllocError::NotAllocated));
+        assert_eq!(r.buddy.free_page_count(), 16);
+    }
+}
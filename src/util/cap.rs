@@ -0,0 +1,455 @@
+//! Capabilities: which kernel objects a user program may use, and how.
+//!
+//! Every user thread has a table of capabilities, shared with the other
+//! threads in its space, and names them in syscalls by slot number. A cap is
+//! an object and the rights it gives over it. That's the only way a program
+//! gets at an endpoint, memory, device registers or an interrupt; there are
+//! no global names to guess.
+//!
+//! `derive` makes a child of a cap with fewer rights or, for memory, a
+//! smaller window onto it. `revoke` kills every descendant of a cap,
+//! wherever they've been passed to, by bumping the cap's epoch: a child is
+//! only good while its parent is and still has the epoch the child was made
+//! in. Dead caps are cleared out of a table when they're next looked up.
+//! Revoking doesn't undo mappings already made with a cap.
+//!
+//! A cap goes to another program in an IPC message with `MSG_CAP` set, see
+//! `ipc`. The receiver gets a child of the sender's cap, so the sender can
+//! take it back, and only caps with the GRANT right can be sent.
+use crate::mm::region::Region;
+use crate::util::alloc::PAGE_SIZE;
+use crate::util::ipc::Endpoint;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const MAX_CAPS: usize = 64;
+
+// Rights.
+pub const READ: usize = 1 << 0;
+pub const WRITE: usize = 1 << 1;
+pub const SEND: usize = 1 << 2;
+pub const RECEIVE: usize = 1 << 3;
+// May be passed on over IPC.
+pub const GRANT: usize = 1 << 4;
+pub const ALL_RIGHTS: usize = READ | WRITE | SEND | RECEIVE | GRANT;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CapError {
+    // Out of range, or nothing there.
+    InvalidSlot,
+    // Its parent, or an ancestor of that, was revoked.
+    Revoked,
+    NoRights,
+    // Not the kind of object the operation is for.
+    WrongKind,
+    TableFull,
+    // A window that isn't whole pages inside the parent's.
+    BadWindow,
+}
+
+/// What a cap refers to.
+#[derive(Clone)]
+pub enum Object {
+    Endpoint(Arc<Endpoint>),
+    // A window onto a region of RAM.
+    Memory {
+        region: Arc<Region>,
+        offset: usize,
+        size: usize,
+    },
+    // Device registers or memory, such as the VGA framebuffer BAR.
+    Mmio {
+        base: usize,
+        size: usize,
+    },
+    // A PLIC interrupt source.
+    Irq(u32),
+    // Console input.
+    Console,
+}
+
+/// Object kinds as cap_info reports them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Endpoint = 1,
+    Memory = 2,
+    Mmio = 3,
+    Irq = 4,
+    Console = 5,
+}
+
+impl Object {
+    pub fn kind(&self) -> Kind {
+        match self {
+            Object::Endpoint(_) => Kind::Endpoint,
+            Object::Memory { .. } => Kind::Memory,
+            Object::Mmio { .. } => Kind::Mmio,
+            Object::Irq(_) => Kind::Irq,
+            Object::Console => Kind::Console,
+        }
+    }
+
+    /// Bytes of memory, or the interrupt number, or 0.
+    pub fn size(&self) -> usize {
+        match self {
+            Object::Memory { size, .. } | Object::Mmio { size, .. } => *size,
+            Object::Irq(irq) => *irq as usize,
+            _ => 0,
+        }
+    }
+
+    // The object cut down to [offset, offset + size) of its window.
+    fn window(&self, offset: usize, size: usize) -> Result<Object, CapError> {
+        let inside = |whole: usize| {
+            offset.is_multiple_of(PAGE_SIZE)
+                && size.is_multiple_of(PAGE_SIZE)
+                && size > 0
+                && offset.checked_add(size).is_some_and(|end| end <= whole)
+        };
+        match self {
+            Object::Memory {
+                region,
+                offset: start,
+                size: whole,
+            } if inside(*whole) => Ok(Object::Memory {
+                region: region.clone(),
+                offset: start + offset,
+                size,
+            }),
+            Object::Mmio { base, size: whole } if inside(*whole) => Ok(Object::Mmio {
+                base: base + offset,
+                size,
+            }),
+            Object::Memory { .. } | Object::Mmio { .. } => Err(CapError::BadWindow),
+            _ => Err(CapError::WrongKind),
+        }
+    }
+}
+
+pub struct Cap {
+    object: Object,
+    rights: usize,
+    // The cap this one was derived from and its epoch at the time.
+    parent: Option<(Arc<Cap>, usize)>,
+    epoch: AtomicUsize,
+}
+
+impl Cap {
+    /// A cap with no parent, which only the kernel makes.
+    pub fn new(object: Object, rights: usize) -> Arc<Cap> {
+        Arc::new(Cap {
+            object,
+            rights: rights & ALL_RIGHTS,
+            parent: None,
+            epoch: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn object(&self) -> &Object {
+        &self.object
+    }
+
+    pub fn rights(&self) -> usize {
+        self.rights
+    }
+
+    /// Whether the kernel made it, rather than it being derived.
+    pub fn is_original(&self) -> bool {
+        self.parent.is_none()
+    }
+
+    /// Whether nothing above it has been revoked.
+    pub fn is_valid(&self) -> bool {
+        let mut cap = self;
+        while let Some((parent, epoch)) = &cap.parent {
+            if parent.epoch.load(Ordering::Acquire) != *epoch {
+                return false;
+            }
+            cap = parent;
+        }
+        true
+    }
+
+    /// Fails unless the cap is valid and has all of `rights`.
+    pub fn check(&self, rights: usize) -> Result<(), CapError> {
+        if !self.is_valid() {
+            return Err(CapError::Revoked);
+        }
+        if self.rights & rights != rights {
+            return Err(CapError::NoRights);
+        }
+        Ok(())
+    }
+
+    /// A child with `rights`, which can't be more than this cap's, and for
+    /// memory, a `window` of (offset, size) in this one's.
+    pub fn derive(
+        self: &Arc<Cap>,
+        rights: usize,
+        window: Option<(usize, usize)>,
+    ) -> Result<Arc<Cap>, CapError> {
+        self.check(rights)?;
+        let object = match window {
+            Some((offset, size)) => self.object.window(offset, size)?,
+            None => self.object.clone(),
+        };
+        Ok(Arc::new(Cap {
+            object,
+            rights,
+            parent: Some((self.clone(), self.epoch.load(Ordering::Acquire))),
+            epoch: AtomicUsize::new(0),
+        }))
+    }
+
+    /// Invalidate every cap derived from this one. It stays good itself.
+    pub fn revoke(&self) {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+    }
+}
+
+// Tell an endpoint when a cap that can receive on it goes into a table or
+// comes out, see `Endpoint::holder_removed`.
+fn held(cap: &Cap, added: bool) {
+    if let Object::Endpoint(endpoint) = &cap.object {
+        if cap.rights & RECEIVE == 0 {
+            return;
+        }
+        if added {
+            endpoint.holder_added();
+        } else {
+            endpoint.holder_removed();
+        }
+    }
+}
+
+/// One program's caps by slot.
+pub struct CapTable {
+    slots: Vec<Option<Arc<Cap>>>,
+}
+
+impl Default for CapTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CapTable {
+    pub fn new() -> CapTable {
+        CapTable { slots: Vec::new() }
+    }
+
+    /// Put `cap` in the lowest free slot and return it.
+    pub fn insert(&mut self, cap: Arc<Cap>) -> Result<usize, CapError> {
+        let slot = match self.slots.iter().position(|slot| slot.is_none()) {
+            Some(slot) => slot,
+            None if self.slots.len() == MAX_CAPS => return Err(CapError::TableFull),
+            None => {
+                self.slots.push(None);
+                self.slots.len() - 1
+            }
+        };
+        held(&cap, true);
+        self.slots[slot] = Some(cap);
+        Ok(slot)
+    }
+
+    /// The cap in `slot`, if it's still good. A revoked one is dropped.
+    pub fn get(&mut self, slot: usize) -> Result<Arc<Cap>, CapError> {
+        let entry = self.slots.get_mut(slot).ok_or(CapError::InvalidSlot)?;
+        let cap = entry.as_ref().ok_or(CapError::InvalidSlot)?;
+        if !cap.is_valid() {
+            held(cap, false);
+            *entry = None;
+            return Err(CapError::Revoked);
+        }
+        Ok(cap.clone())
+    }
+
+    /// The cap in `slot` if it has all of `rights`.
+    pub fn lookup(&mut self, slot: usize, rights: usize) -> Result<Arc<Cap>, CapError> {
+        let cap = self.get(slot)?;
+        cap.check(rights)?;
+        Ok(cap)
+    }
+
+    /// The endpoint `slot` refers to, if the cap has `rights` over it.
+    pub fn endpoint(&mut self, slot: usize, rights: usize) -> Result<Arc<Endpoint>, CapError> {
+        match self.lookup(slot, rights)?.object() {
+            Object::Endpoint(endpoint) => Ok(endpoint.clone()),
+            _ => Err(CapError::WrongKind),
+        }
+    }
+
+    pub fn remove(&mut self, slot: usize) -> Result<Arc<Cap>, CapError> {
+        let cap = self
+            .slots
+            .get_mut(slot)
+            .and_then(|entry| entry.take())
+            .ok_or(CapError::InvalidSlot)?;
+        held(&cap, false);
+        Ok(cap)
+    }
+
+    /// Empty the table, handing back what was in it.
+    pub fn clear(&mut self) -> Vec<Arc<Cap>> {
+        let caps: Vec<Arc<Cap>> = self.slots.drain(..).flatten().collect();
+        for cap in &caps {
+            held(cap, false);
+        }
+        caps
+    }
+
+    /// The first good cap with `rights` that `matches`.
+    pub fn find<F: Fn(&Object) -> bool>(&self, rights: usize, matches: F) -> Option<Arc<Cap>> {
+        self.slots
+            .iter()
+            .flatten()
+            .find(|cap| cap.check(rights).is_ok() && matches(cap.object()))
+            .cloned()
+    }
+}
+
+impl Drop for CapTable {
+    fn drop(&mut self) {
+        for cap in self.slots.iter().flatten() {
+            held(cap, false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MMIO_BASE: usize = 0x4000_0000;
+
+    fn mmio(pages: usize) -> Arc<Cap> {
+        let object = Object::Mmio {
+            base: MMIO_BASE,
+            size: pages * PAGE_SIZE,
+        };
+        Cap::new(object, READ | WRITE | GRANT)
+    }
+
+    fn window(cap: &Cap) -> (usize, usize) {
+        match cap.object() {
+            Object::Mmio { base, size } => (*base, *size),
+            _ => panic!("not MMIO"),
+        }
+    }
+
+    #[test]
+    fn rights_only_narrow() {
+        let root = mmio(1);
+        let read = root.derive(READ | GRANT, None).unwrap();
+        assert_eq!(read.rights(), READ | GRANT);
+        assert_eq!(read.check(READ), Ok(()));
+        assert_eq!(read.check(WRITE), Err(CapError::NoRights));
+        // Not back up to what the parent had, nor to what nobody had.
+        assert_eq!(
+            read.derive(READ | WRITE, None).err(),
+            Some(CapError::NoRights)
+        );
+        assert_eq!(root.derive(SEND, None).err(), Some(CapError::NoRights));
+        assert!(read.derive(0, None).is_ok());
+        // The kernel can't hand out rights that don't exist.
+        assert_eq!(Cap::new(Object::Console, usize::MAX).rights(), ALL_RIGHTS);
+    }
+
+    #[test]
+    fn revoke_kills_descendants_not_the_cap() {
+        let root = mmio(1);
+        let child = root.derive(READ | GRANT, None).unwrap();
+        let grandchild = child.derive(READ, None).unwrap();
+        let sibling = root.derive(READ, None).unwrap();
+        child.revoke();
+        assert!(child.is_valid());
+        assert!(!grandchild.is_valid());
+        assert_eq!(grandchild.check(READ), Err(CapError::Revoked));
+        assert!(sibling.is_valid());
+        // New children after the revoke are good.
+        assert!(child.derive(READ, None).unwrap().is_valid());
+
+        root.revoke();
+        assert!(root.is_valid());
+        assert!(!child.is_valid());
+        assert!(!sibling.is_valid());
+        assert_eq!(child.derive(READ, None).err(), Some(CapError::Revoked));
+    }
+
+    #[test]
+    fn windows_stay_inside() {
+        let root = mmio(4);
+        let middle = root.derive(READ, Some((PAGE_SIZE, 2 * PAGE_SIZE))).unwrap();
+        assert_eq!(window(&middle), (MMIO_BASE + PAGE_SIZE, 2 * PAGE_SIZE));
+        // Offsets are into the parent's window, not the device.
+        let last = middle.derive(READ, Some((PAGE_SIZE, PAGE_SIZE))).unwrap();
+        assert_eq!(window(&last), (MMIO_BASE + 2 * PAGE_SIZE, PAGE_SIZE));
+        assert_eq!(
+            middle.derive(READ, Some((PAGE_SIZE, 2 * PAGE_SIZE))).err(),
+            Some(CapError::BadWindow)
+        );
+        assert_eq!(
+            root.derive(READ, Some((0, 0))).err(),
+            Some(CapError::BadWindow)
+        );
+        assert_eq!(
+            root.derive(READ, Some((1, PAGE_SIZE))).err(),
+            Some(CapError::BadWindow)
+        );
+        assert_eq!(
+            root.derive(READ, Some((0, 100))).err(),
+            Some(CapError::BadWindow)
+        );
+        assert_eq!(
+            root.derive(READ, Some((usize::MAX & !(PAGE_SIZE - 1), PAGE_SIZE)))
+                .err(),
+            Some(CapError::BadWindow)
+        );
+        let irq = Cap::new(Object::Irq(10), RECEIVE);
+        assert_eq!(
+            irq.derive(RECEIVE, Some((0, PAGE_SIZE))).err(),
+            Some(CapError::WrongKind)
+        );
+    }
+
+    #[test]
+    fn slots_are_reused() {
+        let mut table = CapTable::new();
+        let root = mmio(1);
+        assert_eq!(table.insert(root.clone()), Ok(0));
+        assert_eq!(table.insert(root.clone()), Ok(1));
+        assert_eq!(table.insert(root.clone()), Ok(2));
+        assert!(table.remove(1).is_ok());
+        assert_eq!(table.get(1).err(), Some(CapError::InvalidSlot));
+        assert_eq!(table.remove(1).err(), Some(CapError::InvalidSlot));
+        assert_eq!(table.insert(root.clone()), Ok(1));
+        assert_eq!(table.get(MAX_CAPS).err(), Some(CapError::InvalidSlot));
+    }
+
+    #[test]
+    fn table_fills_up() {
+        let mut table = CapTable::new();
+        let root = mmio(1);
+        for slot in 0..MAX_CAPS {
+            assert_eq!(table.insert(root.clone()), Ok(slot));
+        }
+        assert_eq!(table.insert(root.clone()), Err(CapError::TableFull));
+        assert_eq!(table.clear().len(), MAX_CAPS);
+        assert_eq!(table.insert(root), Ok(0));
+    }
+
+    #[test]
+    fn revoked_caps_leave_the_table() {
+        let mut table = CapTable::new();
+        let root = mmio(1);
+        let slot = table.insert(root.derive(READ, None).unwrap()).unwrap();
+        assert_eq!(table.lookup(slot, READ).map(|cap| cap.rights()), Ok(READ));
+        assert_eq!(table.lookup(slot, WRITE).err(), Some(CapError::NoRights));
+        root.revoke();
+        assert_eq!(table.get(slot).err(), Some(CapError::Revoked));
+        assert_eq!(table.get(slot).err(), Some(CapError::InvalidSlot));
+        assert_eq!(table.insert(root), Ok(slot));
+    }
+}
@@ -0,0 +1,344 @@
+//! ELF64 executable parser.
+//! https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.eheader.html
+//!
+//! Just enough to load a statically linked RISC-V program: the file header
+//! and the program headers. Like the device tree parser everything borrows
+//! from the image, and `Elf::new` checks every loadable segment up front so
+//! the loader can take them as they come.
+const ELF_MAGIC: [u8; 4] = [0x7F, b'E', b'L', b'F'];
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+// e_ident bytes.
+const EI_CLASS: usize = 4;
+const EI_DATA: usize = 5;
+const EI_VERSION: usize = 6;
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+
+const ET_EXEC: u16 = 2;
+const EM_RISCV: u16 = 243;
+
+pub const PT_LOAD: u32 = 1;
+
+// p_flags.
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    Truncated,
+    BadMagic,
+    // Not ELF64, not little endian or not version 1.
+    BadClass,
+    // Not an executable, e.g. an object file or a shared library.
+    NotExecutable,
+    WrongMachine,
+    BadProgramHeader,
+    // A loadable segment that runs off the end of the file, is bigger on
+    // disk than in memory, wraps around or is misaligned.
+    BadSegment,
+    // Nothing to load, or an entry point outside the executable segments.
+    BadEntry,
+}
+
+#[derive(Clone, Copy)]
+pub struct Elf<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    phoff: usize,
+    phnum: usize,
+    phentsize: usize,
+}
+
+/// One program header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+    pub align: u64,
+}
+
+fn le16(data: &[u8], off: usize) -> Result<u16, ElfError> {
+    let bytes = data.get(off..off + 2).ok_or(ElfError::Truncated)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn le32(data: &[u8], off: usize) -> Result<u32, ElfError> {
+    let bytes = data.get(off..off + 4).ok_or(ElfError::Truncated)?;
+    Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn le64(data: &[u8], off: usize) -> Result<u64, ElfError> {
+    Ok(le32(data, off)? as u64 | (le32(data, off + 4)? as u64) << 32)
+}
+
+impl Segment {
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+
+    pub fn is_readable(&self) -> bool {
+        self.flags & PF_R != 0
+    }
+
+    pub fn is_writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn is_executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+
+    /// Whether `addr` falls inside the segment in memory.
+    pub fn contains(&self, addr: u64) -> bool {
+        addr >= self.vaddr && addr - self.vaddr < self.memsz
+    }
+
+    fn check(&self, file_size: usize) -> Result<(), ElfError> {
+        let file_end = self.offset.checked_add(self.filesz);
+        let fits = file_end.is_some_and(|end| end <= file_size as u64);
+        let aligned = match self.align {
+            0 | 1 => true,
+            align if align.is_power_of_two() => (self.vaddr % align) == (self.offset % align),
+            _ => false,
+        };
+        if !fits
+            || self.filesz > self.memsz
+            || self.vaddr.checked_add(self.memsz).is_none()
+            || !aligned
+        {
+            return Err(ElfError::BadSegment);
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Elf<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Elf<'a>, ElfError> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err(ElfError::Truncated);
+        }
+        if data[..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[EI_CLASS] != ELFCLASS64
+            || data[EI_DATA] != ELFDATA2LSB
+            || data[EI_VERSION] != EV_CURRENT
+        {
+            return Err(ElfError::BadClass);
+        }
+        if le16(data, 16)? != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if le16(data, 18)? != EM_RISCV {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let elf = Elf {
+            data,
+            entry: le64(data, 24)?,
+            phoff: le64(data, 32)? as usize,
+            phentsize: le16(data, 54)? as usize,
+            phnum: le16(data, 56)? as usize,
+        };
+        let table_end = elf
+            .phnum
+            .checked_mul(elf.phentsize)
+            .and_then(|size| size.checked_add(elf.phoff));
+        if elf.phentsize < PROGRAM_HEADER_SIZE || table_end.is_none_or(|end| end > data.len()) {
+            return Err(ElfError::BadProgramHeader);
+        }
+
+        let mut entry_ok = false;
+        for segment in elf.loadable() {
+            segment.check(data.len())?;
+            entry_ok |= segment.is_executable() && segment.contains(elf.entry);
+        }
+        if !entry_ok {
+            return Err(ElfError::BadEntry);
+        }
+        Ok(elf)
+    }
+
+    /// The `index`th program header.
+    pub fn segment(&self, index: usize) -> Option<Segment> {
+        if index >= self.phnum {
+            return None;
+        }
+        let off = self.phoff + index * self.phentsize;
+        let data = self.data;
+        Some(Segment {
+            kind: le32(data, off).ok()?,
+            flags: le32(data, off + 4).ok()?,
+            offset: le64(data, off + 8).ok()?,
+            vaddr: le64(data, off + 16).ok()?,
+            filesz: le64(data, off + 32).ok()?,
+            memsz: le64(data, off + 40).ok()?,
+            align: le64(data, off + 48).ok()?,
+        })
+    }
+
+    pub fn segments(&self) -> impl Iterator<Item = Segment> + 'a {
+        let elf = *self;
+        (0..self.phnum).filter_map(move |index| elf.segment(index))
+    }
+
+    /// The PT_LOAD segments, what a loader has to map.
+    pub fn loadable(&self) -> impl Iterator<Item = Segment> + 'a {
+        self.segments().filter(|segment| segment.is_load())
+    }
+
+    /// The bytes of `segment` that come from the file. The rest of its
+    /// `memsz` is zero.
+    pub fn contents(&self, segment: &Segment) -> &'a [u8] {
+        let start = segment.offset as usize;
+        let end = start + segment.filesz as usize;
+        self.data.get(start..end).unwrap_or(&[])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Built from user/hello.S with user/user.lds.
+    static HELLO_ELF: &[u8] = include_bytes!("../../user/hello.elf");
+
+    fn hello() -> Elf<'static> {
+        Elf::new(HELLO_ELF).expect("hello.elf should parse")
+    }
+
+    // A copy of hello.elf with `bytes` written at `off`.
+    fn patched(off: usize, bytes: &[u8]) -> std::vec::Vec<u8> {
+        let mut data = HELLO_ELF.to_vec();
+        data[off..off + bytes.len()].copy_from_slice(bytes);
+        data
+    }
+
+    // Offset of field `field` in program header `index`.
+    fn ph(index: usize, field: usize) -> usize {
+        let elf = hello();
+        elf.phoff + index * elf.phentsize + field
+    }
+
+    #[test]
+    fn header() {
+        let elf = hello();
+        assert_eq!(elf.entry, 0x10_0000_0000);
+        assert_eq!(elf.segments().count(), 3);
+    }
+
+    #[test]
+    fn load_segments() {
+        let segments: std::vec::Vec<Segment> = hello().loadable().collect();
+        assert_eq!(segments.len(), 3);
+        let (text, rodata, data) = (segments[0], segments[1], segments[2]);
+
+        assert_eq!(text.vaddr, 0x10_0000_0000);
+        assert!(text.is_readable() && text.is_executable() && !text.is_writable());
+        assert!(rodata.is_readable() && !rodata.is_executable() && !rodata.is_writable());
+        assert!(data.is_readable() && data.is_writable() && !data.is_executable());
+        for segment in &segments {
+            assert_eq!(segment.vaddr % 0x1000, 0);
+            assert_eq!(segment.align, 0x1000);
+        }
+        // .bss follows .data in the same segment.
+        assert_eq!(data.filesz, 8);
+        assert_eq!(data.memsz, 16);
+    }
+
+    #[test]
+    fn contents() {
+        let elf = hello();
+        let rodata = elf.loadable().nth(1).unwrap();
+        let bytes = elf.contents(&rodata);
+        assert_eq!(bytes.len() as u64, rodata.filesz);
+        assert!(bytes.starts_with(b"[USER]: hello"));
+        // The first instruction, mv s0, a0 (addi s0, a0, 0).
+        let text = elf.loadable().next().unwrap();
+        assert_eq!(elf.contents(&text)[..4], 0x0005_0413u32.to_le_bytes());
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert_eq!(Elf::new(&[0; 16]).err(), Some(ElfError::Truncated));
+        assert_eq!(Elf::new(&[0; 64]).err(), Some(ElfError::BadMagic));
+        assert_eq!(
+            Elf::new(&HELLO_ELF[..0x100]).err(),
+            Some(ElfError::BadSegment)
+        );
+    }
+
+    #[test]
+    fn rejects_other_kinds_of_elf() {
+        // ELF32 and big endian.
+        assert_eq!(
+            Elf::new(&patched(EI_CLASS, &[1])).err(),
+            Some(ElfError::BadClass)
+        );
+        assert_eq!(
+            Elf::new(&patched(EI_DATA, &[2])).err(),
+            Some(ElfError::BadClass)
+        );
+        // A relocatable object and a shared library.
+        assert_eq!(
+            Elf::new(&patched(16, &1u16.to_le_bytes())).err(),
+            Some(ElfError::NotExecutable)
+        );
+        assert_eq!(
+            Elf::new(&patched(16, &3u16.to_le_bytes())).err(),
+            Some(ElfError::NotExecutable)
+        );
+        // x86-64.
+        assert_eq!(
+            Elf::new(&patched(18, &62u16.to_le_bytes())).err(),
+            Some(ElfError::WrongMachine)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_program_headers() {
+        // Table past the end of the file.
+        assert_eq!(
+            Elf::new(&patched(56, &0xFFFFu16.to_le_bytes())).err(),
+            Some(ElfError::BadProgramHeader)
+        );
+        // Entries too small to hold a program header.
+        assert_eq!(
+            Elf::new(&patched(54, &32u16.to_le_bytes())).err(),
+            Some(ElfError::BadProgramHeader)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_segments() {
+        // More in the file than in memory.
+        let data = patched(ph(2, 32), &0x20u64.to_le_bytes());
+        assert_eq!(Elf::new(&data).err(), Some(ElfError::BadSegment));
+        // Past the end of the file.
+        let data = patched(ph(1, 8), &0x10_0000u64.to_le_bytes());
+        assert_eq!(Elf::new(&data).err(), Some(ElfError::BadSegment));
+        // Wrapping around the address space.
+        let data = patched(ph(2, 40), &u64::MAX.to_le_bytes());
+        assert_eq!(Elf::new(&data).err(), Some(ElfError::BadSegment));
+        // vaddr and offset disagree modulo the alignment.
+        let data = patched(ph(1, 16), &0x10_0000_1008u64.to_le_bytes());
+        assert_eq!(Elf::new(&data).err(), Some(ElfError::BadSegment));
+    }
+
+    #[test]
+    fn rejects_bad_entry() {
+        // In the read-only data, not the code.
+        let data = patched(24, &0x10_0000_1000u64.to_le_bytes());
+        assert_eq!(Elf::new(&data).err(), Some(ElfError::BadEntry));
+        let data = patched(24, &0u64.to_le_bytes());
+        assert_eq!(Elf::new(&data).err(), Some(ElfError::BadEntry));
+    }
+}
@@ -0,0 +1,288 @@
+//! Starting user programs from ELF images.
+//!
+//! A program gets a fresh address space with its PT_LOAD segments mapped
+//! at the addresses it was linked for, with the permissions its program
+//! headers ask for, and a stack at the top of user space. The arguments go
+//! on the stack the System V way, argc and then the argv pointers, and in
+//! a0 and a1 too, and its first thread starts at the ELF entry point.
+//!
+//! Each program runs as a process of its own, see `process`, and starts
+//! with the capabilities it's handed and no others. The
+//! shell's exec gives out the framebuffer with +fb, console input with
+//! +input and all of a device to drive with +dev:<name>, see `driver`.
+//!
+//! There's no file system yet, so the programs are built into the kernel,
+//! see `PROGRAMS`.
+use crate::dev::driver::{self, DriverError};
+use crate::dev::vga;
+use crate::mm::paging::{MapError, EXECUTE, READ, WRITE};
+use crate::mm::space::{AddressSpace, USER_END};
+use crate::print;
+use crate::println;
+use crate::srv::console::{self, Command};
+use crate::util::alloc::PAGE_SIZE;
+use crate::util::cap::{self, Cap, CapError, CapTable, Object};
+use crate::util::elf::{Elf, ElfError, Segment};
+use crate::util::process::{Pid, Process, ProcessError};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+
+const STACK_PAGES: usize = 16;
+const STACK_SIZE: usize = STACK_PAGES * PAGE_SIZE;
+// Room for the argument strings and pointers at the top of the stack.
+const MAX_ARGS_SIZE: usize = PAGE_SIZE;
+
+/// Programs linked into the kernel, by name.
+static PROGRAMS: [(&str, &[u8]); 2] = [
+    ("hello", include_bytes!("../../user/hello.elf")),
+    ("peer", include_bytes!("../../user/peer.elf")),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExecError {
+    NoSuchProgram,
+    Elf(ElfError),
+    // A segment outside user space, overlapping another, or no memory.
+    Map(MapError),
+    ArgsTooLong,
+    // An exec option for a device that isn't there, or isn't one.
+    NoDevice,
+    // A capability that was asked for isn't there to give.
+    Cap(CapError),
+    // A device that's missing or someone else's to drive.
+    Device(DriverError),
+    Thread(ProcessError),
+}
+
+impl From<ElfError> for ExecError {
+    fn from(e: ElfError) -> Self {
+        ExecError::Elf(e)
+    }
+}
+
+impl From<MapError> for ExecError {
+    fn from(e: MapError) -> Self {
+        ExecError::Map(e)
+    }
+}
+
+impl From<CapError> for ExecError {
+    fn from(e: CapError) -> Self {
+        ExecError::Cap(e)
+    }
+}
+
+impl From<DriverError> for ExecError {
+    fn from(e: DriverError) -> Self {
+        ExecError::Device(e)
+    }
+}
+
+impl From<ProcessError> for ExecError {
+    fn from(e: ProcessError) -> Self {
+        ExecError::Thread(e)
+    }
+}
+
+pub fn init() {
+    let _ = console::register(Command {
+        name: "exec",
+        usage: "exec [+fb] [+input] [+dev:<name>] [program [args...]]",
+        help: "run a built-in user program with the devices given, or list them",
+        handler: exec_command,
+    });
+}
+
+/// The built-in program called `name`.
+pub fn find(name: &str) -> Option<(&'static str, &'static [u8])> {
+    PROGRAMS
+        .iter()
+        .copied()
+        .find(|(program, _)| *program == name)
+}
+
+// PTE permissions for a segment.
+fn flags(segment: &Segment) -> usize {
+    let mut flags = 0;
+    if segment.is_readable() {
+        flags |= READ;
+    }
+    if segment.is_writable() {
+        flags |= WRITE;
+    }
+    if segment.is_executable() {
+        flags |= EXECUTE;
+    }
+    flags
+}
+
+/// Map `elf`'s segments into `space` and copy their contents in. What the
+/// file doesn't cover, .bss, is left as the zeroes `map` hands out. Two
+/// segments can't share a page.
+pub fn load(space: &mut AddressSpace, elf: &Elf) -> Result<(), ExecError> {
+    for segment in elf.loadable().filter(|segment| segment.memsz > 0) {
+        let vaddr = segment.vaddr as usize;
+        let start = vaddr & !(PAGE_SIZE - 1);
+        let end = vaddr + segment.memsz as usize;
+        space.map(start, end - start, flags(&segment))?;
+        space.load(vaddr, elf.contents(&segment))?;
+    }
+    Ok(())
+}
+
+// Map the stack and lay `args` out at the top of it. Returns the initial sp
+// and argv.
+fn push_args(space: &mut AddressSpace, args: &[&str]) -> Result<(usize, usize), ExecError> {
+    space.map(USER_END - STACK_SIZE, STACK_SIZE, READ | WRITE)?;
+    // From the top down: the strings, then argc, argv[] and its null, and
+    // an empty envp, with sp 16 byte aligned.
+    let strings: usize = args.iter().map(|arg| arg.len() + 1).sum();
+    let words = 1 + args.len() + 1 + 1;
+    let strings_at = USER_END - strings;
+    let sp = (strings_at - words * 8) & !15;
+    if USER_END - sp > MAX_ARGS_SIZE {
+        return Err(ExecError::ArgsTooLong);
+    }
+
+    let mut block = vec![0u8; USER_END - sp];
+    let mut put_word = |index: usize, value: usize| {
+        block[index * 8..index * 8 + 8].copy_from_slice(&value.to_le_bytes());
+    };
+    put_word(0, args.len());
+    let mut at = strings_at;
+    for (i, arg) in args.iter().enumerate() {
+        put_word(1 + i, at);
+        at += arg.len() + 1;
+    }
+    let mut at = strings_at - sp;
+    for arg in args {
+        block[at..at + arg.len()].copy_from_slice(arg.as_bytes());
+        at += arg.len() + 1;
+    }
+    space.copy_out(sp, &block)?;
+    Ok((sp, sp + 8))
+}
+
+/// Start `image` as a new program in a space of its own, `args` being its
+/// argv and `caps` all it may use. `name` goes on its thread. The devices
+/// handed over to it in `devices` are reclaimed when it ends.
+pub fn exec(
+    name: &'static str,
+    image: &[u8],
+    args: &[&str],
+    caps: CapTable,
+    devices: &[&str],
+    parent: Option<Pid>,
+) -> Result<Arc<Process>, ExecError> {
+    let elf = Elf::new(image)?;
+    let mut space = AddressSpace::new()?;
+    load(&mut space, &elf)?;
+    let (sp, argv) = push_args(&mut space, args)?;
+    let process = Process::new(name, parent, space, caps);
+    for device in devices {
+        if let Err(e) = driver::assign(device, process.pid()) {
+            process.abandon();
+            return Err(e.into());
+        }
+    }
+    let entry = elf.entry as usize;
+    if let Err(e) = process.spawn_thread(name, entry, sp, [args.len(), argv]) {
+        // It's over before it started, with nobody to wait for it.
+        process.detach();
+        return Err(e.into());
+    }
+    Ok(process)
+}
+
+/// Start the built-in program `args[0]` with `args` as its argv.
+pub fn spawn(
+    args: &[&str],
+    caps: CapTable,
+    devices: &[&str],
+    parent: Option<Pid>,
+) -> Result<Arc<Process>, ExecError> {
+    let (name, image) = args
+        .first()
+        .and_then(|name| find(name))
+        .ok_or(ExecError::NoSuchProgram)?;
+    exec(name, image, args, caps, devices, parent)
+}
+
+// Caps for the device exec's `+option` names.
+fn device_caps(option: &str) -> Result<Vec<Arc<Cap>>, ExecError> {
+    let rights = cap::READ | cap::WRITE | cap::GRANT;
+    match option {
+        "+fb" => {
+            let fb = vga::framebuffer().ok_or(ExecError::NoDevice)?;
+            let object = Object::Mmio {
+                base: fb.base,
+                size: fb.size.next_multiple_of(PAGE_SIZE),
+            };
+            Ok(vec![Cap::new(object, rights)])
+        }
+        "+input" => Ok(vec![Cap::new(Object::Console, cap::READ | cap::GRANT)]),
+        _ => match option.strip_prefix("+dev:") {
+            Some(name) => Ok(driver::hand_over(name)?),
+            None => Err(ExecError::NoDevice),
+        },
+    }
+}
+
+// Put the caps for `option` in `caps`. A device handed over for them goes
+// on `handed`, even if they didn't all fit.
+fn grant<'a>(
+    caps: &mut CapTable,
+    option: &'a str,
+    handed: &mut Vec<&'a str>,
+) -> Result<(), ExecError> {
+    let device_caps = device_caps(option)?;
+    handed.extend(option.strip_prefix("+dev:"));
+    for cap in device_caps {
+        caps.insert(cap)?;
+    }
+    Ok(())
+}
+
+fn exec_command(args: &[&str]) {
+    if args.is_empty() {
+        print!("programs:");
+        for (name, image) in PROGRAMS.iter() {
+            print!(" {} ({} bytes)", name, image.len());
+        }
+        println!();
+        return;
+    }
+    let options = args.iter().take_while(|arg| arg.starts_with('+')).count();
+    let (options, args) = args.split_at(options);
+    if args.is_empty() {
+        println!("usage: exec [+fb] [+input] [+dev:<name>] [program [args...]]");
+        return;
+    }
+    let mut caps = CapTable::new();
+    let mut handed = Vec::new();
+    let mut result = Ok(());
+    for option in options {
+        result = grant(&mut caps, option, &mut handed);
+        if let Err(e) = result {
+            println!("exec: {}: {:?}", option, e);
+            break;
+        }
+    }
+    let result = result.and_then(|()| spawn(args, caps, &handed, None));
+    match result {
+        Ok(process) => {
+            // Leave it running, the shell stays usable.
+            process.detach();
+            println!("[EXEC]: {} is process {}", args[0], process.pid().0);
+        }
+        Err(e) => {
+            println!("exec: {}: {:?}", args[0], e);
+            // Devices handed over for it go back to being free, if they
+            // didn't when it ended. Any that weren't are someone else's.
+            for name in handed {
+                let _ = driver::reclaim(name);
+            }
+        }
+    }
+}
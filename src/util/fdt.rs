@@ -0,0 +1,849 @@
+//! Flattened device tree (DTB) parser.
+//! https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+//!
+//! Everything here borrows straight out of the blob, nothing is copied or
+//! allocated, so it can run before the page allocator is up.
+use core::str;
+
+const FDT_MAGIC: u32 = 0xD00D_FEED;
+const FDT_HEADER_SIZE: usize = 40;
+const FDT_MIN_VERSION: u32 = 16;
+const FDT_MAX_VERSION: u32 = 17;
+
+const FDT_BEGIN_NODE: u32 = 0x1;
+const FDT_END_NODE: u32 = 0x2;
+const FDT_PROP: u32 = 0x3;
+const FDT_NOP: u32 = 0x4;
+const FDT_END: u32 = 0x9;
+
+// Defaults from the spec when a node doesn't say.
+const DEFAULT_ADDRESS_CELLS: u32 = 2;
+const DEFAULT_SIZE_CELLS: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdtError {
+    BadMagic,
+    BadVersion,
+    Truncated,
+    BadToken,
+    BadString,
+}
+
+#[derive(Clone, Copy)]
+pub struct Fdt<'a> {
+    data: &'a [u8],
+    struct_off: usize,
+    struct_size: usize,
+    strings_off: usize,
+    strings_size: usize,
+    rsvmap_off: usize,
+    pub boot_cpuid: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct Node<'a> {
+    fdt: Fdt<'a>,
+    pub name: &'a str,
+    // Offset (inside the structure block) of the first token after the name.
+    body: usize,
+    // #address-cells / #size-cells of the parent, used to decode our `reg`.
+    parent_address_cells: u32,
+    parent_size_cells: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct Property<'a> {
+    pub name: &'a str,
+    pub value: &'a [u8],
+}
+
+/// One `(address, size)` pair from a `reg` property.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    pub address: u64,
+    pub size: u64,
+}
+
+/// One `ranges` entry. `child_flags` is only meaningful for buses whose
+/// child addresses are more than two cells wide (PCI's phys.hi).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Range {
+    pub child_flags: u32,
+    pub child_address: u64,
+    pub parent_address: u64,
+    pub size: u64,
+}
+
+/// A `/memreserve/` entry from the memory reservation block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reservation {
+    pub address: u64,
+    pub size: u64,
+}
+
+enum Token<'a> {
+    BeginNode(&'a str),
+    EndNode,
+    Prop(Property<'a>),
+    Nop,
+    End,
+}
+
+fn be32(data: &[u8], off: usize) -> Result<u32, FdtError> {
+    let bytes = data.get(off..off + 4).ok_or(FdtError::Truncated)?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+fn be64(data: &[u8], off: usize) -> Result<u64, FdtError> {
+    Ok(((be32(data, off)? as u64) << 32) | be32(data, off + 4)? as u64)
+}
+
+fn align4(off: usize) -> usize {
+    (off + 3) & !3
+}
+
+// Read a null terminated string starting at the beginning of `data`.
+fn cstr(data: &[u8]) -> Result<&str, FdtError> {
+    let len = data.iter().position(|&b| b == 0).ok_or(FdtError::BadString)?;
+    str::from_utf8(&data[..len]).map_err(|_| FdtError::BadString)
+}
+
+// Fold `cells` big endian u32s into one number. Anything wider than two cells
+// keeps the low 64 bits, which is what every address we care about fits in.
+fn read_cells(data: &[u8], off: usize, cells: u32) -> Result<u64, FdtError> {
+    let mut value: u64 = 0;
+    for i in 0..cells as usize {
+        value = (value << 32) | be32(data, off + i * 4)? as u64;
+    }
+    Ok(value)
+}
+
+impl<'a> Fdt<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Fdt<'a>, FdtError> {
+        if data.len() < FDT_HEADER_SIZE {
+            return Err(FdtError::Truncated);
+        }
+        if be32(data, 0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = be32(data, 4)? as usize;
+        if total_size > data.len() {
+            return Err(FdtError::Truncated);
+        }
+        // Version 16 is the oldest with the header layout we read, and 17 is
+        // the newest we understand.
+        if be32(data, 20)? < FDT_MIN_VERSION || be32(data, 24)? > FDT_MAX_VERSION {
+            return Err(FdtError::BadVersion);
+        }
+
+        let fdt = Fdt {
+            data: &data[..total_size],
+            struct_off: be32(data, 8)? as usize,
+            strings_off: be32(data, 12)? as usize,
+            rsvmap_off: be32(data, 16)? as usize,
+            boot_cpuid: be32(data, 28)?,
+            strings_size: be32(data, 32)? as usize,
+            struct_size: be32(data, 36)? as usize,
+        };
+        if fdt.struct_off + fdt.struct_size > total_size
+            || fdt.strings_off + fdt.strings_size > total_size
+        {
+            return Err(FdtError::Truncated);
+        }
+        Ok(fdt)
+    }
+
+    /// Parse the blob at a raw physical address, like the one handed to us in
+    /// `a1` at boot. The length comes from the header's `totalsize`.
+    pub unsafe fn from_addr(addr: usize) -> Result<Fdt<'static>, FdtError> {
+        if addr == 0 {
+            return Err(FdtError::BadMagic);
+        }
+        let header = core::slice::from_raw_parts(addr as *const u8, FDT_HEADER_SIZE);
+        if be32(header, 0)? != FDT_MAGIC {
+            return Err(FdtError::BadMagic);
+        }
+        let total_size = be32(header, 4)? as usize;
+        Fdt::new(core::slice::from_raw_parts(addr as *const u8, total_size))
+    }
+
+    /// Physical address of the blob itself, so the allocator can keep out of it.
+    pub fn addr(&self) -> usize {
+        self.data.as_ptr() as usize
+    }
+
+    pub fn total_size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn root(&self) -> Result<Node<'a>, FdtError> {
+        let mut off = 0;
+        loop {
+            let (token, next) = self.token(off)?;
+            match token {
+                Token::Nop => off = next,
+                Token::BeginNode(name) => {
+                    return Ok(Node {
+                        fdt: *self,
+                        name,
+                        body: next,
+                        parent_address_cells: DEFAULT_ADDRESS_CELLS,
+                        parent_size_cells: DEFAULT_SIZE_CELLS,
+                    })
+                }
+                _ => return Err(FdtError::BadToken),
+            }
+        }
+    }
+
+    /// Look a node up by its full path, e.g. `/soc/uart@10000000`. A path
+    /// component without a unit address matches the first node of that name.
+    pub fn find_node(&self, path: &str) -> Option<Node<'a>> {
+        let mut node = self.root().ok()?;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            node = node.children().find(|child| {
+                child.name == component
+                    || (!component.contains('@') && child.base_name() == component)
+            })?;
+        }
+        Some(node)
+    }
+
+    /// First node (depth first) that lists `compatible` in its compatible
+    /// property.
+    pub fn find_compatible(&self, compatible: &str) -> Option<Node<'a>> {
+        let mut found = None;
+        self.walk(|node, _| {
+            if found.is_none() && node.is_compatible(compatible) {
+                found = Some(*node);
+            }
+        });
+        found
+    }
+
+    /// Node whose `phandle` property equals `phandle`.
+    pub fn find_phandle(&self, phandle: u32) -> Option<Node<'a>> {
+        let mut found = None;
+        self.walk(|node, _| {
+            if found.is_none() && node.phandle() == Some(phandle) {
+                found = Some(*node);
+            }
+        });
+        found
+    }
+
+    /// Resolve `/chosen/stdout-path` (minus any `:options` suffix).
+    pub fn stdout(&self) -> Option<Node<'a>> {
+        let path = self.find_node("/chosen")?.property("stdout-path")?.as_str()?;
+        let path = path.split(':').next()?;
+        self.find_node(path)
+    }
+
+    /// Every node in the tree, depth first, along with how deep it is. The
+    /// root is depth 0.
+    pub fn walk<F: FnMut(&Node<'a>, usize)>(&self, mut f: F) {
+        if let Ok(root) = self.root() {
+            Self::walk_node(&root, 0, &mut f);
+        }
+    }
+
+    fn walk_node<F: FnMut(&Node<'a>, usize)>(node: &Node<'a>, depth: usize, f: &mut F) {
+        f(node, depth);
+        for child in node.children() {
+            Self::walk_node(&child, depth + 1, f);
+        }
+    }
+
+    pub fn reservations(&self) -> ReservationIter<'a> {
+        ReservationIter {
+            data: self.data,
+            off: self.rsvmap_off,
+        }
+    }
+
+    fn string(&self, name_off: usize) -> Result<&'a str, FdtError> {
+        if name_off >= self.strings_size {
+            return Err(FdtError::BadString);
+        }
+        let start = self.strings_off + name_off;
+        cstr(&self.data[start..self.strings_off + self.strings_size])
+    }
+
+    // Decode the token at `off` (relative to the structure block) and return
+    // it along with the offset of the one after it.
+    fn token(&self, off: usize) -> Result<(Token<'a>, usize), FdtError> {
+        if off + 4 > self.struct_size {
+            return Err(FdtError::Truncated);
+        }
+        let base = self.struct_off;
+        let structure = &self.data[base..base + self.struct_size];
+        let token = be32(structure, off)?;
+        let off = off + 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let name = cstr(&structure[off..])?;
+                Ok((Token::BeginNode(name), align4(off + name.len() + 1)))
+            }
+            FDT_END_NODE => Ok((Token::EndNode, off)),
+            FDT_PROP => {
+                let len = be32(structure, off)? as usize;
+                let name_off = be32(structure, off + 4)? as usize;
+                let start = off + 8;
+                let value = structure
+                    .get(start..start + len)
+                    .ok_or(FdtError::Truncated)?;
+                let name = self.string(name_off)?;
+                Ok((Token::Prop(Property { name, value }), align4(start + len)))
+            }
+            FDT_NOP => Ok((Token::Nop, off)),
+            FDT_END => Ok((Token::End, off)),
+            _ => Err(FdtError::BadToken),
+        }
+    }
+
+    // Given the offset just past a BEGIN_NODE, return the offset just past
+    // its matching END_NODE.
+    fn skip_node(&self, mut off: usize) -> Result<usize, FdtError> {
+        let mut depth = 1;
+        while depth > 0 {
+            let (token, next) = self.token(off)?;
+            match token {
+                Token::BeginNode(_) => depth += 1,
+                Token::EndNode => depth -= 1,
+                Token::End => return Err(FdtError::BadToken),
+                _ => {}
+            }
+            off = next;
+        }
+        Ok(off)
+    }
+}
+
+impl<'a> Node<'a> {
+    /// Node name without the `@unit-address` part.
+    pub fn base_name(&self) -> &'a str {
+        self.name.split('@').next().unwrap_or(self.name)
+    }
+
+    pub fn unit_address(&self) -> Option<&'a str> {
+        self.name.split_once('@').map(|(_, addr)| addr)
+    }
+
+    pub fn properties(&self) -> PropertyIter<'a> {
+        PropertyIter {
+            fdt: self.fdt,
+            off: self.body,
+        }
+    }
+
+    pub fn property(&self, name: &str) -> Option<Property<'a>> {
+        self.properties().find(|prop| prop.name == name)
+    }
+
+    pub fn children(&self) -> ChildIter<'a> {
+        ChildIter {
+            fdt: self.fdt,
+            off: self.body,
+            address_cells: self.address_cells(),
+            size_cells: self.size_cells(),
+        }
+    }
+
+    /// Cells used by this node's children for their addresses.
+    pub fn address_cells(&self) -> u32 {
+        self.property("#address-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(DEFAULT_ADDRESS_CELLS)
+    }
+
+    /// Cells used by this node's children for their sizes.
+    pub fn size_cells(&self) -> u32 {
+        self.property("#size-cells")
+            .and_then(|p| p.as_u32())
+            .unwrap_or(DEFAULT_SIZE_CELLS)
+    }
+
+    pub fn phandle(&self) -> Option<u32> {
+        self.property("phandle")
+            .or_else(|| self.property("linux,phandle"))
+            .and_then(|p| p.as_u32())
+    }
+
+    pub fn compatible(&self) -> StringListIter<'a> {
+        StringListIter {
+            data: self.property("compatible").map(|p| p.value).unwrap_or(&[]),
+        }
+    }
+
+    pub fn is_compatible(&self, compatible: &str) -> bool {
+        self.compatible().any(|c| c == compatible)
+    }
+
+    pub fn device_type(&self) -> Option<&'a str> {
+        self.property("device_type")?.as_str()
+    }
+
+    /// `status` defaults to okay when absent.
+    pub fn is_enabled(&self) -> bool {
+        match self.property("status").and_then(|p| p.as_str()) {
+            Some(status) => status == "okay" || status == "ok",
+            None => true,
+        }
+    }
+
+    /// Decode `reg` using the parent's `#address-cells` / `#size-cells`.
+    pub fn reg(&self) -> RegIter<'a> {
+        RegIter {
+            data: self.property("reg").map(|p| p.value).unwrap_or(&[]),
+            off: 0,
+            address_cells: self.parent_address_cells,
+            size_cells: self.parent_size_cells,
+        }
+    }
+
+    /// Decode `ranges`. Child addresses use our own `#address-cells`, parent
+    /// addresses use the parent's. An empty `ranges` means an identity
+    /// mapping and yields nothing.
+    pub fn ranges(&self) -> RangeIter<'a> {
+        RangeIter {
+            data: self.property("ranges").map(|p| p.value).unwrap_or(&[]),
+            off: 0,
+            child_address_cells: self.address_cells(),
+            parent_address_cells: self.parent_address_cells,
+            size_cells: self.size_cells(),
+        }
+    }
+
+    /// Raw cells of the `interrupts` property. Every interrupt parent on
+    /// virt uses `#interrupt-cells = <1>`, so each cell is one IRQ number.
+    pub fn interrupts(&self) -> CellIter<'a> {
+        CellIter {
+            data: self.property("interrupts").map(|p| p.value).unwrap_or(&[]),
+            off: 0,
+        }
+    }
+
+    pub fn interrupt_parent(&self) -> Option<u32> {
+        self.property("interrupt-parent")?.as_u32()
+    }
+
+    /// `interrupts-extended` as `(controller phandle, first specifier cell)`
+    /// pairs. The specifier width comes from the controller's
+    /// `#interrupt-cells`.
+    pub fn interrupts_extended(&self) -> InterruptsExtendedIter<'a> {
+        InterruptsExtendedIter {
+            fdt: self.fdt,
+            data: self
+                .property("interrupts-extended")
+                .map(|p| p.value)
+                .unwrap_or(&[]),
+            off: 0,
+        }
+    }
+}
+
+impl<'a> Property<'a> {
+    pub fn as_u32(&self) -> Option<u32> {
+        if self.value.len() != 4 {
+            return None;
+        }
+        be32(self.value, 0).ok()
+    }
+
+    /// A one or two cell number.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self.value.len() {
+            4 => be32(self.value, 0).ok().map(|v| v as u64),
+            8 => be64(self.value, 0).ok(),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&'a str> {
+        cstr(self.value).ok()
+    }
+
+    pub fn as_str_list(&self) -> StringListIter<'a> {
+        StringListIter { data: self.value }
+    }
+
+    pub fn cells(&self) -> CellIter<'a> {
+        CellIter {
+            data: self.value,
+            off: 0,
+        }
+    }
+}
+
+pub struct ChildIter<'a> {
+    fdt: Fdt<'a>,
+    off: usize,
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> Iterator for ChildIter<'a> {
+    type Item = Node<'a>;
+
+    fn next(&mut self) -> Option<Node<'a>> {
+        loop {
+            let (token, next) = self.fdt.token(self.off).ok()?;
+            match token {
+                Token::Prop(_) | Token::Nop => self.off = next,
+                Token::BeginNode(name) => {
+                    // Park the cursor past the whole subtree for next time.
+                    self.off = self.fdt.skip_node(next).ok()?;
+                    return Some(Node {
+                        fdt: self.fdt,
+                        name,
+                        body: next,
+                        parent_address_cells: self.address_cells,
+                        parent_size_cells: self.size_cells,
+                    });
+                }
+                Token::EndNode | Token::End => return None,
+            }
+        }
+    }
+}
+
+pub struct PropertyIter<'a> {
+    fdt: Fdt<'a>,
+    off: usize,
+}
+
+impl<'a> Iterator for PropertyIter<'a> {
+    type Item = Property<'a>;
+
+    fn next(&mut self) -> Option<Property<'a>> {
+        loop {
+            let (token, next) = self.fdt.token(self.off).ok()?;
+            match token {
+                Token::Nop => self.off = next,
+                Token::Prop(prop) => {
+                    self.off = next;
+                    return Some(prop);
+                }
+                // Properties always come before child nodes.
+                _ => return None,
+            }
+        }
+    }
+}
+
+pub struct StringListIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for StringListIter<'a> {
+    type Item = &'a str;
+
+    fn next(&mut self) -> Option<&'a str> {
+        if self.data.is_empty() {
+            return None;
+        }
+        let s = cstr(self.data).ok()?;
+        self.data = &self.data[(s.len() + 1).min(self.data.len())..];
+        Some(s)
+    }
+}
+
+pub struct CellIter<'a> {
+    data: &'a [u8],
+    off: usize,
+}
+
+impl<'a> Iterator for CellIter<'a> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<u32> {
+        let cell = be32(self.data, self.off).ok()?;
+        self.off += 4;
+        Some(cell)
+    }
+}
+
+pub struct RegIter<'a> {
+    data: &'a [u8],
+    off: usize,
+    address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> Iterator for RegIter<'a> {
+    type Item = Region;
+
+    fn next(&mut self) -> Option<Region> {
+        // No cells, nothing to step over: stop rather than repeat forever.
+        if self.address_cells + self.size_cells == 0 {
+            return None;
+        }
+        let address = read_cells(self.data, self.off, self.address_cells).ok()?;
+        let size_off = self.off + self.address_cells as usize * 4;
+        let size = read_cells(self.data, size_off, self.size_cells).ok()?;
+        self.off = size_off + self.size_cells as usize * 4;
+        Some(Region { address, size })
+    }
+}
+
+pub struct RangeIter<'a> {
+    data: &'a [u8],
+    off: usize,
+    child_address_cells: u32,
+    parent_address_cells: u32,
+    size_cells: u32,
+}
+
+impl<'a> Iterator for RangeIter<'a> {
+    type Item = Range;
+
+    fn next(&mut self) -> Option<Range> {
+        let child_cells = self.child_address_cells;
+        if child_cells + self.parent_address_cells + self.size_cells == 0 {
+            return None;
+        }
+        // The first of three child cells is a flags word (PCI phys.hi).
+        let (child_flags, child_address) = if child_cells > 2 {
+            (
+                be32(self.data, self.off).ok()?,
+                read_cells(self.data, self.off + 4, child_cells - 1).ok()?,
+            )
+        } else {
+            (0, read_cells(self.data, self.off, child_cells).ok()?)
+        };
+        let parent_off = self.off + child_cells as usize * 4;
+        let parent_address = read_cells(self.data, parent_off, self.parent_address_cells).ok()?;
+        let size_off = parent_off + self.parent_address_cells as usize * 4;
+        let size = read_cells(self.data, size_off, self.size_cells).ok()?;
+        self.off = size_off + self.size_cells as usize * 4;
+        Some(Range {
+            child_flags,
+            child_address,
+            parent_address,
+            size,
+        })
+    }
+}
+
+pub struct InterruptsExtendedIter<'a> {
+    fdt: Fdt<'a>,
+    data: &'a [u8],
+    off: usize,
+}
+
+impl<'a> Iterator for InterruptsExtendedIter<'a> {
+    type Item = (u32, u32);
+
+    fn next(&mut self) -> Option<(u32, u32)> {
+        let phandle = be32(self.data, self.off).ok()?;
+        let cells = self
+            .fdt
+            .find_phandle(phandle)
+            .and_then(|ctrl| ctrl.property("#interrupt-cells"))
+            .and_then(|p| p.as_u32())
+            .unwrap_or(1);
+        let specifier = be32(self.data, self.off + 4).ok()?;
+        self.off += 4 + cells as usize * 4;
+        Some((phandle, specifier))
+    }
+}
+
+pub struct ReservationIter<'a> {
+    data: &'a [u8],
+    off: usize,
+}
+
+impl<'a> Iterator for ReservationIter<'a> {
+    type Item = Reservation;
+
+    fn next(&mut self) -> Option<Reservation> {
+        let address = be64(self.data, self.off).ok()?;
+        let size = be64(self.data, self.off + 8).ok()?;
+        // The block ends with an all zero entry.
+        if address == 0 && size == 0 {
+            return None;
+        }
+        self.off += 16;
+        Some(Reservation { address, size })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static VIRT_DTB: &[u8] = include_bytes!("../../virt.dtb");
+
+    fn virt() -> Fdt<'static> {
+        Fdt::new(VIRT_DTB).expect("virt.dtb should parse")
+    }
+
+    #[test]
+    fn header() {
+        let fdt = virt();
+        assert_eq!(fdt.total_size(), 0x12CE);
+        assert_eq!(fdt.boot_cpuid, 0);
+        assert_eq!(fdt.reservations().count(), 0);
+    }
+
+    #[test]
+    fn rejects_garbage() {
+        assert_eq!(Fdt::new(&[0; 8]).err(), Some(FdtError::Truncated));
+        assert_eq!(Fdt::new(&[0; 64]).err(), Some(FdtError::BadMagic));
+        let mut short = [0u8; 64];
+        short[..8].copy_from_slice(&VIRT_DTB[..8]);
+        assert_eq!(Fdt::new(&short).err(), Some(FdtError::Truncated));
+    }
+
+    #[test]
+    fn root_properties() {
+        let root = virt().root().unwrap();
+        assert_eq!(root.name, "");
+        assert_eq!(root.address_cells(), 2);
+        assert_eq!(root.size_cells(), 2);
+        assert!(root.is_compatible("riscv-virtio"));
+        assert_eq!(
+            root.property("model").and_then(|p| p.as_str()),
+            Some("riscv-virtio,qemu")
+        );
+    }
+
+    #[test]
+    fn memory_reg() {
+        let memory = virt().find_node("/memory@80000000").unwrap();
+        assert_eq!(memory.device_type(), Some("memory"));
+        let mut reg = memory.reg();
+        assert_eq!(
+            reg.next(),
+            Some(Region {
+                address: 0x8000_0000,
+                size: 0x800_0000
+            })
+        );
+        assert_eq!(reg.next(), None);
+    }
+
+    #[test]
+    fn find_by_path_without_unit_address() {
+        let fdt = virt();
+        assert_eq!(fdt.find_node("/memory").unwrap().name, "memory@80000000");
+        assert_eq!(fdt.find_node("/soc/uart").unwrap().name, "uart@10000000");
+        assert!(fdt.find_node("/soc/nothing").is_none());
+    }
+
+    #[test]
+    fn uart_from_stdout_path() {
+        let uart = virt().stdout().unwrap();
+        assert!(uart.is_compatible("ns16550a"));
+        assert_eq!(uart.reg().next().unwrap().address, 0x1000_0000);
+        assert_eq!(uart.interrupts().collect::<std::vec::Vec<_>>(), [0x0A]);
+        let plic = virt().find_phandle(uart.interrupt_parent().unwrap()).unwrap();
+        assert_eq!(plic.name, "plic@c000000");
+    }
+
+    #[test]
+    fn compatible_lists() {
+        let fdt = virt();
+        let clint = fdt.find_compatible("riscv,clint0").unwrap();
+        assert_eq!(clint.name, "clint@2000000");
+        assert_eq!(
+            clint.compatible().collect::<std::vec::Vec<_>>(),
+            ["sifive,clint0", "riscv,clint0"]
+        );
+        assert_eq!(
+            clint.reg().next(),
+            Some(Region {
+                address: 0x200_0000,
+                size: 0x1_0000
+            })
+        );
+        // cpu@0's interrupt controller is phandle 8: software then timer.
+        let irqs = clint.interrupts_extended().collect::<std::vec::Vec<_>>();
+        assert_eq!(irqs.len(), 8);
+        assert_eq!(irqs[0], (0x08, 3));
+        assert_eq!(irqs[1], (0x08, 7));
+    }
+
+    #[test]
+    fn pci_ecam_and_ranges() {
+        let pci = virt().find_compatible("pci-host-ecam-generic").unwrap();
+        assert_eq!(
+            pci.reg().next(),
+            Some(Region {
+                address: 0x3000_0000,
+                size: 0x1000_0000
+            })
+        );
+        let ranges = pci.ranges().collect::<std::vec::Vec<_>>();
+        assert_eq!(ranges.len(), 3);
+        // I/O space
+        assert_eq!(ranges[0].child_flags >> 24 & 0x3, 1);
+        assert_eq!(ranges[0].parent_address, 0x300_0000);
+        assert_eq!(ranges[0].size, 0x1_0000);
+        // 32-bit memory space, which is where the VGA BARs go.
+        assert_eq!(ranges[1].child_flags >> 24 & 0x3, 2);
+        assert_eq!(ranges[1].child_address, 0x4000_0000);
+        assert_eq!(ranges[1].parent_address, 0x4000_0000);
+        assert_eq!(ranges[1].size, 0x4000_0000);
+        // 64-bit memory space
+        assert_eq!(ranges[2].child_flags >> 24 & 0x3, 3);
+        assert_eq!(ranges[2].parent_address, 0x4_0000_0000);
+    }
+
+    #[test]
+    fn zero_cells_end_reg_and_ranges() {
+        let data = [0u8; 8];
+        let mut reg = RegIter {
+            data: &data,
+            off: 0,
+            address_cells: 0,
+            size_cells: 0,
+        };
+        assert_eq!(reg.next(), None);
+        let mut ranges = RangeIter {
+            data: &data,
+            off: 0,
+            child_address_cells: 0,
+            parent_address_cells: 0,
+            size_cells: 0,
+        };
+        assert!(ranges.next().is_none());
+    }
+
+    #[test]
+    fn empty_ranges_is_identity() {
+        let soc = virt().find_node("/soc").unwrap();
+        assert!(soc.property("ranges").is_some());
+        assert_eq!(soc.ranges().count(), 0);
+    }
+
+    #[test]
+    fn cpus() {
+        let fdt = virt();
+        let cpus = fdt.find_node("/cpus").unwrap();
+        assert_eq!(
+            cpus.property("timebase-frequency").and_then(|p| p.as_u64()),
+            Some(10_000_000)
+        );
+        let harts = cpus
+            .children()
+            .filter(|n| n.device_type() == Some("cpu"))
+            .map(|n| n.reg().next().unwrap().address)
+            .collect::<std::vec::Vec<_>>();
+        assert_eq!(harts, [0, 1, 2, 3]);
+        assert!(cpus.children().all(|n| n.is_enabled()));
+    }
+
+    #[test]
+    fn walk_visits_every_node() {
+        let mut count = 0;
+        let mut deepest = 0;
+        virt().walk(|_, depth| {
+            count += 1;
+            deepest = deepest.max(depth);
+        });
+        // root, 6 top level nodes, 4 cpus + their intcs, cpu-map (6), soc's 16
+        assert_eq!(count, 1 + 6 + 8 + 6 + 16);
+        assert_eq!(deepest, 4);
+    }
+}
@@ -0,0 +1,209 @@
+//! Kernel heap for the `alloc` crate, built on the page allocator.
+//!
+//! Small requests come out of power of two size classes from 16 to 2048
+//! bytes. Each class keeps a free list threaded through its free blocks and
+//! takes a fresh page from `Alloc` when it runs dry; freed blocks go back on
+//! the list rather than back to `Alloc`. Anything bigger, or more strictly
+//! aligned, gets whole pages of its own.
+//!
+//! Everything runs under `HEAP_LOCK` with interrupts off, so any hart and
+//! any interrupt handler can allocate.
+use crate::print;
+use crate::println;
+use crate::util::alloc::{Alloc, PAGE_SIZE};
+use crate::util::lock::RawSpinLock;
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+
+const MIN_CLASS_SHIFT: usize = 4;
+const MAX_CLASS_SHIFT: usize = 11;
+const NUM_CLASSES: usize = MAX_CLASS_SHIFT - MIN_CLASS_SHIFT + 1;
+const MAX_CLASS_SIZE: usize = 1 << MAX_CLASS_SHIFT;
+
+// A free block. Lives in the block itself.
+struct FreeBlock {
+    next: *mut FreeBlock,
+}
+
+// Sits just below a large allocation to find the pages again.
+struct LargeHeader {
+    pages: *mut u8,
+}
+
+#[derive(Clone, Copy)]
+pub struct HeapStats {
+    /// Bytes handed out and not yet freed, as requested.
+    pub in_use: usize,
+    /// Pages taken from the page allocator for size classes.
+    pub class_pages: usize,
+    /// Pages currently held by large allocations.
+    pub large_pages: usize,
+    pub allocs: usize,
+    pub frees: usize,
+    pub failures: usize,
+}
+
+// Guards FREE_LISTS and STATS.
+static HEAP_LOCK: RawSpinLock = RawSpinLock::new();
+static mut FREE_LISTS: [*mut FreeBlock; NUM_CLASSES] = [null_mut(); NUM_CLASSES];
+static mut STATS: HeapStats = HeapStats {
+    in_use: 0,
+    class_pages: 0,
+    large_pages: 0,
+    allocs: 0,
+    frees: 0,
+    failures: 0,
+};
+
+pub struct KernelHeap;
+
+#[cfg(not(test))]
+#[global_allocator]
+static HEAP: KernelHeap = KernelHeap;
+
+pub fn stats() -> HeapStats {
+    HEAP_LOCK.with(|| unsafe { STATS })
+}
+
+// Size class index for a layout, or None if it needs whole pages.
+fn class_of(layout: &Layout) -> Option<usize> {
+    let size = layout.size().max(layout.align()).max(1 << MIN_CLASS_SHIFT);
+    if size > MAX_CLASS_SIZE {
+        return None;
+    }
+    let shift = size.next_power_of_two().trailing_zeros() as usize;
+    Some(shift - MIN_CLASS_SHIFT)
+}
+
+fn align_up(addr: usize, align: usize) -> usize {
+    (addr + align - 1) & !(align - 1)
+}
+
+// Carve a new page into blocks for `class`. Blocks are aligned to their own
+// size, which covers any alignment that picked this class.
+unsafe fn refill(class: usize) -> bool {
+    let size = 1 << (class + MIN_CLASS_SHIFT);
+    let page = match Alloc::get(1) {
+        Some(page) => page as usize,
+        None => return false,
+    };
+    STATS.class_pages += 1;
+    let end = page + PAGE_SIZE;
+    let mut block = align_up(page, size);
+    while block + size <= end {
+        let free = block as *mut FreeBlock;
+        (*free).next = FREE_LISTS[class];
+        FREE_LISTS[class] = free;
+        block += size;
+    }
+    true
+}
+
+unsafe fn alloc_small(class: usize) -> *mut u8 {
+    if FREE_LISTS[class].is_null() && !refill(class) {
+        return null_mut();
+    }
+    let block = FREE_LISTS[class];
+    FREE_LISTS[class] = (*block).next;
+    block as *mut u8
+}
+
+unsafe fn dealloc_small(ptr: *mut u8, class: usize) {
+    let block = ptr as *mut FreeBlock;
+    (*block).next = FREE_LISTS[class];
+    FREE_LISTS[class] = block;
+}
+
+// Pages big enough for the header, the worst case alignment padding and
+// the object.
+fn large_pages(layout: &Layout) -> usize {
+    let align = layout.align().max(core::mem::align_of::<LargeHeader>());
+    let bytes = core::mem::size_of::<LargeHeader>() + align + layout.size();
+    bytes.div_ceil(PAGE_SIZE)
+}
+
+unsafe fn alloc_large(layout: &Layout) -> *mut u8 {
+    let pages = large_pages(layout);
+    let base = match Alloc::get(pages) {
+        Some(base) => base,
+        None => return null_mut(),
+    };
+    let align = layout.align().max(core::mem::align_of::<LargeHeader>());
+    let ptr = align_up(base as usize + core::mem::size_of::<LargeHeader>(), align);
+    let header = (ptr as *mut LargeHeader).sub(1);
+    (*header).pages = base;
+    STATS.large_pages += pages;
+    ptr as *mut u8
+}
+
+unsafe fn dealloc_large(ptr: *mut u8, layout: &Layout) {
+    let header = (ptr as *mut LargeHeader).sub(1);
+    let _ = Alloc::free((*header).pages);
+    STATS.large_pages -= large_pages(layout);
+}
+
+// The default alloc error handler only panics with the layout, so say what
+// the heap looked like first.
+fn out_of_memory(layout: &Layout) {
+    let stats = unsafe { STATS };
+    let pages = Alloc::stats();
+    println!(
+        "[HEAP]: out of memory allocating {} bytes (align {}): {} bytes in use, {} class pages, {} large pages, {}/{} pages taken",
+        layout.size(),
+        layout.align(),
+        stats.in_use,
+        stats.class_pages,
+        stats.large_pages,
+        pages.used + pages.reserved,
+        pages.total
+    );
+}
+
+unsafe impl GlobalAlloc for KernelHeap {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        HEAP_LOCK.with(|| {
+            let ptr = match class_of(&layout) {
+                Some(class) => alloc_small(class),
+                None => alloc_large(&layout),
+            };
+            if ptr.is_null() {
+                STATS.failures += 1;
+                out_of_memory(&layout);
+            } else {
+                STATS.allocs += 1;
+                STATS.in_use += layout.size();
+            }
+            ptr
+        })
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        HEAP_LOCK.with(|| {
+            match class_of(&layout) {
+                Some(class) => dealloc_small(ptr, class),
+                None => dealloc_large(ptr, &layout),
+            }
+            STATS.frees += 1;
+            STATS.in_use -= layout.size();
+        })
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // Same size class, nothing to move.
+        let new_layout = Layout::from_size_align_unchecked(new_size, layout.align());
+        if let (Some(old), Some(new)) = (class_of(&layout), class_of(&new_layout)) {
+            if old == new {
+                HEAP_LOCK.with(|| {
+                    STATS.in_use = STATS.in_use - layout.size() + new_size;
+                });
+                return ptr;
+            }
+        }
+        let new_ptr = self.alloc(new_layout);
+        if !new_ptr.is_null() {
+            core::ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
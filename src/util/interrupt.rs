@@ -2,35 +2,16 @@ use crate::print;
 use crate::println;
 use core::arch::asm;
 
-#[allow(dead_code)]
-fn placeholder_handler() {}
-
-#[repr(C)]
-struct HardwareInterruptTable {
-    int0: u32,
-    int1: fn(),
-    int2: fn(),
-    int3: fn(),
-    int4: fn(),
-    int5: fn(),
-    int6: fn(),
-    int7: fn(), // handler0: fn(),
-                // ssip: fn(),
-                // handler2: fn(),
-                // msip: fn(),
-                // handler4: fn(),
-                // stip: fn(),
-                // handler6: fn(),
-                // mtip: fn(),
-                // handler8: fn(),
-                // seip: fn(),
-                // handler10: fn(),
-                // meip: fn(),
-                // handler12: fn(),
-                // lcofip: fn()
-}
-
-enum MachineInterruptRegister {
+use crate::dev::{clint, plic};
+use crate::smp::{self, ipi};
+use crate::util::trap::TrapFrame;
+
+extern "C" {
+    fn asm_trap_vector();
+}
+
+#[derive(Clone, Copy)]
+enum InterruptRegister {
     SSIP = 1,
     MSIP = 3,
     STIP = 5,
@@ -40,36 +21,26 @@ enum MachineInterruptRegister {
     LCOFIP = 13,
 }
 
-static RISCV_MACHINE_INTERRUPT_HANDLERS: HardwareInterruptTable = HardwareInterruptTable {
-    // handler0: placeholder_handler,
-    // ssip: placeholder_handler,
-    // handler2: placeholder_handler,
-    // msip: placeholder_handler,
-    // handler4: placeholder_handler,
-    // stip: placeholder_handler,
-    // handler6: placeholder_handler,
-    // mtip: timer_handler,
-    // handler8: placeholder_handler,
-    // seip: placeholder_handler,
-    // handler10: placeholder_handler,
-    // meip: placeholder_handler,
-    // handler12: placeholder_handler,
-    // lcofip: placeholder_handler,
-    int0: 0,
-    int1: timer_handler,
-    int2: timer_handler,
-    int3: timer_handler,
-    int4: timer_handler,
-    int5: timer_handler,
-    int6: timer_handler,
-    int7: timer_handler,
-};
-
-fn enable_interrupt(register: MachineInterruptRegister) {
+impl InterruptRegister {
+    fn from_code(code: usize) -> Option<Self> {
+        match code {
+            1 => Some(Self::SSIP),
+            3 => Some(Self::MSIP),
+            5 => Some(Self::STIP),
+            7 => Some(Self::MTIP),
+            9 => Some(Self::SEIP),
+            11 => Some(Self::MEIP),
+            13 => Some(Self::LCOFIP),
+            _ => None,
+        }
+    }
+}
+
+fn enable_interrupt(register: InterruptRegister) {
     let read_value: usize;
     unsafe {
         asm!(
-            "csrr {0}, mie",
+            "csrr {0}, sie",
             out(reg) read_value
         );
     }
@@ -78,9 +49,7 @@ fn enable_interrupt(register: MachineInterruptRegister) {
     let shifted_value: usize = 1 << (register as u8);
     unsafe {
         asm!(
-            "csrr t1, mie",
-            "or t1, t1, {0}",
-            "csrw mie, t1",
+            "csrs sie, {0}",
             in(reg) shifted_value
         );
     }
@@ -88,43 +57,135 @@ fn enable_interrupt(register: MachineInterruptRegister) {
     let read_value: usize;
     unsafe {
         asm!(
-            "csrr {0}, mie",
+            "csrr {0}, sie",
             out(reg) read_value
         );
     }
     println!("AFTER: {}", read_value);
 }
 
-fn software_handler() {}
+/// Called from `trap_handler` with the interrupt number (scause without the
+/// interrupt bit).
+pub fn handle(code: usize, frame: &mut TrapFrame) {
+    match InterruptRegister::from_code(code) {
+        Some(InterruptRegister::SSIP) => software_handler(),
+        Some(InterruptRegister::STIP) => timer_handler(),
+        Some(InterruptRegister::SEIP) => external_handler(),
+        _ => println!("[TRAP]: unhandled interrupt {} at {:#X}", code, frame.sepc),
+    }
+}
+
+// An IPI. S-mode owns sip.SSIP, so we clear it ourselves, before looking
+// at what was sent so a later IPI isn't lost.
+fn software_handler() {
+    unsafe {
+        asm!("csrc sip, {0}", in(reg) 1 << InterruptRegister::SSIP as usize);
+    }
+    ipi::handle_interrupt();
+}
 
 fn timer_handler() {
-    println!("TIMER HERE TIMER HERE");
+    clint::handle_interrupt();
+}
+
+fn external_handler() {
+    plic::handle_interrupt();
+}
+
+/// This hart's id. mhartid is M-mode only, so it comes from the per hart
+/// data `smp::init_hart` set up.
+pub fn hart_id() -> usize {
+    smp::this_hart().id()
+}
+
+// sstatus.SIE
+const SSTATUS_SIE: usize = 1 << 1;
+
+/// Turn on interrupts for this hart (sstatus.SIE).
+#[cfg(not(test))]
+pub fn enable_global() {
+    unsafe {
+        asm!("csrs sstatus, {0}", in(reg) SSTATUS_SIE);
+    }
+}
+
+/// Turn interrupts off, returning whether they were on.
+#[cfg(not(test))]
+pub fn disable_global() -> bool {
+    let previous: usize;
+    unsafe {
+        asm!("csrrc {0}, sstatus, {1}", out(reg) previous, in(reg) SSTATUS_SIE);
+    }
+    previous & SSTATUS_SIE != 0
+}
+
+// Host tests are an ordinary process, with no CSRs to touch. These, the
+// hart, mtime, satp and the assembly (`host` in lib.rs) are all they need
+// stand-ins for, so the locks and the Drop impls that take them build and
+// run the same there.
+#[cfg(test)]
+pub fn enable_global() {}
+
+#[cfg(test)]
+pub fn disable_global() -> bool {
+    false
+}
+
+/// Sleep until an interrupt is pending.
+pub fn wait_for_interrupt() {
+    unsafe {
+        asm!("wfi", options(nomem, nostack, preserves_flags));
+    }
+}
+
+/// Run `f` with interrupts masked on this hart, putting them back the way
+/// they were afterwards.
+pub fn without_interrupts<R, F: FnOnce() -> R>(f: F) -> R {
+    let was_enabled = disable_global();
+    let result = f();
+    if was_enabled {
+        enable_global();
+    }
+    result
 }
 
 fn get_vec_base() -> u64 {
     let vec_base_addr: u64;
     unsafe {
-        asm!("csrr {0}, mtvec", out(reg) vec_base_addr);
+        asm!("csrr {0}, stvec", out(reg) vec_base_addr);
     }
 
     return vec_base_addr;
 }
 
+// Direct mode: every trap goes through the one entry point, which saves
+// registers and dispatches on scause.
 fn write_vec_base(addr: usize) {
-    let addr_vectored = addr | 1;
     unsafe {
         asm!(
-            "csrw mtvec, {0}",
-            in(reg) addr_vectored
+            "csrw stvec, {0}",
+            in(reg) addr
         );
     }
 }
 
 pub fn init() {
-    //initialize timer
-    write_vec_base(&RISCV_MACHINE_INTERRUPT_HANDLERS as *const HardwareInterruptTable as usize);
+    write_vec_base(asm_trap_vector as *const () as usize);
 
     let read_value = get_vec_base();
-    println!("MTEC VALUE: {:X}", read_value);
-    enable_interrupt(MachineInterruptRegister::MTIP);
+    println!("STVEC VALUE: {:X}", read_value);
+    enable_interrupt(InterruptRegister::SSIP);
+    enable_interrupt(InterruptRegister::STIP);
+    enable_interrupt(InterruptRegister::SEIP);
+}
+
+/// `init` for a secondary hart, without the chatter.
+pub fn init_hart() {
+    write_vec_base(asm_trap_vector as *const () as usize);
+    let bits = (1 << InterruptRegister::SSIP as usize)
+        | (1 << InterruptRegister::STIP as usize)
+        | (1 << InterruptRegister::SEIP as usize);
+    unsafe {
+        asm!("csrs sie, {0}", in(reg) bits);
+    }
 }
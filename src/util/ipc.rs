@@ -0,0 +1,566 @@
+//! Synchronous IPC through endpoints, L4 style.
+//!
+//! An endpoint is a rendezvous point with no buffering of its own: `send`
+//! blocks until a receiver has taken the message, and `call` carries on
+//! blocking until that receiver answers with `Reply::reply`. Queued senders
+//! are served first come first served, and any number of threads can wait
+//! in `receive` on the same endpoint.
+//!
+//! A message is a label and `MSG_WORDS` words, which from user space travel
+//! in registers. Anything bigger goes by page grant: with `MSG_GRANT` set in
+//! the label, the last two words are the address and length of whole pages
+//! in the sender's space. They're taken out of it when the message is sent
+//! and show up mapped somewhere free in the receiver's space, with the same
+//! permissions, where the last two words then point. A grant the receiver
+//! has no room for fails the send and goes back where it came from.
+//!
+//! With `MSG_CAP` set, word 1 is a slot in the sender's capability table,
+//! and the receiver finds the slot of its own copy there instead.
+//!
+//! An endpoint also takes notifications, which don't block: `notify` ORs
+//! bits into a word the next receive picks up, ahead of any messages, as a
+//! message labelled `MSG_NOTIFY` with the bits in word 0. Interrupt
+//! handlers use them to wake user-space drivers.
+//!
+//! Kernel threads hold endpoints directly. User threads reach them through
+//! capabilities: sending needs the SEND right and receiving RECEIVE. Once
+//! the last cap with RECEIVE has left the cap tables, e.g. because the
+//! server exited, nobody can receive on the endpoint again, so the senders
+//! waiting on it fail with `NoReply` and later ones fail straight away.
+use crate::dev::clint;
+use crate::dev::driver;
+use crate::mm::paging::MapError;
+use crate::mm::space::{AddressSpace, Frames};
+use crate::print;
+use crate::println;
+use crate::srv::console::{self, Command};
+use crate::util::cap::{Cap, CapTable, GRANT};
+use crate::util::lock::{Condvar, IrqSpinLock, Mutex};
+use crate::util::thread::{Thread, ThreadId, WaitQueue, DEFAULT_PRIORITY};
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::sync::{Arc, Weak};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+pub const MSG_WORDS: usize = 4;
+/// Label bit: the message grants the pages in its last two words.
+pub const MSG_GRANT: usize = 1 << 63;
+/// Label bit: the message passes on the capability in word 1.
+pub const MSG_CAP: usize = 1 << 62;
+/// Label of a notification, word 0 the bits.
+pub const MSG_NOTIFY: usize = 1 << 61;
+
+// How many round trips "ipc bench" makes by default.
+const BENCH_CALLS: usize = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpcError {
+    // The grant isn't whole pages, all mapped, in the sender's space, or
+    // the sender has no space at all.
+    BadGrant,
+    // The cap to pass on isn't there or hasn't the GRANT right.
+    BadCap,
+    // The receiver had no room for the granted pages or the cap.
+    OutOfMemory,
+    // The receiver went away, or received again, without replying, or
+    // nobody can receive on the endpoint any more.
+    NoReply,
+    // The thread was interrupted while it waited, see `Thread::interrupt`.
+    // A message already queued may still be received.
+    Interrupted,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Message {
+    pub label: usize,
+    pub words: [usize; MSG_WORDS],
+}
+
+impl Message {
+    pub fn new(label: usize, words: [usize; MSG_WORDS]) -> Message {
+        Message { label, words }
+    }
+
+    /// The address and length of the pages the message grants, if any.
+    pub fn grant(&self) -> Option<(usize, usize)> {
+        (self.label & MSG_GRANT != 0).then_some((self.words[2], self.words[3]))
+    }
+
+    /// The slot of the capability the message passes on, if any.
+    pub fn cap(&self) -> Option<usize> {
+        (self.label & MSG_CAP != 0).then_some(self.words[1])
+    }
+}
+
+type Space = Arc<Mutex<AddressSpace>>;
+type Caps = Arc<Mutex<CapTable>>;
+
+// What a message carries besides its words: pages already out of the
+// sender's space, and a cap.
+#[derive(Default)]
+struct Items {
+    frames: Option<Frames>,
+    cap: Option<Arc<Cap>>,
+}
+
+// How far a message has got, for the thread that sent it.
+enum Delivery {
+    Waiting,
+    Received,
+    Replied(Message, Items),
+    // Any granted pages come back to go where they were.
+    Failed(IpcError, Option<Frames>),
+}
+
+// Where a blocked sender waits to hear what became of its message.
+struct Slot {
+    delivery: Mutex<Delivery>,
+    changed: Condvar,
+}
+
+impl Slot {
+    fn new() -> Arc<Slot> {
+        Arc::new(Slot {
+            delivery: Mutex::new(Delivery::Waiting),
+            changed: Condvar::new(),
+        })
+    }
+
+    fn set(&self, delivery: Delivery) {
+        *self.delivery.lock() = delivery;
+        self.changed.notify_all();
+    }
+
+    fn wait(&self) -> Delivery {
+        let mut delivery = self.delivery.lock();
+        while matches!(*delivery, Delivery::Waiting) {
+            match self.changed.wait_interruptible(delivery) {
+                Some(guard) => delivery = guard,
+                None => return Delivery::Failed(IpcError::Interrupted, None),
+            }
+        }
+        core::mem::replace(&mut *delivery, Delivery::Waiting)
+    }
+}
+
+// A message and its sender, waiting on an endpoint for a receiver.
+struct Pending {
+    message: Message,
+    items: Items,
+    slot: Arc<Slot>,
+    // Sent with `call`, so the sender waits for a reply too.
+    call: bool,
+}
+
+pub struct Endpoint {
+    id: usize,
+    queue: Mutex<VecDeque<Pending>>,
+    // How long `queue` is and the notification bits, for receivers to wait
+    // on without taking the Mutex, which interrupt handlers can't.
+    queued: AtomicUsize,
+    notifications: AtomicUsize,
+    arrived: WaitQueue,
+    // Threads blocked in receive, for "ipc".
+    receivers: AtomicUsize,
+    messages: AtomicUsize,
+    // Caps with RECEIVE in cap tables, and whether they've all gone.
+    holders: AtomicUsize,
+    closed: AtomicBool,
+}
+
+/// The right to answer one `call`. Dropping it unanswered fails the call
+/// with `NoReply`.
+pub struct Reply {
+    // Gone once it's been answered.
+    slot: Option<Arc<Slot>>,
+}
+
+// Every endpoint, for "ipc". It goes with the last reference to it, which
+// can be dropped by the scheduler, hence the Irq lock.
+static ENDPOINTS: IrqSpinLock<BTreeMap<usize, Weak<Endpoint>>> = IrqSpinLock::new(BTreeMap::new());
+static NEXT_ENDPOINT: AtomicUsize = AtomicUsize::new(1);
+// The reply each user thread owes, from its last receive.
+static REPLIES: Mutex<BTreeMap<ThreadId, Reply>> = Mutex::new(BTreeMap::new());
+
+pub fn init() {
+    let _ = console::register(Command {
+        name: "ipc",
+        usage: "ipc [bench [calls]]",
+        help: "list endpoints, or time call/reply round trips",
+        handler: ipc_command,
+    });
+}
+
+// Pick up what `message` carries from the current thread: the pages it
+// grants, out of `space`, and the cap it passes on, from `caps`.
+fn take_items(
+    message: &Message,
+    space: Option<&Space>,
+    caps: Option<&Caps>,
+) -> Result<Items, IpcError> {
+    let mut items = Items::default();
+    if let Some(slot) = message.cap() {
+        let caps = caps.ok_or(IpcError::BadCap)?;
+        let cap = caps.lock().lookup(slot, GRANT);
+        items.cap = Some(cap.map_err(|_| IpcError::BadCap)?);
+    }
+    if let Some((va, len)) = message.grant() {
+        let space = space.ok_or(IpcError::BadGrant)?;
+        let frames = space.lock().take(va, len).map_err(|_| IpcError::BadGrant)?;
+        items.frames = Some(frames);
+    }
+    Ok(items)
+}
+
+// Hand `items` to the current thread: a copy of the cap into `caps`, the
+// pages mapped somewhere free in `space`, and `message` pointed at them. On
+// failure the pages are left in `items`.
+fn accept_items(
+    message: &mut Message,
+    items: &mut Items,
+    space: Option<&Space>,
+    caps: Option<&Caps>,
+) -> Result<(), IpcError> {
+    let mut received = None;
+    if let Some(cap) = items.cap.take() {
+        let caps = caps.ok_or(IpcError::OutOfMemory)?;
+        let copy = cap
+            .derive(cap.rights(), None)
+            .map_err(|_| IpcError::BadCap)?;
+        let slot = caps
+            .lock()
+            .insert(copy)
+            .map_err(|_| IpcError::OutOfMemory)?;
+        message.words[1] = slot;
+        received = Some((caps, slot));
+    }
+    if let Some(frames) = items.frames.as_mut() {
+        if let Err(e) = accept_frames(message, frames, space) {
+            if let Some((caps, slot)) = received {
+                let _ = caps.lock().remove(slot);
+            }
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+fn accept_frames(
+    message: &mut Message,
+    frames: &mut Frames,
+    space: Option<&Space>,
+) -> Result<(), IpcError> {
+    let space = space.ok_or(IpcError::OutOfMemory)?;
+    let regions = frames.regions();
+    let device_pages = frames.device_pages();
+    let len = frames.len();
+    let va = {
+        let mut space = space.lock();
+        let va = space.find_free(len).ok_or(IpcError::OutOfMemory)?;
+        space.give(va, frames).map_err(|e| match e {
+            MapError::OutOfMemory => IpcError::OutOfMemory,
+            _ => IpcError::BadGrant,
+        })?;
+        va
+    };
+    // Shared memory and device windows that moved with them can now be
+    // found here. Memory destroyed in the meantime goes again.
+    for region in regions {
+        if !region.mapped_in(space) {
+            space.lock().unmap_region(&region);
+        }
+    }
+    for pa in device_pages {
+        driver::mapped_in(pa, space);
+    }
+    message.words[2] = va;
+    message.words[3] = len;
+    Ok(())
+}
+
+// Put pages from a failed grant back at `va` in `space`. If something has
+// been mapped there since, they're lost and `frames` frees them.
+fn restore_grant(message: &Message, frames: Option<Frames>, space: Option<&Space>) {
+    if let (Some(mut frames), Some((va, _)), Some(space)) = (frames, message.grant(), space) {
+        let _ = space.lock().give(va, &mut frames);
+    }
+}
+
+impl Endpoint {
+    /// A new endpoint.
+    pub fn create() -> Arc<Endpoint> {
+        let endpoint = Arc::new(Endpoint {
+            id: NEXT_ENDPOINT.fetch_add(1, Ordering::Relaxed),
+            queue: Mutex::new(VecDeque::new()),
+            queued: AtomicUsize::new(0),
+            notifications: AtomicUsize::new(0),
+            arrived: WaitQueue::new(),
+            receivers: AtomicUsize::new(0),
+            messages: AtomicUsize::new(0),
+            holders: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+        });
+        ENDPOINTS
+            .lock()
+            .insert(endpoint.id, Arc::downgrade(&endpoint));
+        endpoint
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    // Queue `message` from the current thread and wait until a receiver
+    // has it, and for a call until the reply.
+    fn deliver(&self, message: Message, call: bool) -> Result<Delivery, IpcError> {
+        let space = Thread::space();
+        let items = take_items(&message, space.as_ref(), Thread::caps().as_ref())?;
+        let slot = Slot::new();
+        {
+            let mut queue = self.queue.lock();
+            if self.closed.load(Ordering::Acquire) {
+                drop(queue);
+                restore_grant(&message, items.frames, space.as_ref());
+                return Err(IpcError::NoReply);
+            }
+            queue.push_back(Pending {
+                message,
+                items,
+                slot: slot.clone(),
+                call,
+            });
+        }
+        self.queued.fetch_add(1, Ordering::Release);
+        self.arrived.notify_one();
+        match slot.wait() {
+            Delivery::Failed(e, frames) => {
+                restore_grant(&message, frames, space.as_ref());
+                Err(e)
+            }
+            delivery => Ok(delivery),
+        }
+    }
+
+    /// A cap with RECEIVE on the endpoint has gone into a cap table.
+    pub fn holder_added(&self) {
+        self.holders.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// One has come out. After the last, fail everyone waiting to send.
+    pub fn holder_removed(&self) {
+        if self.holders.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+        let queue = {
+            let mut queue = self.queue.lock();
+            self.closed.store(true, Ordering::Release);
+            core::mem::take(&mut *queue)
+        };
+        self.queued.fetch_sub(queue.len(), Ordering::Release);
+        for pending in queue {
+            let frames = pending.items.frames;
+            pending
+                .slot
+                .set(Delivery::Failed(IpcError::NoReply, frames));
+        }
+    }
+
+    /// OR `bits` into the notification word and wake a receiver. Doesn't
+    /// block, so it's fine from an interrupt handler.
+    pub fn notify(&self, bits: usize) {
+        if bits != 0 {
+            self.notifications.fetch_or(bits, Ordering::AcqRel);
+            self.arrived.notify_one();
+        }
+    }
+
+    /// Send `message` and wait for a receiver to take it.
+    pub fn send(&self, message: Message) -> Result<(), IpcError> {
+        self.deliver(message, false).map(|_| ())
+    }
+
+    /// Send `message` and wait for the receiver's reply.
+    pub fn call(&self, message: Message) -> Result<Message, IpcError> {
+        match self.deliver(message, true)? {
+            Delivery::Replied(mut reply, mut items) => {
+                let (space, caps) = (Thread::space(), Thread::caps());
+                accept_items(&mut reply, &mut items, space.as_ref(), caps.as_ref())?;
+                Ok(reply)
+            }
+            _ => Err(IpcError::NoReply),
+        }
+    }
+
+    /// Wait for a message. It comes with a `Reply` if it was sent with
+    /// `call`.
+    pub fn receive(&self) -> Result<(Message, Option<Reply>), IpcError> {
+        let (space, caps) = (Thread::space(), Thread::caps());
+        loop {
+            self.receivers.fetch_add(1, Ordering::Relaxed);
+            let arrived = self.arrived.wait_interruptible(|| {
+                self.notifications.load(Ordering::Acquire) != 0
+                    || self.queued.load(Ordering::Acquire) != 0
+            });
+            self.receivers.fetch_sub(1, Ordering::Relaxed);
+            if !arrived {
+                return Err(IpcError::Interrupted);
+            }
+            let bits = self.notifications.swap(0, Ordering::AcqRel);
+            if bits != 0 {
+                return Ok((Message::new(MSG_NOTIFY, [bits, 0, 0, 0]), None));
+            }
+            // Another receiver may have got there first.
+            let Some(mut pending) = self.queue.lock().pop_front() else {
+                continue;
+            };
+            self.queued.fetch_sub(1, Ordering::Release);
+
+            let message = &mut pending.message;
+            if let Err(e) = accept_items(message, &mut pending.items, space.as_ref(), caps.as_ref())
+            {
+                pending.slot.set(Delivery::Failed(e, pending.items.frames));
+                continue;
+            }
+            self.messages.fetch_add(1, Ordering::Relaxed);
+            if pending.call {
+                let reply = Reply {
+                    slot: Some(pending.slot),
+                };
+                return Ok((pending.message, Some(reply)));
+            }
+            pending.slot.set(Delivery::Received);
+            return Ok((pending.message, None));
+        }
+    }
+}
+
+impl Drop for Endpoint {
+    fn drop(&mut self) {
+        ENDPOINTS.lock().remove(&self.id);
+    }
+}
+
+impl Reply {
+    /// Answer the call, waking the caller.
+    pub fn reply(mut self, message: Message) -> Result<(), IpcError> {
+        let items = take_items(&message, Thread::space().as_ref(), Thread::caps().as_ref())?;
+        if let Some(slot) = self.slot.take() {
+            slot.set(Delivery::Replied(message, items));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Reply {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot.take() {
+            slot.set(Delivery::Failed(IpcError::NoReply, None));
+        }
+    }
+}
+
+/// Hold on to `reply` for the current thread to answer with `reply`. One it
+/// was already holding is dropped.
+pub fn keep_reply(reply: Option<Reply>) {
+    let old = match reply {
+        Some(reply) => REPLIES.lock().insert(Thread::current(), reply),
+        None => REPLIES.lock().remove(&Thread::current()),
+    };
+    // Failing the old call takes the slot's Mutex, not under REPLIES.
+    drop(old);
+}
+
+/// Answer the call the current thread last received.
+pub fn reply(message: Message) -> Result<(), IpcError> {
+    let reply = REPLIES.lock().remove(&Thread::current());
+    reply.ok_or(IpcError::NoReply)?.reply(message)
+}
+
+/// Let go of any reply the current thread owes, before it exits, so its
+/// caller isn't left waiting.
+pub fn thread_exit() {
+    keep_reply(None);
+}
+
+fn ipc_command(args: &[&str]) {
+    match args {
+        [] => {
+            println!(
+                "{:>4} {:>7} {:>9} {:>9}",
+                "ID", "QUEUED", "RECEIVERS", "MESSAGES"
+            );
+            let endpoints: alloc::vec::Vec<Arc<Endpoint>> = ENDPOINTS
+                .lock()
+                .values()
+                .filter_map(Weak::upgrade)
+                .collect();
+            for endpoint in endpoints {
+                println!(
+                    "{:>4} {:>7} {:>9} {:>9}",
+                    endpoint.id,
+                    endpoint.queue.lock().len(),
+                    endpoint.receivers.load(Ordering::Relaxed),
+                    endpoint.messages.load(Ordering::Relaxed)
+                );
+            }
+        }
+        ["bench", rest @ ..] => {
+            let calls = match rest.first().map(|n| n.parse::<usize>()) {
+                None => BENCH_CALLS,
+                Some(Ok(n)) if n > 0 => n,
+                _ => {
+                    println!("usage: ipc bench [calls]");
+                    return;
+                }
+            };
+            bench(calls);
+        }
+        _ => println!("usage: ipc [bench [calls]]"),
+    }
+}
+
+// Time `calls` round trips to a kernel thread that adds one to the first
+// word and replies.
+fn bench(calls: usize) {
+    let endpoint = Endpoint::create();
+    let server = endpoint.clone();
+    let spawned = Thread::spawn("ipc-bench", DEFAULT_PRIORITY, move || loop {
+        let Ok((message, reply)) = server.receive() else {
+            break;
+        };
+        if message.label == 0 {
+            break;
+        }
+        if let Some(reply) = reply {
+            let words = [message.words[0] + 1, 0, 0, 0];
+            let _ = reply.reply(Message::new(message.label, words));
+        }
+    });
+    let id = match spawned {
+        Ok(id) => id,
+        Err(e) => {
+            println!("ipc: no thread: {:?}", e);
+            return;
+        }
+    };
+
+    let start = clint::uptime_micros();
+    let mut value = 0;
+    for _ in 0..calls {
+        match endpoint.call(Message::new(1, [value, 0, 0, 0])) {
+            Ok(reply) => value = reply.words[0],
+            Err(e) => {
+                println!("ipc: call failed: {:?}", e);
+                break;
+            }
+        }
+    }
+    let elapsed = clint::uptime_micros() - start;
+    let _ = endpoint.send(Message::new(0, [0; MSG_WORDS]));
+    let _ = Thread::join(id);
+    println!(
+        "[IPC]: {} round trips in {} us, {} ns each",
+        value,
+        elapsed,
+        elapsed * 1000 / calls.max(1) as u64
+    );
+}
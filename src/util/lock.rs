@@ -0,0 +1,336 @@
+//! Locks for data shared between harts and threads.
+//!
+//! `SpinLock` and `IrqSpinLock` busy-wait and are fine anywhere, interrupt
+//! handlers included as long as every user of the lock masks interrupts,
+//! which is what the `Irq` variant is for. `Mutex` and `Condvar` put the
+//! thread to sleep on a `WaitQueue` instead, so they need the scheduler and
+//! must not be used from interrupt context.
+//!
+//! Lock order, outermost first: a `Mutex`, the scheduler, the console, the
+//! heap, the page allocator.
+use crate::util::interrupt;
+use crate::util::thread::WaitQueue;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+/// A bare test-and-set lock with nothing inside it. Pair it with the
+/// `static mut` it protects.
+pub struct RawSpinLock {
+    locked: AtomicBool,
+}
+
+impl RawSpinLock {
+    pub const fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    pub fn lock(&self) {
+        // swap is a single amoswap.w.aq.
+        while self.locked.swap(true, Ordering::Acquire) {
+            // Spin on a plain load so we aren't hammering the line with AMOs.
+            while self.locked.load(Ordering::Relaxed) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn try_lock(&self) -> bool {
+        !self.locked.swap(true, Ordering::Acquire)
+    }
+
+    pub fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// Run `f` with interrupts off on this hart and the lock held, so
+    /// neither an interrupt handler here nor another hart can get in.
+    pub fn with<R, F: FnOnce() -> R>(&self, f: F) -> R {
+        interrupt::without_interrupts(|| {
+            self.lock();
+            let result = f();
+            self.unlock();
+            result
+        })
+    }
+}
+
+/// A spinlock around `T`. Interrupts stay as they are, so don't take it in
+/// an interrupt handler if it's also taken with interrupts on.
+pub struct SpinLock<T> {
+    raw: RawSpinLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for SpinLock<T> {}
+unsafe impl<T: Send> Send for SpinLock<T> {}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            raw: RawSpinLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        self.raw.lock();
+        SpinLockGuard { lock: self }
+    }
+
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        if self.raw.try_lock() {
+            Some(SpinLockGuard { lock: self })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock();
+    }
+}
+
+/// A spinlock that also masks interrupts on this hart (sstatus.SIE, the
+/// kernel runs in S-mode) while it's held, so an interrupt handler that
+/// takes it can't deadlock against the code it interrupted.
+pub struct IrqSpinLock<T> {
+    raw: RawSpinLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for IrqSpinLock<T> {}
+unsafe impl<T: Send> Send for IrqSpinLock<T> {}
+
+pub struct IrqSpinLockGuard<'a, T> {
+    lock: &'a IrqSpinLock<T>,
+    // Whether to turn interrupts back on when the guard goes.
+    irqs_were_on: bool,
+}
+
+impl<T> IrqSpinLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            raw: RawSpinLock::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> IrqSpinLockGuard<'_, T> {
+        let irqs_were_on = interrupt::disable_global();
+        self.raw.lock();
+        IrqSpinLockGuard {
+            lock: self,
+            irqs_were_on,
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSpinLockGuard<'_, T>> {
+        let irqs_were_on = interrupt::disable_global();
+        if self.raw.try_lock() {
+            return Some(IrqSpinLockGuard {
+                lock: self,
+                irqs_were_on,
+            });
+        }
+        if irqs_were_on {
+            interrupt::enable_global();
+        }
+        None
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Release the lock whoever holds it. Only for the panic handler, which
+    /// may have interrupted the holder and needs the console regardless.
+    ///
+    /// # Safety
+    /// The holder's guard still thinks it owns the data.
+    pub unsafe fn force_unlock(&self) {
+        self.raw.unlock();
+    }
+}
+
+impl<T> Deref for IrqSpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T> DerefMut for IrqSpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T> Drop for IrqSpinLockGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock.raw.unlock();
+        if self.irqs_were_on {
+            interrupt::enable_global();
+        }
+    }
+}
+
+/// A lock that sleeps instead of spinning when it's taken.
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        loop {
+            if let Some(guard) = self.try_lock() {
+                return guard;
+            }
+            self.waiters
+                .wait_until(|| !self.locked.load(Ordering::Relaxed));
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        if self.locked.swap(true, Ordering::Acquire) {
+            None
+        } else {
+            Some(MutexGuard { mutex: self })
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Ordering::Release);
+        self.mutex.waiters.notify_one();
+    }
+}
+
+/// Wait for a condition that's protected by a `Mutex`. Wakeups can be
+/// spurious, so check the condition again in a loop, or use `wait_while`.
+pub struct Condvar {
+    // Bumped by every notify, so a waiter can tell it missed nothing
+    // between letting go of the mutex and going to sleep.
+    generation: AtomicUsize,
+    waiters: WaitQueue,
+}
+
+impl Condvar {
+    pub const fn new() -> Self {
+        Self {
+            generation: AtomicUsize::new(0),
+            waiters: WaitQueue::new(),
+        }
+    }
+
+    /// Release `guard`'s mutex, sleep until notified and take it again.
+    pub fn wait<'a, T>(&self, guard: MutexGuard<'a, T>) -> MutexGuard<'a, T> {
+        let mutex = guard.mutex;
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        self.waiters
+            .wait_until(|| self.generation.load(Ordering::Acquire) != generation);
+        mutex.lock()
+    }
+
+    /// Like `wait`, but gives up if the thread is interrupted, and then
+    /// doesn't take the mutex again.
+    pub fn wait_interruptible<'a, T>(&self, guard: MutexGuard<'a, T>) -> Option<MutexGuard<'a, T>> {
+        let mutex = guard.mutex;
+        let generation = self.generation.load(Ordering::Acquire);
+        drop(guard);
+        let notified = self
+            .waiters
+            .wait_interruptible(|| self.generation.load(Ordering::Acquire) != generation);
+        notified.then(|| mutex.lock())
+    }
+
+    /// Wait for as long as `condition` holds.
+    pub fn wait_while<'a, T, F: FnMut(&mut T) -> bool>(
+        &self,
+        mut guard: MutexGuard<'a, T>,
+        mut condition: F,
+    ) -> MutexGuard<'a, T> {
+        while condition(&mut guard) {
+            guard = self.wait(guard);
+        }
+        guard
+    }
+
+    pub fn notify_one(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.notify_one();
+    }
+
+    pub fn notify_all(&self) {
+        self.generation.fetch_add(1, Ordering::Release);
+        self.waiters.notify_all();
+    }
+}
@@ -0,0 +1,163 @@
+//! One-time initialisation for globals that can't be built in a `const`.
+use core::cell::UnsafeCell;
+use core::mem::MaybeUninit;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+/// A value that's set once and read-only after that. Whoever gets there
+/// first runs the initialiser; anyone else spins until it's done.
+pub struct Once<T> {
+    state: AtomicU8,
+    value: UnsafeCell<MaybeUninit<T>>,
+}
+
+unsafe impl<T: Send + Sync> Sync for Once<T> {}
+unsafe impl<T: Send> Send for Once<T> {}
+
+impl<T> Once<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+            value: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+
+    /// The value, running `f` to make it if nobody has yet.
+    pub fn call_once<F: FnOnce() -> T>(&self, f: F) -> &T {
+        if self
+            .state
+            .compare_exchange(INCOMPLETE, RUNNING, Ordering::Acquire, Ordering::Acquire)
+            .is_ok()
+        {
+            unsafe {
+                (*self.value.get()).write(f());
+            }
+            self.state.store(COMPLETE, Ordering::Release);
+        }
+        while self.state.load(Ordering::Acquire) != COMPLETE {
+            core::hint::spin_loop();
+        }
+        unsafe { (*self.value.get()).assume_init_ref() }
+    }
+
+    /// Set the value. Fails, handing `value` back, if it was already set.
+    pub fn set(&self, value: T) -> Result<(), T> {
+        let mut value = Some(value);
+        self.call_once(|| value.take().unwrap());
+        match value {
+            Some(value) => Err(value),
+            None => Ok(()),
+        }
+    }
+
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == COMPLETE {
+            Some(unsafe { (*self.value.get()).assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+/// A global built on first use, e.g. a driver instance that needs the heap.
+pub struct Lazy<T> {
+    once: Once<T>,
+    init: fn() -> T,
+}
+
+impl<T> Lazy<T> {
+    pub const fn new(init: fn() -> T) -> Self {
+        Self {
+            once: Once::new(),
+            init,
+        }
+    }
+
+    pub fn force(this: &Self) -> &T {
+        this.once.call_once(this.init)
+    }
+}
+
+impl<T> Deref for Lazy<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Lazy::force(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+    use std::thread;
+    use std::vec::Vec;
+
+    #[test]
+    fn get_before_and_after() {
+        let once: Once<u32> = Once::new();
+        assert_eq!(once.get(), None);
+        assert!(!once.is_completed());
+        assert_eq!(*once.call_once(|| 5), 5);
+        assert_eq!(once.get(), Some(&5));
+        assert!(once.is_completed());
+    }
+
+    #[test]
+    fn runs_once() {
+        let once: Once<u32> = Once::new();
+        let mut runs = 0;
+        once.call_once(|| {
+            runs += 1;
+            1
+        });
+        assert_eq!(*once.call_once(|| 2), 1);
+        assert_eq!(runs, 1);
+        assert_eq!(once.set(3), Err(3));
+        assert_eq!(once.get(), Some(&1));
+    }
+
+    #[test]
+    fn set_then_get() {
+        let once: Once<u32> = Once::new();
+        assert_eq!(once.set(7), Ok(()));
+        assert_eq!(once.get(), Some(&7));
+    }
+
+    #[test]
+    fn runs_once_across_threads() {
+        static ONCE: Once<usize> = Once::new();
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        let threads: Vec<_> = (0..8)
+            .map(|i| {
+                thread::spawn(move || {
+                    *ONCE.call_once(|| {
+                        RUNS.fetch_add(1, Ordering::SeqCst);
+                        i
+                    })
+                })
+            })
+            .collect();
+        let seen: Vec<usize> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+        assert!(seen.iter().all(|&v| v == seen[0]));
+    }
+
+    #[test]
+    fn lazy_runs_its_init_once() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+        static LAZY: Lazy<usize> = Lazy::new(|| RUNS.fetch_add(1, Ordering::SeqCst) + 10);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 0);
+        assert_eq!(*LAZY, 10);
+        assert_eq!(*Lazy::force(&LAZY), 10);
+        assert_eq!(RUNS.load(Ordering::SeqCst), 1);
+    }
+}
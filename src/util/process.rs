@@ -0,0 +1,359 @@
+//! Processes: a running program's address space, capabilities and threads.
+//!
+//! A process is what `exec` starts. Its threads share one address space
+//! and one capability table, which holds its handles on everything outside
+//! it. It ends when its last thread does, when one calls `exit` with a
+//! code, or when it's killed, from the shell, by its parent or by a fault
+//! in one of its threads; only that process goes, never the kernel. Its
+//! other threads are interrupted out of whatever they're waiting in and go
+//! the next time they'd return to user mode.
+//!
+//! When the last thread has gone its pages go back to the page allocator,
+//! its caps are dropped, the names it registered removed and the devices
+//! it drove reclaimed. What's left is how it ended, for `wait` to collect.
+//! A detached process, such as one the shell starts, isn't waited for and
+//! is forgotten there and then, and so are the children of one that's
+//! ended, with nobody left to wait for them.
+use crate::dev::driver;
+use crate::mm::space::AddressSpace;
+use crate::print;
+use crate::println;
+use crate::srv::console::{self, Command};
+use crate::srv::names;
+use crate::util::cap::CapTable;
+use crate::util::interrupt;
+use crate::util::ipc;
+use crate::util::lock::Mutex;
+use crate::util::thread::{Thread, ThreadError, ThreadId, WaitQueue};
+use crate::util::trap::Exception;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(pub usize);
+
+/// How a process is doing, or how it ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Running,
+    Exited(isize),
+    Killed,
+    // One of its threads took this exception.
+    Faulted(Exception),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    NoSuchProcess,
+    // Only a process's parent may wait for it or kill it.
+    NotChild,
+    // It's ending, so it can't have more threads.
+    Dying,
+    // The waiting thread's own process is being killed.
+    Interrupted,
+    Thread(ThreadError),
+}
+
+impl From<ThreadError> for ProcessError {
+    fn from(e: ThreadError) -> Self {
+        ProcessError::Thread(e)
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    name: &'static str,
+    parent: Option<Pid>,
+    space: Arc<Mutex<AddressSpace>>,
+    caps: Arc<Mutex<CapTable>>,
+    // Its threads, for killing them. May still list some that have gone.
+    threads: Mutex<Vec<ThreadId>>,
+    // Threads started that haven't gone yet.
+    live: AtomicUsize,
+    status: Mutex<Status>,
+    // Set once the status isn't Running.
+    dying: AtomicBool,
+    // Set once the last thread has gone and everything's been freed.
+    done: AtomicBool,
+    exited: WaitQueue,
+    detached: AtomicBool,
+}
+
+static PROCESSES: Mutex<BTreeMap<Pid, Arc<Process>>> = Mutex::new(BTreeMap::new());
+static NEXT_PID: AtomicUsize = AtomicUsize::new(1);
+
+pub fn init() {
+    let _ = console::register(Command {
+        name: "ps",
+        usage: "ps",
+        help: "list processes",
+        handler: ps_command,
+    });
+    let _ = console::register(Command {
+        name: "kill",
+        usage: "kill <pid>",
+        help: "kill a process",
+        handler: kill_command,
+    });
+}
+
+impl Process {
+    /// A process with no threads yet, to start with `spawn_thread`.
+    pub fn new(
+        name: &'static str,
+        parent: Option<Pid>,
+        space: AddressSpace,
+        caps: CapTable,
+    ) -> Arc<Process> {
+        let pid = Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed));
+        let process = Arc::new(Process {
+            pid,
+            name,
+            parent,
+            space: Arc::new(Mutex::new(space)),
+            caps: Arc::new(Mutex::new(caps)),
+            threads: Mutex::new(Vec::new()),
+            live: AtomicUsize::new(0),
+            status: Mutex::new(Status::Running),
+            dying: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+            exited: WaitQueue::new(),
+            detached: AtomicBool::new(false),
+        });
+        PROCESSES.lock().insert(pid, process.clone());
+        process
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn parent(&self) -> Option<Pid> {
+        self.parent
+    }
+
+    pub fn space(&self) -> &Arc<Mutex<AddressSpace>> {
+        &self.space
+    }
+
+    pub fn caps(&self) -> &Arc<Mutex<CapTable>> {
+        &self.caps
+    }
+
+    pub fn status(&self) -> Status {
+        *self.status.lock()
+    }
+
+    /// Start a thread in the process running user code from `entry`, with
+    /// `sp` as its stack and `args` in a0 and a1.
+    pub fn spawn_thread(
+        self: &Arc<Self>,
+        name: &'static str,
+        entry: usize,
+        sp: usize,
+        args: [usize; 2],
+    ) -> Result<ThreadId, ProcessError> {
+        if self.dying.load(Ordering::Acquire) {
+            return Err(ProcessError::Dying);
+        }
+        self.live.fetch_add(1, Ordering::AcqRel);
+        let id = match Thread::spawn_user(name, self.clone(), entry, sp, args) {
+            Ok(id) => id,
+            Err(e) => {
+                self.thread_gone();
+                return Err(e.into());
+            }
+        };
+        // Threads are waited for through their process.
+        let _ = Thread::detach(id);
+        self.threads.lock().push(id);
+        // Killed while it was starting, after `end` looked at the list.
+        if self.dying.load(Ordering::Acquire) {
+            let _ = Thread::interrupt(id);
+        }
+        Ok(id)
+    }
+
+    /// End the process with `status`, unless it's already ending, and
+    /// interrupt its threads so they go.
+    pub fn end(&self, status: Status) {
+        {
+            let mut current = self.status.lock();
+            if *current == Status::Running {
+                *current = status;
+            }
+        }
+        self.dying.store(true, Ordering::Release);
+        for &id in self.threads.lock().iter() {
+            let _ = Thread::interrupt(id);
+        }
+    }
+
+    /// Forget about the process when it ends rather than keep it for `wait`.
+    pub fn detach(&self) {
+        self.detached.store(true, Ordering::SeqCst);
+        if self.done.load(Ordering::SeqCst) {
+            PROCESSES.lock().remove(&self.pid);
+        }
+    }
+
+    /// Give up on a process before starting any thread in it: free what it
+    /// has, as if its last thread had gone, and forget about it.
+    pub fn abandon(&self) {
+        self.detached.store(true, Ordering::SeqCst);
+        self.live.fetch_add(1, Ordering::AcqRel);
+        self.thread_gone();
+    }
+
+    // A thread has gone, or never started. After the last, free everything.
+    fn thread_gone(&self) {
+        if self.live.fetch_sub(1, Ordering::AcqRel) != 1 {
+            return;
+        }
+        self.end(Status::Exited(0));
+        self.space.lock().clear();
+        // Dropping the caps can free endpoints, which takes locks of its own.
+        let caps = self.caps.lock().clear();
+        drop(caps);
+        names::process_exit(self.pid);
+        driver::process_exit(self.pid);
+        match self.status() {
+            Status::Exited(code) => {
+                println!(
+                    "[PROC]: {} ({}) exited with {}",
+                    self.name, self.pid.0, code
+                )
+            }
+            Status::Killed => println!("[PROC]: {} ({}) was killed", self.name, self.pid.0),
+            Status::Faulted(exception) => println!(
+                "[PROC]: {} ({}) died of a {}",
+                self.name,
+                self.pid.0,
+                exception.name()
+            ),
+            Status::Running => {}
+        }
+        self.done.store(true, Ordering::SeqCst);
+        self.exited.notify_all();
+        if self.detached.load(Ordering::SeqCst) {
+            PROCESSES.lock().remove(&self.pid);
+        }
+        let children: Vec<Arc<Process>> = PROCESSES
+            .lock()
+            .values()
+            .filter(|process| process.parent == Some(self.pid))
+            .cloned()
+            .collect();
+        for child in children {
+            child.detach();
+        }
+    }
+}
+
+pub fn get(pid: Pid) -> Option<Arc<Process>> {
+    PROCESSES.lock().get(&pid).cloned()
+}
+
+/// The current thread's process, if it's a user thread.
+pub fn current() -> Option<Arc<Process>> {
+    Thread::process()
+}
+
+/// End the current thread. The process goes with its last thread.
+pub fn exit_thread() -> ! {
+    ipc::thread_exit();
+    if let Some(process) = current() {
+        process.threads.lock().retain(|&id| id != Thread::current());
+        process.thread_gone();
+    }
+    Thread::exit()
+}
+
+/// End the current process with `code`.
+pub fn exit(code: isize) -> ! {
+    if let Some(process) = current() {
+        process.end(Status::Exited(code));
+    }
+    exit_thread()
+}
+
+/// Kill the current process for an exception one of its threads took.
+pub fn fault(exception: Exception) -> ! {
+    if let Some(process) = current() {
+        process.end(Status::Faulted(exception));
+    }
+    exit_thread()
+}
+
+/// On the way back to user mode: go if the process is ending.
+pub fn check() {
+    if Thread::interrupted() {
+        interrupt::enable_global();
+        exit_thread();
+    }
+}
+
+/// Wait for process `pid` to end, collect how it did and forget about it.
+pub fn wait(pid: Pid) -> Result<Status, ProcessError> {
+    let process = get(pid).ok_or(ProcessError::NoSuchProcess)?;
+    if !process
+        .exited
+        .wait_interruptible(|| process.done.load(Ordering::SeqCst))
+    {
+        return Err(ProcessError::Interrupted);
+    }
+    PROCESSES.lock().remove(&pid);
+    Ok(process.status())
+}
+
+/// Kill process `pid`.
+pub fn kill(pid: Pid) -> Result<(), ProcessError> {
+    let process = get(pid).ok_or(ProcessError::NoSuchProcess)?;
+    process.end(Status::Killed);
+    Ok(())
+}
+
+fn ps_command(_args: &[&str]) {
+    println!(
+        "{:>4} {:>6} {:<12} {:>7} {:>8}  {}",
+        "PID", "PARENT", "NAME", "THREADS", "MEMORY", "STATUS"
+    );
+    let processes: Vec<Arc<Process>> = PROCESSES.lock().values().cloned().collect();
+    for process in processes {
+        let parent = process.parent.map_or(0, |pid| pid.0);
+        println!(
+            "{:>4} {:>6} {:<12} {:>7} {:>7}K  {:?}",
+            process.pid.0,
+            parent,
+            process.name,
+            process.live.load(Ordering::Relaxed),
+            process.space.lock().size() / 1024,
+            process.status()
+        );
+    }
+}
+
+fn kill_command(args: &[&str]) {
+    let pid = match args {
+        [pid] => match pid.parse() {
+            Ok(pid) => Pid(pid),
+            Err(_) => {
+                println!("kill: not a pid: {}", pid);
+                return;
+            }
+        },
+        _ => {
+            println!("usage: kill <pid>");
+            return;
+        }
+    };
+    if let Err(e) = kill(pid) {
+        println!("kill: {}: {:?}", pid.0, e);
+    }
+}
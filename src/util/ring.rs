@@ -0,0 +1,136 @@
+//! Fixed capacity FIFO that never allocates.
+
+pub struct RingBuffer<T: Copy, const N: usize> {
+    data: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            data: [None; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Append to the back. Hands the value back if there's no room.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(value);
+        }
+        self.data[(self.head + self.len) % N] = Some(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Take from the front.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        let value = self.data[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        value
+    }
+
+    pub fn peek(&self) -> Option<T> {
+        if self.is_empty() {
+            return None;
+        }
+        self.data[self.head]
+    }
+
+    pub fn clear(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty() {
+        let mut ring: RingBuffer<u8, 4> = RingBuffer::new();
+        assert!(ring.is_empty());
+        assert!(!ring.is_full());
+        assert_eq!(ring.len(), 0);
+        assert_eq!(ring.capacity(), 4);
+        assert_eq!(ring.peek(), None);
+        assert_eq!(ring.pop(), None);
+    }
+
+    #[test]
+    fn full() {
+        let mut ring: RingBuffer<u8, 4> = RingBuffer::new();
+        for i in 0..4 {
+            assert_eq!(ring.push(i), Ok(()));
+        }
+        assert!(ring.is_full());
+        // The value comes back and nothing already in it is lost.
+        assert_eq!(ring.push(9), Err(9));
+        assert_eq!(ring.len(), 4);
+        assert_eq!(ring.peek(), Some(0));
+    }
+
+    #[test]
+    fn counts_follow_pushes_and_pops() {
+        let mut ring: RingBuffer<u8, 4> = RingBuffer::new();
+        ring.push(1).unwrap();
+        ring.push(2).unwrap();
+        ring.push(3).unwrap();
+        assert_eq!(ring.len(), 3);
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.len(), 2);
+        ring.clear();
+        assert_eq!(ring.len(), 0);
+        assert!(ring.is_empty());
+    }
+
+    #[test]
+    fn wraps_around() {
+        let mut ring: RingBuffer<u32, 3> = RingBuffer::new();
+        let mut next = 0;
+        let mut expected = 0;
+        // Several times round, never more than two in it at once.
+        for _ in 0..10 {
+            ring.push(next).unwrap();
+            ring.push(next + 1).unwrap();
+            next += 2;
+            assert_eq!(ring.pop(), Some(expected));
+            assert_eq!(ring.pop(), Some(expected + 1));
+            expected += 2;
+        }
+        assert!(ring.is_empty());
+        // Fill it starting part way round.
+        ring.push(7).unwrap();
+        assert_eq!(ring.pop(), Some(7));
+        for i in 0..3 {
+            ring.push(i).unwrap();
+        }
+        assert!(ring.is_full());
+        assert_eq!(ring.pop(), Some(0));
+        assert_eq!(ring.pop(), Some(1));
+        assert_eq!(ring.pop(), Some(2));
+        assert_eq!(ring.pop(), None);
+    }
+}
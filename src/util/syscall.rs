@@ -0,0 +1,846 @@
+//! The syscall ABI: how user code asks the kernel for things.
+//!
+//! `ecall` from U-mode with the number in a7 and up to six arguments in
+//! a0 - a5. The result comes back in a0, a value on success or a negative
+//! `SyscallError` code, and every other register is left alone. The numbers
+//! are fixed, new calls go on the end.
+//!
+//! ```text
+//!  0  write(buf, len)               console output, returns len
+//!  1  map(addr, len, prot)          zeroed memory at addr (0: anywhere),
+//!                                   returns the address
+//!  2  unmap(addr, len)
+//!  3  send(ep, label, w0..w3)       waits for a receiver to take it
+//!  4  receive(ep)                   waits for a message: label in a1,
+//!                                   words in a2 - a5, a0 1 if the sender
+//!                                   is waiting for a reply
+//!  5  thread_create(entry, sp, arg) returns the new thread's id
+//!  6  thread_exit()
+//!  7  sleep(micros)
+//!  8  time()                        microseconds since boot
+//!  9  fb_info(info)                 fills in an `FbInfo`
+//! 10  fb_map(addr)                  maps the framebuffer at addr (0:
+//!                                   anywhere), returns the address
+//! 11  input_read(buf, len)          console input, without waiting,
+//!                                   returns how many bytes
+//! 12  endpoint_create()             returns a cap for a new endpoint
+//! 13  call(ep, label, w0..w3)       send and wait for the reply, which
+//!                                   comes back like a received message
+//! 14  reply(label, w0..w3)          answer the last call received
+//! 15  cap_derive(cap, rights, offset, size)
+//!                                   a child cap with fewer rights, and for
+//!                                   memory a window (size 0: all of it)
+//! 16  cap_revoke(cap)               kill every cap derived from it
+//! 17  cap_delete(cap)
+//! 18  cap_info(cap)                 kind in a0, rights in a1, bytes or
+//!                                   interrupt number in a2
+//! 19  cap_map(cap, addr, prot)      maps memory or MMIO at addr (0:
+//!                                   anywhere), returns the address
+//! 20  irq_bind(irq, ep, bits)       deliver the interrupt to ep as a
+//!                                   notification with bits, masked till
+//!                                   it's acked
+//! 21  irq_ack(irq)                  unmask it again
+//! 22  dma_alloc(size)               a cap for zeroed, physically
+//!                                   contiguous memory, with its physical
+//!                                   address in a1
+//! 23  name_register(name, len, ep)  make the name refer to ep, see `names`
+//! 24  name_lookup(name, len)        returns a cap to send to the service
+//! 25  name_watch(name, len, ep, bits)
+//!                                   notify ep with bits when the name is
+//!                                   registered or removed
+//! 26  name_remove(name, len, ep)
+//! 27  exit(code)                    end the whole process
+//! 28  spawn(name, len, cap)         start a built-in program as a child,
+//!                                   handing it a copy of cap (-1: none),
+//!                                   returns its pid
+//! 29  wait(pid)                     wait for a child to end: a0 0 if it
+//!                                   exited, code in a1, 1 if it was
+//!                                   killed, 2 if it faulted, scause in a1
+//! 30  kill(pid)                     kill a child
+//! 31  getpid()
+//! 32  shm_create(size)              a cap for zeroed shared memory
+//! 33  shm_destroy(cap)              unmap it from every process and kill
+//!                                   every cap for it
+//! ```
+//!
+//! thread_exit ends only the calling thread, and the process with its last
+//! thread; see `process`. map won't take a process past `MAX_USER_MEMORY`,
+//! so one can't starve the kernel of frames. `ep` and `cap` are slots in the caller's
+//! capability table, see `cap`.
+//! fb_map needs a cap for MMIO covering the framebuffer, with WRITE, and
+//! input_read one for the console with READ. irq_bind needs RECEIVE on the
+//! interrupt's cap and SEND on the endpoint's; `driver` hands those out.
+//! name_register needs SEND and GRANT on the endpoint, since it's passed on
+//! to clients, and RECEIVE, which only the server has; name_remove needs
+//! RECEIVE too and name_watch SEND.
+//! Shared memory is mapped with cap_map, by each process with the rights
+//! of the cap it was given, so one can write a buffer another only reads.
+//! shm_destroy needs the cap shm_create gave, not one derived from it, and
+//! won't take dma_alloc's.
+//! See `ipc` for messages, notifications, page grants and passing caps on.
+//!
+//! Pointer arguments are checked against the caller's address space and
+//! copied through it, so a bad one gets `BadAddress` instead of a fault.
+use crate::dev::clint;
+use crate::dev::driver::{self, DriverError};
+use crate::dev::uart;
+use crate::dev::vga;
+use crate::mm::paging::{MapError, EXECUTE, READ, WRITE};
+use crate::mm::region::Region;
+use crate::mm::space::{AddressSpace, USER_BASE, USER_END};
+use crate::print;
+use crate::println;
+use crate::srv::console::{self, Command};
+use crate::srv::names::{self, NameError, MAX_NAME};
+use crate::util::alloc::PAGE_SIZE;
+use crate::util::cap::{self, Cap, CapError, CapTable, Object};
+use crate::util::exec::{self, ExecError};
+use crate::util::ipc::{self, Endpoint, IpcError, Message};
+use crate::util::lock::Mutex;
+use crate::util::process::{self, Pid, Process, ProcessError, Status};
+use crate::util::thread::{Thread, ThreadError};
+use crate::util::trap::TrapFrame;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const SYS_WRITE: usize = 0;
+pub const SYS_MAP: usize = 1;
+pub const SYS_UNMAP: usize = 2;
+pub const SYS_SEND: usize = 3;
+pub const SYS_RECEIVE: usize = 4;
+pub const SYS_THREAD_CREATE: usize = 5;
+pub const SYS_THREAD_EXIT: usize = 6;
+pub const SYS_SLEEP: usize = 7;
+pub const SYS_TIME: usize = 8;
+pub const SYS_FB_INFO: usize = 9;
+pub const SYS_FB_MAP: usize = 10;
+pub const SYS_INPUT_READ: usize = 11;
+pub const SYS_ENDPOINT_CREATE: usize = 12;
+pub const SYS_CALL: usize = 13;
+pub const SYS_REPLY: usize = 14;
+pub const SYS_CAP_DERIVE: usize = 15;
+pub const SYS_CAP_REVOKE: usize = 16;
+pub const SYS_CAP_DELETE: usize = 17;
+pub const SYS_CAP_INFO: usize = 18;
+pub const SYS_CAP_MAP: usize = 19;
+pub const SYS_IRQ_BIND: usize = 20;
+pub const SYS_IRQ_ACK: usize = 21;
+pub const SYS_DMA_ALLOC: usize = 22;
+pub const SYS_NAME_REGISTER: usize = 23;
+pub const SYS_NAME_LOOKUP: usize = 24;
+pub const SYS_NAME_WATCH: usize = 25;
+pub const SYS_NAME_REMOVE: usize = 26;
+pub const SYS_EXIT: usize = 27;
+pub const SYS_SPAWN: usize = 28;
+pub const SYS_WAIT: usize = 29;
+pub const SYS_KILL: usize = 30;
+pub const SYS_GETPID: usize = 31;
+pub const SYS_SHM_CREATE: usize = 32;
+pub const SYS_SHM_DESTROY: usize = 33;
+const SYSCALLS: usize = 34;
+
+const NAMES: [&str; SYSCALLS] = [
+    "write",
+    "map",
+    "unmap",
+    "send",
+    "receive",
+    "thread_create",
+    "thread_exit",
+    "sleep",
+    "time",
+    "fb_info",
+    "fb_map",
+    "input_read",
+    "endpoint_create",
+    "call",
+    "reply",
+    "cap_derive",
+    "cap_revoke",
+    "cap_delete",
+    "cap_info",
+    "cap_map",
+    "irq_bind",
+    "irq_ack",
+    "dma_alloc",
+    "name_register",
+    "name_lookup",
+    "name_watch",
+    "name_remove",
+    "exit",
+    "spawn",
+    "wait",
+    "kill",
+    "getpid",
+    "shm_create",
+    "shm_destroy",
+];
+
+// map's prot bits.
+pub const PROT_READ: usize = 1 << 0;
+pub const PROT_WRITE: usize = 1 << 1;
+pub const PROT_EXEC: usize = 1 << 2;
+
+// Argument and result registers.
+const A0: usize = 10;
+const A7: usize = 17;
+
+// How much of a write or input_read goes through the kernel at a time.
+const CHUNK: usize = 128;
+
+/// The most one shm_create can ask for.
+pub const MAX_SHM_SIZE: usize = 16 << 20;
+
+/// The most a process can have mapped after a map.
+pub const MAX_USER_MEMORY: usize = 32 << 20;
+
+/// Why a syscall failed. User code sees the code negated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyscallError {
+    // No such syscall, or not one there's anything behind yet.
+    NoSys = 1,
+    // A pointer that isn't mapped for the caller with the access it needs.
+    BadAddress = 2,
+    InvalidArgument = 3,
+    OutOfMemory = 4,
+    // Something is in the way, e.g. a mapping.
+    AlreadyMapped = 5,
+    // The device asked for isn't there.
+    NoDevice = 6,
+    // A cap that isn't for an endpoint where one's wanted.
+    NoEndpoint = 7,
+    // The other side of a call went away without answering, or there's no
+    // call to answer.
+    NoReply = 8,
+    // No cap in that slot, or one that's been revoked.
+    NoCap = 9,
+    // The cap doesn't give the rights asked for.
+    PermissionDenied = 10,
+    // Another driver has the device or interrupt.
+    Busy = 11,
+    // No service by that name.
+    NotFound = 12,
+    // Given up on because the process is being killed.
+    Interrupted = 13,
+}
+
+impl SyscallError {
+    /// The value that goes back in a0.
+    pub fn code(self) -> isize {
+        -(self as isize)
+    }
+}
+
+impl From<MapError> for SyscallError {
+    fn from(e: MapError) -> Self {
+        match e {
+            MapError::AlreadyMapped => SyscallError::AlreadyMapped,
+            MapError::OutOfMemory => SyscallError::OutOfMemory,
+            MapError::Misaligned
+            | MapError::NotMapped
+            | MapError::OutOfRange
+            | MapError::InvalidFlags => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl From<ThreadError> for SyscallError {
+    fn from(e: ThreadError) -> Self {
+        match e {
+            ThreadError::OutOfMemory => SyscallError::OutOfMemory,
+            _ => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl From<IpcError> for SyscallError {
+    fn from(e: IpcError) -> Self {
+        match e {
+            IpcError::BadGrant => SyscallError::BadAddress,
+            IpcError::BadCap => SyscallError::NoCap,
+            IpcError::OutOfMemory => SyscallError::OutOfMemory,
+            IpcError::NoReply => SyscallError::NoReply,
+            IpcError::Interrupted => SyscallError::Interrupted,
+        }
+    }
+}
+
+impl From<CapError> for SyscallError {
+    fn from(e: CapError) -> Self {
+        match e {
+            CapError::InvalidSlot | CapError::Revoked => SyscallError::NoCap,
+            CapError::NoRights => SyscallError::PermissionDenied,
+            CapError::TableFull => SyscallError::OutOfMemory,
+            CapError::WrongKind | CapError::BadWindow => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl From<DriverError> for SyscallError {
+    fn from(e: DriverError) -> Self {
+        match e {
+            DriverError::NoSuchDevice | DriverError::NotPresent => SyscallError::NoDevice,
+            DriverError::AlreadyRegistered | DriverError::Busy => SyscallError::Busy,
+            DriverError::InvalidIrq => SyscallError::InvalidArgument,
+        }
+    }
+}
+
+impl From<NameError> for SyscallError {
+    fn from(e: NameError) -> Self {
+        match e {
+            NameError::BadName => SyscallError::InvalidArgument,
+            NameError::NotFound => SyscallError::NotFound,
+            NameError::NotOwner => SyscallError::PermissionDenied,
+            NameError::Cap(e) => e.into(),
+        }
+    }
+}
+
+impl From<ProcessError> for SyscallError {
+    fn from(e: ProcessError) -> Self {
+        match e {
+            ProcessError::NoSuchProcess => SyscallError::NotFound,
+            ProcessError::NotChild => SyscallError::PermissionDenied,
+            ProcessError::Dying | ProcessError::Interrupted => SyscallError::Interrupted,
+            ProcessError::Thread(e) => e.into(),
+        }
+    }
+}
+
+/// What fb_info hands back.
+#[repr(C)]
+pub struct FbInfo {
+    pub width: u32,
+    pub height: u32,
+    // Bytes from one row to the next.
+    pub stride: u32,
+    pub bytes_per_pixel: u32,
+    pub size: u64,
+}
+
+type Space = Arc<Mutex<AddressSpace>>;
+type Caps = Arc<Mutex<CapTable>>;
+type SysResult = Result<usize, SyscallError>;
+
+static COUNTS: [AtomicUsize; SYSCALLS] = [const { AtomicUsize::new(0) }; SYSCALLS];
+
+extern "C" {
+    static user_test_start: u8;
+    static user_test_end: u8;
+}
+
+pub fn init() {
+    let _ = console::register(Command {
+        name: "syscalls",
+        usage: "syscalls",
+        help: "how many times each syscall has been made",
+        handler: syscalls_command,
+    });
+    let _ = console::register(Command {
+        name: "usertest",
+        usage: "usertest",
+        help: "run a small program in U-mode that tries some syscalls",
+        handler: usertest_command,
+    });
+}
+
+/// Carry out the syscall in `frame` and put the result in its a0. sepc is
+/// already past the ecall.
+pub fn dispatch(frame: &mut TrapFrame) {
+    let number = frame.reg(A7);
+    let args = [
+        frame.reg(A0),
+        frame.reg(A0 + 1),
+        frame.reg(A0 + 2),
+        frame.reg(A0 + 3),
+        frame.reg(A0 + 4),
+        frame.reg(A0 + 5),
+    ];
+    if let Some(count) = COUNTS.get(number) {
+        count.fetch_add(1, Ordering::Relaxed);
+    }
+    // These don't come back, so they mustn't be holding any references.
+    match number {
+        SYS_THREAD_EXIT => process::exit_thread(),
+        SYS_EXIT => process::exit(args[0] as isize),
+        _ => {}
+    }
+    // Only user threads get here, and they all have a process.
+    let result = match Thread::process() {
+        Some(process) => call(&process, frame, number, args),
+        None => Err(SyscallError::NoSys),
+    };
+    let value = match result {
+        Ok(value) => value,
+        Err(e) => e.code() as usize,
+    };
+    frame.set_reg(A0, value);
+}
+
+fn call(
+    process: &Arc<Process>,
+    frame: &mut TrapFrame,
+    number: usize,
+    args: [usize; 6],
+) -> SysResult {
+    let (space, caps) = (process.space(), process.caps());
+    match number {
+        SYS_WRITE => write(space, args[0], args[1]),
+        SYS_MAP => map(space, args[0], args[1], args[2]),
+        SYS_UNMAP => {
+            space.lock().unmap(args[0], args[1])?;
+            Ok(0)
+        }
+        SYS_SEND => {
+            endpoint(caps, args[0], cap::SEND)?.send(message(args))?;
+            Ok(0)
+        }
+        SYS_RECEIVE => {
+            let (message, reply) = endpoint(caps, args[0], cap::RECEIVE)?.receive()?;
+            let owed = reply.is_some();
+            ipc::keep_reply(reply);
+            put_message(frame, message);
+            Ok(owed as usize)
+        }
+        SYS_THREAD_CREATE => thread_create(process, args[0], args[1], args[2]),
+        SYS_SLEEP => {
+            Thread::sleep(args[0] as u64);
+            Ok(0)
+        }
+        SYS_TIME => Ok(clint::uptime_micros() as usize),
+        SYS_FB_INFO => fb_info(space, args[0]),
+        SYS_FB_MAP => fb_map(space, caps, args[0]),
+        SYS_INPUT_READ => input_read(space, caps, args[0], args[1]),
+        SYS_ENDPOINT_CREATE => {
+            let endpoint = Object::Endpoint(Endpoint::create());
+            Ok(caps.lock().insert(Cap::new(endpoint, cap::ALL_RIGHTS))?)
+        }
+        SYS_CALL => {
+            let reply = endpoint(caps, args[0], cap::SEND)?.call(message(args))?;
+            put_message(frame, reply);
+            Ok(0)
+        }
+        SYS_REPLY => {
+            // No endpoint, so the message starts one register earlier.
+            ipc::reply(Message::new(args[0], [args[1], args[2], args[3], args[4]]))?;
+            Ok(0)
+        }
+        SYS_CAP_DERIVE => cap_derive(caps, args[0], args[1], args[2], args[3]),
+        SYS_CAP_REVOKE => {
+            caps.lock().get(args[0])?.revoke();
+            Ok(0)
+        }
+        SYS_CAP_DELETE => {
+            let cap = caps.lock().remove(args[0])?;
+            // The last reference to an endpoint or region can take locks of
+            // its own going.
+            drop(cap);
+            Ok(0)
+        }
+        SYS_CAP_INFO => {
+            let cap = caps.lock().get(args[0])?;
+            frame.set_reg(A0 + 1, cap.rights());
+            frame.set_reg(A0 + 2, cap.object().size());
+            Ok(cap.object().kind() as usize)
+        }
+        SYS_CAP_MAP => cap_map(space, caps, args[0], args[1], args[2]),
+        SYS_IRQ_BIND => {
+            let irq = irq(caps, args[0])?;
+            driver::bind(irq, endpoint(caps, args[1], cap::SEND)?, args[2])?;
+            Ok(0)
+        }
+        SYS_IRQ_ACK => {
+            driver::ack(irq(caps, args[0])?)?;
+            Ok(0)
+        }
+        SYS_DMA_ALLOC => {
+            let region = driver::dma_alloc(args[0])?;
+            let slot = insert_region(caps, &region)?;
+            frame.set_reg(A0 + 1, region.base());
+            Ok(slot)
+        }
+        SYS_NAME_REGISTER | SYS_NAME_LOOKUP | SYS_NAME_WATCH | SYS_NAME_REMOVE => {
+            let mut buf = [0u8; MAX_NAME];
+            let name = read_name(space, args[0], args[1], &mut buf)?;
+            match number {
+                SYS_NAME_REGISTER => {
+                    let rights = cap::SEND | cap::GRANT | cap::RECEIVE;
+                    let endpoint = endpoint(caps, args[2], rights)?;
+                    names::register(name, endpoint, Some(process.pid()))?
+                }
+                SYS_NAME_LOOKUP => {
+                    let cap = names::lookup(name)?;
+                    return Ok(caps.lock().insert(cap)?);
+                }
+                SYS_NAME_WATCH => {
+                    names::watch(name, &endpoint(caps, args[2], cap::SEND)?, args[3])?
+                }
+                _ => names::remove(name, &endpoint(caps, args[2], cap::RECEIVE)?)?,
+            }
+            Ok(0)
+        }
+        SYS_SPAWN => spawn(process, args[0], args[1], args[2]),
+        SYS_WAIT => wait(process, frame, args[0]),
+        SYS_KILL => {
+            process::kill(child(process, args[0])?)?;
+            Ok(0)
+        }
+        SYS_GETPID => Ok(process.pid().0),
+        SYS_SHM_CREATE => shm_create(caps, args[0]),
+        SYS_SHM_DESTROY => shm_destroy(caps, args[0]),
+        _ => Err(SyscallError::NoSys),
+    }
+}
+
+// The endpoint behind the cap in `slot`, which needs `rights`.
+fn endpoint(caps: &Caps, slot: usize, rights: usize) -> Result<Arc<Endpoint>, SyscallError> {
+    caps.lock().endpoint(slot, rights).map_err(|e| match e {
+        CapError::WrongKind => SyscallError::NoEndpoint,
+        e => e.into(),
+    })
+}
+
+// The interrupt behind the cap in `slot`, which needs RECEIVE.
+fn irq(caps: &Caps, slot: usize) -> Result<u32, SyscallError> {
+    match caps.lock().lookup(slot, cap::RECEIVE)?.object() {
+        Object::Irq(irq) => Ok(*irq),
+        _ => Err(CapError::WrongKind.into()),
+    }
+}
+
+// A service name from user memory, which has to fit in `buf`.
+fn read_name<'a>(
+    space: &Space,
+    addr: usize,
+    len: usize,
+    buf: &'a mut [u8; MAX_NAME],
+) -> Result<&'a str, SyscallError> {
+    let buf = buf.get_mut(..len).ok_or(SyscallError::InvalidArgument)?;
+    space
+        .lock()
+        .copy_in(addr, buf)
+        .map_err(|_| SyscallError::BadAddress)?;
+    core::str::from_utf8(buf).map_err(|_| SyscallError::InvalidArgument)
+}
+
+// The message in a send or call: the label in a1, the words in a2 - a5.
+fn message(args: [usize; 6]) -> Message {
+    Message::new(args[1], [args[2], args[3], args[4], args[5]])
+}
+
+// Hand a received message back the same way.
+fn put_message(frame: &mut TrapFrame, message: Message) {
+    frame.set_reg(A0 + 1, message.label);
+    for (i, &word) in message.words.iter().enumerate() {
+        frame.set_reg(A0 + 2 + i, word);
+    }
+}
+
+fn write(space: &Space, buf: usize, len: usize) -> SysResult {
+    if !space.lock().check(buf, len, READ) {
+        return Err(SyscallError::BadAddress);
+    }
+    let mut chunk = [0u8; CHUNK];
+    let mut done = 0;
+    while done < len {
+        let n = (len - done).min(CHUNK);
+        space
+            .lock()
+            .copy_in(buf + done, &mut chunk[..n])
+            .map_err(|_| SyscallError::BadAddress)?;
+        let mut console = uart::console();
+        for &byte in &chunk[..n] {
+            console.put_byte(byte);
+        }
+        done += n;
+    }
+    Ok(len)
+}
+
+// PTE permissions for map's `prot`.
+fn prot_flags(prot: usize) -> Result<usize, SyscallError> {
+    if prot & !(PROT_READ | PROT_WRITE | PROT_EXEC) != 0 {
+        return Err(SyscallError::InvalidArgument);
+    }
+    let mut flags = 0;
+    for (bit, flag) in [(PROT_READ, READ), (PROT_WRITE, WRITE), (PROT_EXEC, EXECUTE)] {
+        if prot & bit != 0 {
+            flags |= flag;
+        }
+    }
+    Ok(flags)
+}
+
+fn map(space: &Space, addr: usize, len: usize, prot: usize) -> SysResult {
+    let flags = prot_flags(prot)?;
+    let mut space = space.lock();
+    if space.size().saturating_add(len) > MAX_USER_MEMORY {
+        return Err(SyscallError::OutOfMemory);
+    }
+    let addr = match addr {
+        0 => space.find_free(len).ok_or(SyscallError::OutOfMemory)?,
+        addr => addr,
+    };
+    space.map(addr, len, flags)?;
+    Ok(addr)
+}
+
+fn thread_create(process: &Arc<Process>, entry: usize, sp: usize, arg: usize) -> SysResult {
+    if !process.space().lock().check(entry, 4, EXECUTE) {
+        return Err(SyscallError::BadAddress);
+    }
+    let id = process.spawn_thread("uthread", entry, sp, [arg, 0])?;
+    Ok(id.0)
+}
+
+fn spawn(process: &Arc<Process>, name: usize, len: usize, slot: usize) -> SysResult {
+    let mut buf = [0u8; MAX_NAME];
+    let name = read_name(process.space(), name, len, &mut buf)?;
+    let mut caps = CapTable::new();
+    if slot != usize::MAX {
+        let cap = process.caps().lock().lookup(slot, cap::GRANT)?;
+        caps.insert(cap.derive(cap.rights(), None)?)?;
+    }
+    let child = exec::spawn(&[name], caps, &[], Some(process.pid())).map_err(|e| match e {
+        ExecError::NoSuchProgram => SyscallError::NotFound,
+        ExecError::Map(e) => e.into(),
+        ExecError::Thread(e) => e.into(),
+        _ => SyscallError::InvalidArgument,
+    })?;
+    Ok(child.pid().0)
+}
+
+// A process the caller may wait for or kill.
+fn child(process: &Process, pid: usize) -> Result<Pid, SyscallError> {
+    let child = process::get(Pid(pid)).ok_or(ProcessError::NoSuchProcess)?;
+    if child.parent() != Some(process.pid()) {
+        return Err(ProcessError::NotChild.into());
+    }
+    Ok(child.pid())
+}
+
+fn wait(process: &Process, frame: &mut TrapFrame, pid: usize) -> SysResult {
+    let (how, value) = match process::wait(child(process, pid)?)? {
+        Status::Exited(code) => (0, code as usize),
+        Status::Killed => (1, 0),
+        Status::Faulted(exception) => (2, exception as usize),
+        Status::Running => (0, 0),
+    };
+    frame.set_reg(A0 + 1, value);
+    Ok(how)
+}
+
+fn fb_info(space: &Space, info: usize) -> SysResult {
+    let fb = vga::framebuffer().ok_or(SyscallError::NoDevice)?;
+    let out = FbInfo {
+        width: fb.width,
+        height: fb.height,
+        stride: fb.width,
+        bytes_per_pixel: 1,
+        size: fb.size as u64,
+    };
+    let bytes = unsafe {
+        core::slice::from_raw_parts(
+            &out as *const FbInfo as *const u8,
+            core::mem::size_of::<FbInfo>(),
+        )
+    };
+    space
+        .lock()
+        .copy_out(info, bytes)
+        .map_err(|_| SyscallError::BadAddress)?;
+    Ok(0)
+}
+
+fn fb_map(space: &Space, caps: &Caps, addr: usize) -> SysResult {
+    let fb = vga::framebuffer().ok_or(SyscallError::NoDevice)?;
+    let covers = |object: &Object| match *object {
+        Object::Mmio { base, size } => base <= fb.base && fb.base + fb.size <= base + size,
+        _ => false,
+    };
+    if caps.lock().find(cap::WRITE, covers).is_none() {
+        return Err(SyscallError::PermissionDenied);
+    }
+    let mut space = space.lock();
+    let addr = match addr {
+        0 => space.find_free(fb.size).ok_or(SyscallError::OutOfMemory)?,
+        addr => addr,
+    };
+    space.map_physical(addr, fb.base, fb.size, READ | WRITE)?;
+    Ok(addr)
+}
+
+fn input_read(space: &Space, caps: &Caps, buf: usize, len: usize) -> SysResult {
+    let console = |object: &Object| matches!(object, Object::Console);
+    if caps.lock().find(cap::READ, console).is_none() {
+        return Err(SyscallError::PermissionDenied);
+    }
+    if !space.lock().check(buf, len, WRITE) {
+        return Err(SyscallError::BadAddress);
+    }
+    let mut chunk = [0u8; CHUNK];
+    let mut n = 0;
+    while n < len.min(CHUNK) {
+        match uart::try_read() {
+            Some(byte) => {
+                chunk[n] = byte;
+                n += 1;
+            }
+            None => break,
+        }
+    }
+    space
+        .lock()
+        .copy_out(buf, &chunk[..n])
+        .map_err(|_| SyscallError::BadAddress)?;
+    Ok(n)
+}
+
+fn cap_derive(caps: &Caps, slot: usize, rights: usize, offset: usize, size: usize) -> SysResult {
+    let mut caps = caps.lock();
+    let window = (size != 0).then_some((offset, size));
+    let child = caps.get(slot)?.derive(rights, window)?;
+    Ok(caps.insert(child)?)
+}
+
+// Map all of a memory or MMIO cap's window at `addr`. `prot` can't ask for
+// more than the cap's rights, and device memory can't be executed.
+fn cap_map(space: &Space, caps: &Caps, slot: usize, addr: usize, prot: usize) -> SysResult {
+    let flags = prot_flags(prot)?;
+    let mut need = 0;
+    if prot & (PROT_READ | PROT_EXEC) != 0 {
+        need |= cap::READ;
+    }
+    if prot & PROT_WRITE != 0 {
+        need |= cap::WRITE;
+    }
+    let cap = caps.lock().lookup(slot, need)?;
+    let size = cap.object().size();
+    let addr = {
+        let mut space = space.lock();
+        map_cap(&mut space, &cap, addr, size, prot, flags)?
+    };
+    // So shm_destroy and driver::reclaim can find it here. Either may have
+    // revoked the cap since it was looked up, and missed this space if so,
+    // and a destroyed region refuses it whatever cap it came through.
+    let registered = match cap.object() {
+        Object::Memory { region, .. } => region.mapped_in(space),
+        Object::Mmio { base, .. } => {
+            driver::mapped_in(*base, space);
+            true
+        }
+        _ => true,
+    };
+    if !registered || !cap.is_valid() {
+        let _ = space.lock().unmap(addr, size);
+        return Err(CapError::Revoked.into());
+    }
+    Ok(addr)
+}
+
+fn map_cap(
+    space: &mut AddressSpace,
+    cap: &Cap,
+    addr: usize,
+    size: usize,
+    prot: usize,
+    flags: usize,
+) -> SysResult {
+    let addr = match (addr, cap.object()) {
+        (_, Object::Memory { .. } | Object::Mmio { .. }) if size == 0 => {
+            return Err(SyscallError::InvalidArgument)
+        }
+        (0, _) => space.find_free(size).ok_or(SyscallError::OutOfMemory)?,
+        (addr, _) => addr,
+    };
+    match cap.object() {
+        Object::Memory { region, offset, .. } => {
+            space.map_region(addr, region, *offset, size, flags)?
+        }
+        Object::Mmio { base, .. } if prot & PROT_EXEC == 0 => {
+            space.map_physical(addr, *base, size, flags)?
+        }
+        Object::Mmio { .. } => return Err(SyscallError::InvalidArgument),
+        _ => return Err(CapError::WrongKind.into()),
+    }
+    Ok(addr)
+}
+
+// A cap for all of a new region, in the caller's table.
+fn insert_region(caps: &Caps, region: &Arc<Region>) -> SysResult {
+    let memory = Object::Memory {
+        region: region.clone(),
+        offset: 0,
+        size: region.size(),
+    };
+    let rights = cap::READ | cap::WRITE | cap::GRANT;
+    Ok(caps.lock().insert(Cap::new(memory, rights))?)
+}
+
+fn shm_create(caps: &Caps, size: usize) -> SysResult {
+    if size == 0 || size > MAX_SHM_SIZE {
+        return Err(SyscallError::InvalidArgument);
+    }
+    insert_region(caps, &Region::shared(size)?)
+}
+
+// Only with the cap shm_create made, which covers all of the region. DMA
+// buffers go with their last reference instead.
+fn shm_destroy(caps: &Caps, slot: usize) -> SysResult {
+    let cap = caps.lock().lookup(slot, cap::READ | cap::WRITE)?;
+    let region = match cap.object() {
+        Object::Memory { region, .. } if !region.is_shared() => {
+            return Err(CapError::WrongKind.into())
+        }
+        Object::Memory { region, .. } if cap.is_original() => region.clone(),
+        Object::Memory { .. } => return Err(SyscallError::PermissionDenied),
+        _ => return Err(CapError::WrongKind.into()),
+    };
+    cap.revoke();
+    region.unmap_everywhere();
+    let cap = caps.lock().remove(slot)?;
+    // The frames go back with the last reference, which may be this.
+    drop(cap);
+    Ok(0)
+}
+
+fn syscalls_command(_args: &[&str]) {
+    println!("{:>3} {:<14} {:>10}", "NR", "NAME", "CALLS");
+    for (number, name) in NAMES.iter().enumerate() {
+        println!(
+            "{:>3} {:<14} {:>10}",
+            number,
+            name,
+            COUNTS[number].load(Ordering::Relaxed)
+        );
+    }
+}
+
+// Code at the bottom of user space, a page of stack at the top.
+fn usertest_command(_args: &[&str]) {
+    let code = unsafe {
+        let start = core::ptr::addr_of!(user_test_start);
+        let end = core::ptr::addr_of!(user_test_end);
+        core::slice::from_raw_parts(start, end as usize - start as usize)
+    };
+    let setup = || -> Result<AddressSpace, MapError> {
+        let mut space = AddressSpace::new()?;
+        space.map(USER_BASE, code.len(), READ | EXECUTE)?;
+        space.load(USER_BASE, code)?;
+        space.map(USER_END - PAGE_SIZE, PAGE_SIZE, READ | WRITE)?;
+        Ok(space)
+    };
+    let space = match setup() {
+        Ok(space) => space,
+        Err(e) => {
+            println!("usertest: no address space: {:?}", e);
+            return;
+        }
+    };
+    let process = Process::new("usertest", None, space, CapTable::new());
+    if let Err(e) = process.spawn_thread("usertest", USER_BASE, USER_END, [0, 0]) {
+        println!("usertest: no thread: {:?}", e);
+    }
+    let _ = process::wait(process.pid());
+}
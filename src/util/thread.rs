@@ -1,9 +1,1081 @@
-enum ThreadState {
+//! Kernel threads.
+//!
+//! Every thread gets its own stack from the page allocator and a `Context`
+//! with the registers a function call preserves; `switch_context` in
+//! `asm/switch.S` saves one and loads the other. `Thread::init` turns the
+//! boot code into thread 0, "kmain".
+//!
+//! A user thread is a kernel thread that drops to U-mode in its process's
+//! address space and comes back into the kernel on its kernel stack for
+//! syscalls and interrupts. Switching to it loads its space's satp.
+//! `interrupt` gets a thread out of waits it can give up on, such as IPC,
+//! so that killing a process doesn't have to wait on what it's blocked in.
+//!
+//! Scheduling is preemptive and by strict priority, higher numbers first.
+//! Each priority level has its own FIFO run queue, so equal priorities take
+//! turns a time slice at a time. A one-shot timer ends the slice (or wakes
+//! the first sleeper); its callback only flags that a switch is due, and
+//! `trap_handler` calls `preempt` on the way out. When nothing is ready the
+//! idle thread sits in `wfi`.
+//!
+//! Every hart runs the same scheduler. The threads and run queues are shared
+//! under `SCHED_LOCK`; what a hart is running, its idle thread and its timer
+//! are its own. The lock is held across `switch_context`, so the thread
+//! being switched to is the one that lets go of it, and a thread that was
+//! just queued can't be picked up by another hart before its registers are
+//! saved. Making a thread ready sends an IPI to a hart that's idle so it
+//! doesn't sleep through the work.
+//!
+//! Above the priorities sits a real-time class for periodic work such as the
+//! emulator's frame loop. A periodic thread gets `budget` of CPU in every
+//! `period` and is scheduled earliest deadline first, ahead of everything
+//! else. Running out of budget throttles it until its next period, so a
+//! runaway frame can't starve the rest of the system either. Admission
+//! control keeps the class under `MAX_RT_PERCENT` of the CPU.
+use crate::dev::clint::{self, TimerId, MAX_HARTS};
+use crate::mm::paging;
+use crate::mm::space::AddressSpace;
+use crate::print;
+use crate::println;
+use crate::smp::ipi;
+use crate::srv::console::{self, Command};
+use crate::util::alloc::{Alloc, PAGE_SIZE};
+use crate::util::cap::CapTable;
+use crate::util::interrupt;
+use crate::util::lock::{Mutex, RawSpinLock};
+use crate::util::process::Process;
+use crate::util::trap;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::ptr::null_mut;
+
+const STACK_PAGES: usize = 4;
+const STACK_SIZE: usize = STACK_PAGES * PAGE_SIZE;
+// Written at the bottom of every stack and checked on each switch away.
+const STACK_CANARY: u64 = 0x5354_4143_4B21_2121;
+
+pub const MAX_PRIORITY: u8 = 7;
+pub const DEFAULT_PRIORITY: u8 = 4;
+const PRIORITY_LEVELS: usize = MAX_PRIORITY as usize + 1;
+const TIME_SLICE_MICROS: u64 = 10_000;
+// Real-time threads together may ask for this much of the CPU.
+const MAX_RT_PERCENT: u64 = 90;
+// Report the first deadline miss of a thread and then every this many.
+const MISS_REPORT_INTERVAL: usize = 60;
+const IDLE_NAMES: [&str; MAX_HARTS] = [
+    "idle0", "idle1", "idle2", "idle3", "idle4", "idle5", "idle6", "idle7",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    // Exited, waiting to be joined.
     Stopped,
+    // Sleeping or blocked in join.
     Waiting,
     Running,
+    // On the run queue.
+    Ready,
 }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(pub usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadError {
+    // No pages left for the stack.
+    OutOfMemory,
+    NoSuchThread,
+    // A thread can't wait for itself to exit.
+    JoinSelf,
+    // A zero budget, or more budget than period.
+    InvalidPeriod,
+    // Admitting the thread would push real-time threads over MAX_RT_PERCENT.
+    Overloaded,
+    // Not a periodic thread.
+    NotPeriodic,
+}
+
+/// Callee-saved registers, saved and loaded by `switch_context`. Layout is
+/// shared with the assembly.
+#[repr(C)]
+#[derive(Default)]
+pub struct Context {
+    pub ra: usize,
+    pub sp: usize,
+    pub s: [usize; 12],
+    pub fs: [u64; 12],
+}
+
+// A periodic thread's parameters and progress, in mtime ticks.
+#[derive(Clone, Copy)]
+struct Periodic {
+    period: u64,
+    budget: u64,
+    // Start of the current period. Its deadline is one period later.
+    release: u64,
+    // CPU used since `release`.
+    used: u64,
+    // Out of budget, waiting for the next period.
+    throttled: bool,
+    misses: usize,
+    overruns: usize,
+}
+
+impl Periodic {
+    fn deadline(&self) -> u64 {
+        self.release + self.period
+    }
+}
+
+/// What `Thread::periodic_stats` reports, in microseconds.
+#[derive(Debug, Clone, Copy)]
+pub struct PeriodicStats {
+    pub period: u64,
+    pub budget: u64,
+    // Periods that ended before their job did.
+    pub misses: usize,
+    // Times the budget ran out.
+    pub overruns: usize,
+}
+
 pub struct Thread {
+    id: ThreadId,
+    name: &'static str,
     state: ThreadState,
     priority: u8,
+    context: Context,
+    // Lowest address of the stack pages. Null for kmain, which keeps the
+    // boot stack.
+    stack: *mut u8,
+    // mtime to wake at while sleeping.
+    wake_at: u64,
+    // Threads blocked in join on this one.
+    joiners: Vec<ThreadId>,
+    entry: Option<Box<dyn FnOnce()>>,
+    // mtime ticks spent running, up to the last switch away.
+    cpu_ticks: u64,
+    switches: usize,
+    // Set for threads in the real-time class.
+    periodic: Option<Periodic>,
+    // Hart it's running on, or last ran on.
+    hart: usize,
+    // The process a user thread belongs to and its space's satp. Kernel
+    // threads have none and 0, and run on the kernel table.
+    process: Option<Arc<Process>>,
+    satp: usize,
+    // Asked to stop waiting, see `interrupt`.
+    interrupted: bool,
+    // Nobody will join it, so it goes as soon as it exits.
+    detached: bool,
+}
+
+struct Scheduler {
+    // Boxed so a Context doesn't move while switch_context holds on to it.
+    threads: BTreeMap<ThreadId, Box<Thread>>,
+    // One run queue per priority.
+    ready: [VecDeque<ThreadId>; PRIORITY_LEVELS],
+    next_id: usize,
+}
+
+// One hart's part of the scheduler. Only that hart touches it, with
+// interrupts off.
+struct HartState {
+    current: ThreadId,
+    // A thread that just exited; its stack is freed once we're off it.
+    dead: Option<ThreadId>,
+    // Runs when nothing else can. Never on a run queue.
+    idle: Option<ThreadId>,
+    // End of the current slice or the next wakeup, whichever is first.
+    timer: Option<TimerId>,
+    // mtime when the current thread was switched to.
+    switched_at: u64,
+    // Set from interrupt context, acted on by `preempt`.
+    need_resched: bool,
+    // Sent a reschedule IPI since it last picked a thread.
+    kicked: bool,
+}
+
+// Guards SCHEDULER. Taken with interrupts off, before the heap's lock.
+static SCHED_LOCK: RawSpinLock = RawSpinLock::new();
+static mut SCHEDULER: Scheduler = Scheduler {
+    threads: BTreeMap::new(),
+    ready: [const { VecDeque::new() }; PRIORITY_LEVELS],
+    next_id: 1,
+};
+static mut HARTS: [HartState; MAX_HARTS] = [const {
+    HartState {
+        current: ThreadId(0),
+        dead: None,
+        idle: None,
+        timer: None,
+        switched_at: 0,
+        need_resched: false,
+        kicked: false,
+    }
+}; MAX_HARTS];
+
+extern "C" {
+    fn switch_context(old: *mut Context, new: *const Context);
+}
+
+fn scheduler() -> &'static mut Scheduler {
+    unsafe { &mut *core::ptr::addr_of_mut!(SCHEDULER) }
+}
+
+fn hart_state(hart: usize) -> &'static mut HartState {
+    unsafe { &mut *core::ptr::addr_of_mut!(HARTS[hart]) }
+}
+
+fn this_hart() -> &'static mut HartState {
+    hart_state(interrupt::hart_id())
+}
+
+impl Scheduler {
+    fn thread(&mut self, id: ThreadId) -> &mut Thread {
+        match self.threads.get_mut(&id) {
+            Some(thread) => thread,
+            None => panic!("[THREAD]: no thread {}", id.0),
+        }
+    }
+
+    // Real-time threads aren't queued, `pop_ready` finds them by deadline.
+    fn make_ready(&mut self, id: ThreadId) {
+        let thread = self.thread(id);
+        thread.state = ThreadState::Ready;
+        if thread.periodic.is_none() {
+            let level = thread.priority as usize;
+            self.ready[level].push_back(id);
+        }
+    }
+
+    // Make a blocked thread ready and find a hart for it.
+    fn wake(&mut self, id: ThreadId) -> bool {
+        match self.threads.get(&id) {
+            Some(thread) if thread.state == ThreadState::Waiting => {
+                self.make_ready(id);
+                kick_idle_hart();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn wake_sleepers(&mut self, now: u64) {
+        for thread in self.threads.values_mut() {
+            if thread.state != ThreadState::Waiting || thread.wake_at > now {
+                continue;
+            }
+            thread.state = ThreadState::Ready;
+            match &mut thread.periodic {
+                // Throttled until this period started, top the budget up.
+                Some(p) if p.throttled => {
+                    p.throttled = false;
+                    p.release = thread.wake_at;
+                    p.used = 0;
+                }
+                Some(_) => {}
+                None => self.ready[thread.priority as usize].push_back(thread.id),
+            }
+            kick_idle_hart();
+        }
+    }
+
+    // The ready real-time thread with the earliest deadline, or else the
+    // front of the highest non-empty run queue.
+    fn pop_ready(&mut self) -> Option<ThreadId> {
+        let realtime = self
+            .threads
+            .values()
+            .filter(|t| t.state == ThreadState::Ready)
+            .filter_map(|t| Some((t.periodic?.deadline(), t.id)))
+            .min();
+        if let Some((_, id)) = realtime {
+            return Some(id);
+        }
+        self.ready
+            .iter_mut()
+            .rev()
+            .find_map(|queue| queue.pop_front())
+    }
+
+    // Charge `ran` ticks to `id`'s budget and throttle it if it's spent.
+    fn charge(&mut self, id: ThreadId, ran: u64) {
+        let thread = self.thread(id);
+        let Some(p) = &mut thread.periodic else {
+            return;
+        };
+        p.used += ran;
+        if p.used < p.budget || thread.state != ThreadState::Running {
+            return;
+        }
+        // The job can't run again before its deadline, so it's missed it.
+        p.throttled = true;
+        p.overruns += 1;
+        p.misses += 1;
+        thread.state = ThreadState::Waiting;
+        thread.wake_at = p.deadline();
+        if (p.misses - 1).is_multiple_of(MISS_REPORT_INTERVAL) {
+            println!(
+                "[EDF]: {} ran out of budget, deadline missed ({} so far)",
+                thread.name, p.misses
+            );
+        }
+    }
+
+    // Share of the CPU, in percent, the real-time threads other than
+    // `except` have been promised.
+    fn rt_load(&self, except: ThreadId) -> u64 {
+        self.threads
+            .values()
+            .filter(|t| t.id != except && t.state != ThreadState::Stopped)
+            .filter_map(|t| t.periodic)
+            .map(|p| p.budget * 100 / p.period)
+            .sum()
+    }
+
+    // Arm this hart's scheduler timer for the end of `next`'s slice or the
+    // first sleeper's wakeup. The idle thread has no slice to end.
+    fn arm_timer(&mut self, hart: &mut HartState, next: ThreadId, now: u64) {
+        if let Some(timer) = hart.timer.take() {
+            clint::cancel(timer);
+        }
+        let mut deadline = self
+            .threads
+            .values()
+            .filter(|t| t.state == ThreadState::Waiting)
+            .map(|t| t.wake_at)
+            .min()
+            .unwrap_or(u64::MAX);
+        let idle = Some(next) == hart.idle;
+        match self.thread(next).periodic {
+            // No slices between real-time threads, just the budget.
+            Some(p) => deadline = deadline.min(now + p.budget.saturating_sub(p.used)),
+            None if !idle => {
+                deadline = deadline.min(now + clint::micros_to_ticks(TIME_SLICE_MICROS));
+            }
+            None => {}
+        }
+        if deadline != u64::MAX {
+            hart.timer = clint::oneshot_at(deadline, tick);
+        }
+    }
+}
+
+// Wake one other hart that's sitting in its idle thread, if any, to come and
+// take a thread that was just made ready. SCHED_LOCK is held, so no hart
+// can switch threads under us.
+fn kick_idle_hart() {
+    let me = interrupt::hart_id();
+    for id in 0..MAX_HARTS {
+        let hart = hart_state(id);
+        if id == me || hart.kicked || hart.idle.is_none() || Some(hart.current) != hart.idle {
+            continue;
+        }
+        hart.kicked = true;
+        ipi::reschedule(id);
+        return;
+    }
+}
+
+/// Have this hart reschedule on its way out of the current interrupt.
+pub fn request_resched() {
+    this_hart().need_resched = true;
+}
+
+// Scheduler timer callback, from the timer interrupt.
+fn tick() {
+    let hart = this_hart();
+    // The one-shot is spent, don't cancel its slot later.
+    hart.timer = None;
+    hart.need_resched = true;
+}
+
+/// Switch threads if the slice or budget is over or a sleeper is due. Called by
+/// `trap_handler` after an interrupt, with interrupts still off.
+pub fn preempt() {
+    if this_hart().need_resched {
+        SCHED_LOCK.lock();
+        schedule();
+        SCHED_LOCK.unlock();
+    }
+}
+
+// Pick the next thread and switch to it. The caller has already set the
+// current thread's state; if it's still Running it goes to the back of its
+// priority's queue. Interrupts must be off and SCHED_LOCK held; it's still
+// held when this returns, on whichever hart the thread resumes on.
+fn schedule() {
+    let s = scheduler();
+    let hart_id = interrupt::hart_id();
+    let hart = hart_state(hart_id);
+    hart.need_resched = false;
+    hart.kicked = false;
+    let current = hart.current;
+    check_stack(s.thread(current));
+
+    let now = clint::mtime();
+    let ran = now - hart.switched_at;
+    s.thread(current).cpu_ticks += ran;
+    s.charge(current, ran);
+    hart.switched_at = now;
+
+    if s.thread(current).state == ThreadState::Running && Some(current) != hart.idle {
+        s.make_ready(current);
+    }
+    s.wake_sleepers(now);
+    let next = match s.pop_ready().or(hart.idle) {
+        Some(next) => next,
+        None => panic!("[THREAD]: nothing to run and no idle thread"),
+    };
+    s.thread(next).state = ThreadState::Running;
+    s.thread(next).hart = hart_id;
+    s.arm_timer(hart, next, now);
+    if next == current {
+        return;
+    }
+    hart.current = next;
+    s.thread(next).switches += 1;
+    // Kernel threads run on the kernel table, user threads on their own.
+    let satp = s.thread(next).satp;
+    if satp != s.thread(current).satp {
+        let kernel = paging::kernel_satp();
+        paging::activate(if satp == 0 { kernel } else { satp });
+    }
+    let old: *mut Context = &mut s.thread(current).context;
+    let new: *const Context = &s.thread(next).context;
+    unsafe {
+        switch_context(old, new);
+    }
+    finish_switch();
+}
+
+// Runs on the new thread right after every switch, still under SCHED_LOCK.
+fn finish_switch() {
+    let s = scheduler();
+    if let Some(dead) = this_hart().dead.take() {
+        let thread = s.thread(dead);
+        if !thread.stack.is_null() {
+            let _ = Alloc::free(thread.stack);
+            thread.stack = null_mut();
+        }
+        if thread.detached {
+            s.threads.remove(&dead);
+        }
+    }
+}
+
+fn check_stack(thread: &Thread) {
+    if !thread.stack.is_null() && unsafe { (thread.stack as *const u64).read() } != STACK_CANARY {
+        panic!(
+            "[THREAD]: stack overflow in {} ({})",
+            thread.name, thread.id.0
+        );
+    }
+}
+
+// Where a new thread's first switch_context returns to.
+extern "C" fn thread_start() -> ! {
+    finish_switch();
+    let current = this_hart().current;
+    let entry = scheduler().thread(current).entry.take();
+    SCHED_LOCK.unlock();
+    interrupt::enable_global();
+    if let Some(entry) = entry {
+        entry();
+    }
+    Thread::exit()
+}
+
+fn idle_loop() -> ! {
+    loop {
+        interrupt::wait_for_interrupt();
+    }
+}
+
+// A thread for code that's already running on its own stack: kmain, or a
+// secondary hart's boot code.
+fn adopt(name: &'static str, priority: u8) -> Box<Thread> {
+    Box::new(Thread {
+        id: ThreadId(0),
+        name,
+        state: ThreadState::Running,
+        priority,
+        context: Context::default(),
+        stack: null_mut(),
+        wake_at: 0,
+        joiners: Vec::new(),
+        entry: None,
+        cpu_ticks: 0,
+        switches: 1,
+        periodic: None,
+        hart: interrupt::hart_id(),
+        process: None,
+        satp: 0,
+        interrupted: false,
+        detached: false,
+    })
+}
+
+fn new_thread<F: FnOnce() + 'static>(
+    name: &'static str,
+    priority: u8,
+    f: F,
+) -> Result<Box<Thread>, ThreadError> {
+    let stack = Alloc::get(STACK_PAGES).ok_or(ThreadError::OutOfMemory)?;
+    unsafe {
+        (stack as *mut u64).write(STACK_CANARY);
+    }
+    let context = Context {
+        ra: thread_start as *const () as usize,
+        sp: stack as usize + STACK_SIZE,
+        ..Context::default()
+    };
+    Ok(Box::new(Thread {
+        id: ThreadId(0),
+        name,
+        state: ThreadState::Ready,
+        priority: priority.min(MAX_PRIORITY),
+        context,
+        stack,
+        wake_at: 0,
+        joiners: Vec::new(),
+        entry: Some(Box::new(f)),
+        cpu_ticks: 0,
+        switches: 0,
+        periodic: None,
+        hart: 0,
+        process: None,
+        satp: 0,
+        interrupted: false,
+        detached: false,
+    }))
+}
+
+// Give `thread` an id and hand it to the scheduler.
+fn add_thread(mut thread: Box<Thread>) -> ThreadId {
+    SCHED_LOCK.with(|| {
+        let s = scheduler();
+        let id = ThreadId(s.next_id);
+        s.next_id += 1;
+        thread.id = id;
+        s.threads.insert(id, thread);
+        id
+    })
+}
+
+// Add `thread` and make it ready, switching to it straight away if it
+// outranks the current thread.
+fn start(thread: Box<Thread>) -> ThreadId {
+    let priority = thread.priority;
+    let id = add_thread(thread);
+    SCHED_LOCK.with(|| {
+        let s = scheduler();
+        s.make_ready(id);
+        kick_idle_hart();
+        let current = this_hart().current;
+        if priority > s.thread(current).priority {
+            schedule();
+        }
+    });
+    id
+}
+
+impl Thread {
+    /// Adopt the code that's running now as thread 0 and start the idle
+    /// thread.
+    pub fn init() {
+        let kmain = adopt("kmain", DEFAULT_PRIORITY);
+        SCHED_LOCK.with(|| {
+            scheduler().threads.insert(ThreadId(0), kmain);
+            let hart = this_hart();
+            hart.current = ThreadId(0);
+            hart.switched_at = clint::mtime();
+        });
+        let name = IDLE_NAMES[interrupt::hart_id()];
+        let idle = match new_thread(name, 0, || idle_loop()) {
+            Ok(idle) => add_thread(idle),
+            Err(e) => panic!("[THREAD]: no idle thread: {:?}", e),
+        };
+        interrupt::without_interrupts(|| this_hart().idle = Some(idle));
+        let _ = console::register(Command {
+            name: "threads",
+            usage: "threads",
+            help: "list kernel threads and their CPU time",
+            handler: threads_command,
+        });
+    }
+
+    /// Make the code running on a secondary hart that hart's idle thread.
+    /// It carries on in `idle` once the hart is ready for interrupts.
+    pub fn init_hart() {
+        let idle = add_thread(adopt(IDLE_NAMES[interrupt::hart_id()], 0));
+        interrupt::without_interrupts(|| {
+            let hart = this_hart();
+            hart.current = idle;
+            hart.idle = Some(idle);
+            hart.switched_at = clint::mtime();
+        });
+    }
+
+    /// The end of a secondary hart's boot: sleep until there's work.
+    pub fn idle() -> ! {
+        idle_loop()
+    }
+
+    /// Start `f` on a thread of its own at `priority` (capped at
+    /// `MAX_PRIORITY`). If that's above the current thread's, it runs
+    /// straight away.
+    pub fn spawn<F: FnOnce() + 'static>(
+        name: &'static str,
+        priority: u8,
+        f: F,
+    ) -> Result<ThreadId, ThreadError> {
+        let thread = new_thread(name, priority, f)?;
+        Ok(start(thread))
+    }
+
+    /// Start a thread in `process` that runs user code from `entry` with
+    /// `sp` as its stack and `args` in a0 and a1. Use `Process::spawn_thread`,
+    /// which keeps count.
+    pub fn spawn_user(
+        name: &'static str,
+        process: Arc<Process>,
+        entry: usize,
+        sp: usize,
+        args: [usize; 2],
+    ) -> Result<ThreadId, ThreadError> {
+        let satp = process.space().lock().satp();
+        let mut thread = new_thread(name, DEFAULT_PRIORITY, move || {
+            trap::enter_user(entry, sp, args);
+        })?;
+        thread.satp = satp;
+        thread.process = Some(process);
+        Ok(start(thread))
+    }
+
+    /// The process the current thread belongs to, if it's a user thread.
+    pub fn process() -> Option<Arc<Process>> {
+        SCHED_LOCK.with(|| scheduler().thread(this_hart().current).process.clone())
+    }
+
+    /// The current thread's address space, if it's a user thread.
+    pub fn space() -> Option<Arc<Mutex<AddressSpace>>> {
+        Thread::process().map(|process| process.space().clone())
+    }
+
+    /// The current thread's capabilities, if it's a user thread.
+    pub fn caps() -> Option<Arc<Mutex<CapTable>>> {
+        Thread::process().map(|process| process.caps().clone())
+    }
+
+    /// Get `id` out of any interruptible wait it's in, or is about to go
+    /// into, for good.
+    pub fn interrupt(id: ThreadId) -> Result<(), ThreadError> {
+        SCHED_LOCK.with(|| {
+            let s = scheduler();
+            let thread = s.threads.get_mut(&id).ok_or(ThreadError::NoSuchThread)?;
+            thread.interrupted = true;
+            s.wake(id);
+            Ok(())
+        })
+    }
+
+    /// Whether the current thread has been interrupted.
+    pub fn interrupted() -> bool {
+        SCHED_LOCK.with(|| scheduler().thread(this_hart().current).interrupted)
+    }
+
+    /// Forget about `id` once it exits instead of waiting for a join.
+    pub fn detach(id: ThreadId) -> Result<(), ThreadError> {
+        SCHED_LOCK.with(|| {
+            let s = scheduler();
+            let thread = s.threads.get_mut(&id).ok_or(ThreadError::NoSuchThread)?;
+            if thread.state == ThreadState::Stopped {
+                s.threads.remove(&id);
+            } else {
+                thread.detached = true;
+            }
+            Ok(())
+        })
+    }
+
+    pub fn current() -> ThreadId {
+        interrupt::without_interrupts(|| this_hart().current)
+    }
+
+    /// Name of the thread running on `hart`, if it's running the scheduler.
+    pub fn running_on(hart: usize) -> Option<&'static str> {
+        if hart >= MAX_HARTS {
+            return None;
+        }
+        SCHED_LOCK.with(|| {
+            let current = hart_state(hart).current;
+            let thread = scheduler().threads.get(&current)?;
+            (thread.state == ThreadState::Running && thread.hart == hart).then_some(thread.name)
+        })
+    }
+
+    /// Go to the back of this priority's run queue.
+    pub fn yield_now() {
+        SCHED_LOCK.with(schedule);
+    }
+
+    /// Don't run again for at least `micros` microseconds, unless
+    /// interrupted.
+    pub fn sleep(micros: u64) {
+        let wake_at = clint::mtime() + clint::micros_to_ticks(micros);
+        SCHED_LOCK.with(|| {
+            let s = scheduler();
+            let current = this_hart().current;
+            let thread = s.thread(current);
+            if thread.interrupted {
+                return;
+            }
+            thread.state = ThreadState::Waiting;
+            thread.wake_at = wake_at;
+            schedule();
+        });
+    }
+
+    /// Move the current thread into the real-time class: `budget_micros` of
+    /// CPU every `period_micros`, the first period starting now. Refused if
+    /// the real-time threads together would want more than `MAX_RT_PERCENT`
+    /// of the CPU.
+    pub fn set_periodic(period_micros: u64, budget_micros: u64) -> Result<(), ThreadError> {
+        if budget_micros == 0 || budget_micros > period_micros {
+            return Err(ThreadError::InvalidPeriod);
+        }
+        let period = clint::micros_to_ticks(period_micros);
+        let budget = clint::micros_to_ticks(budget_micros);
+        SCHED_LOCK.with(|| {
+            let s = scheduler();
+            let current = this_hart().current;
+            if s.rt_load(current) + budget * 100 / period > MAX_RT_PERCENT {
+                return Err(ThreadError::Overloaded);
+            }
+            s.thread(current).periodic = Some(Periodic {
+                period,
+                budget,
+                release: clint::mtime(),
+                used: 0,
+                throttled: false,
+                misses: 0,
+                overruns: 0,
+            });
+            this_hart().need_resched = true;
+            Ok(())
+        })
+    }
+
+    /// Back to the priority class.
+    pub fn clear_periodic() {
+        SCHED_LOCK.with(|| {
+            let s = scheduler();
+            let current = this_hart().current;
+            s.thread(current).periodic = None;
+        });
+    }
+
+    /// Done with this period's work: sleep until the next period starts.
+    /// Finishing after the deadline counts as a miss. If whole periods went
+    /// by, they're skipped and the next one starts now.
+    pub fn wait_next_period() -> Result<(), ThreadError> {
+        SCHED_LOCK.with(|| {
+            let s = scheduler();
+            let current = this_hart().current;
+            let thread = s.thread(current);
+            let name = thread.name;
+            let p = thread.periodic.as_mut().ok_or(ThreadError::NotPeriodic)?;
+            let now = clint::mtime();
+            if now > p.deadline() {
+                p.misses += 1;
+                if (p.misses - 1).is_multiple_of(MISS_REPORT_INTERVAL) {
+                    println!(
+                        "[EDF]: {} missed its deadline by {} us ({} so far)",
+                        name,
+                        clint::ticks_to_micros(now - p.deadline()),
+                        p.misses
+                    );
+                }
+            }
+            p.release += p.period;
+            if p.deadline() <= now {
+                p.release = now;
+            }
+            p.used = 0;
+            let release = p.release;
+            if release > now {
+                thread.state = ThreadState::Waiting;
+                thread.wake_at = release;
+            }
+            schedule();
+            Ok(())
+        })
+    }
+
+    /// Stop the current thread. Its stack goes back to the page allocator;
+    /// the rest stays around until someone joins it.
+    pub fn exit() -> ! {
+        interrupt::disable_global();
+        SCHED_LOCK.lock();
+        let s = scheduler();
+        let hart = this_hart();
+        let current = hart.current;
+        let joiners = core::mem::take(&mut s.thread(current).joiners);
+        for joiner in joiners {
+            s.wake(joiner);
+        }
+        s.thread(current).state = ThreadState::Stopped;
+        hart.dead = Some(current);
+        schedule();
+        unreachable!("[THREAD]: {} ran after exiting", current.0);
+    }
+
+    /// Wait for `id` to exit and forget about it.
+    pub fn join(id: ThreadId) -> Result<(), ThreadError> {
+        SCHED_LOCK.with(|| {
+            let s = scheduler();
+            let current = this_hart().current;
+            if id == current {
+                return Err(ThreadError::JoinSelf);
+            }
+            loop {
+                let target = s.threads.get_mut(&id).ok_or(ThreadError::NoSuchThread)?;
+                if target.state == ThreadState::Stopped {
+                    s.threads.remove(&id);
+                    return Ok(());
+                }
+                target.joiners.push(current);
+                let thread = s.thread(current);
+                thread.state = ThreadState::Waiting;
+                thread.wake_at = u64::MAX;
+                schedule();
+                // Woken some other way, e.g. `interrupt`, we're still on
+                // the list; go round again without being on it twice.
+                if let Some(target) = s.threads.get_mut(&id) {
+                    target.joiners.retain(|&joiner| joiner != current);
+                }
+            }
+        })
+    }
+
+    pub fn id(&self) -> ThreadId {
+        self.id
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    /// Time spent running, in microseconds, not counting the slice in
+    /// progress.
+    pub fn cpu_micros(&self) -> u64 {
+        clint::ticks_to_micros(self.cpu_ticks)
+    }
+
+    /// Period, budget and deadline misses of a real-time thread.
+    pub fn periodic_stats(id: ThreadId) -> Option<PeriodicStats> {
+        SCHED_LOCK.with(|| {
+            let p = scheduler().threads.get(&id)?.periodic?;
+            Some(PeriodicStats {
+                period: clint::ticks_to_micros(p.period),
+                budget: clint::ticks_to_micros(p.budget),
+                misses: p.misses,
+                overruns: p.overruns,
+            })
+        })
+    }
+
+    /// How many times this thread has been switched to.
+    pub fn switches(&self) -> usize {
+        self.switches
+    }
+
+    /// CPU time of `id` in microseconds, including the current slice if
+    /// it's running.
+    pub fn cpu_time(id: ThreadId) -> Option<u64> {
+        SCHED_LOCK.with(|| {
+            let thread = scheduler().threads.get(&id)?;
+            let mut ticks = thread.cpu_ticks;
+            if thread.state == ThreadState::Running {
+                ticks += clint::mtime() - hart_state(thread.hart).switched_at;
+            }
+            Some(clint::ticks_to_micros(ticks))
+        })
+    }
+
+    /// Hart the thread is running on, or last ran on.
+    pub fn hart(&self) -> usize {
+        self.hart
+    }
+
+    /// Call `f` for every thread, stopped ones included. `f` runs with the
+    /// scheduler locked, so it mustn't call back into `Thread`.
+    pub fn for_each<F: FnMut(&Thread)>(mut f: F) {
+        SCHED_LOCK.with(|| {
+            for thread in scheduler().threads.values() {
+                f(thread);
+            }
+        });
+    }
+}
+
+/// Threads blocked until something happens, for building `Mutex` and
+/// friends. The queue is guarded by the scheduler lock, which makes checking
+/// the condition and going to sleep one step: whoever makes the condition
+/// true and then calls `notify_*` can't get in between the two.
+pub struct WaitQueue {
+    waiters: UnsafeCell<VecDeque<ThreadId>>,
+}
+
+unsafe impl Sync for WaitQueue {}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        Self {
+            waiters: UnsafeCell::new(VecDeque::new()),
+        }
+    }
+
+    // Only with SCHED_LOCK held.
+    fn waiters(&self) -> *mut VecDeque<ThreadId> {
+        self.waiters.get()
+    }
+
+    // Sleep on the queue, with SCHED_LOCK held. A notify takes us off it,
+    // but `Thread::interrupt` doesn't, and a notify that found us there
+    // later would be spent on a thread that isn't waiting for it.
+    fn sleep(&self, current: ThreadId) {
+        unsafe { (*self.waiters()).push_back(current) };
+        let thread = scheduler().thread(current);
+        thread.state = ThreadState::Waiting;
+        thread.wake_at = u64::MAX;
+        schedule();
+        unsafe { (*self.waiters()).retain(|&id| id != current) };
+    }
+
+    /// Sleep until `condition` is true. It runs with the scheduler locked and
+    /// interrupts off, so it should be no more than a look at some atomics.
+    pub fn wait_until<F: FnMut() -> bool>(&self, mut condition: F) {
+        SCHED_LOCK.with(|| {
+            while !condition() {
+                self.sleep(this_hart().current);
+            }
+        });
+    }
+
+    /// Like `wait_until`, but gives up if the thread is interrupted. Returns
+    /// whether `condition` came true.
+    pub fn wait_interruptible<F: FnMut() -> bool>(&self, mut condition: F) -> bool {
+        SCHED_LOCK.with(|| loop {
+            if condition() {
+                return true;
+            }
+            let current = this_hart().current;
+            if scheduler().thread(current).interrupted {
+                return false;
+            }
+            self.sleep(current);
+        })
+    }
+
+    /// Wake the longest waiter, if there is one.
+    pub fn notify_one(&self) -> bool {
+        SCHED_LOCK.with(|| {
+            let s = scheduler();
+            while let Some(id) = unsafe { (*self.waiters()).pop_front() } {
+                if s.wake(id) {
+                    return true;
+                }
+            }
+            false
+        })
+    }
+
+    /// Wake every waiter, returning how many there were.
+    pub fn notify_all(&self) -> usize {
+        SCHED_LOCK.with(|| {
+            let s = scheduler();
+            let mut woken = 0;
+            while let Some(id) = unsafe { (*self.waiters()).pop_front() } {
+                if s.wake(id) {
+                    woken += 1;
+                }
+            }
+            woken
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        SCHED_LOCK.with(|| unsafe { (*self.waiters()).is_empty() })
+    }
+}
+
+fn threads_command(_args: &[&str]) {
+    println!(
+        "{:>4} {:<12} {:<8} {:<5} {:>4} {:>4} {:>12} {:>6} {:>8}",
+        "ID", "NAME", "STATE", "CLASS", "PRIO", "HART", "CPU ms", "CPU %", "SWITCHES"
+    );
+    let current = Thread::current();
+    let uptime = clint::uptime_micros().max(1);
+    // Copy what we need out first: printing and the lookups below take
+    // locks of their own.
+    let mut threads = Vec::new();
+    Thread::for_each(|thread| {
+        threads.push((
+            thread.id,
+            thread.name,
+            thread.state,
+            thread.periodic.is_some(),
+            thread.priority,
+            thread.hart,
+            thread.switches,
+        ));
+    });
+    for (id, name, state, realtime, priority, hart, switches) in threads {
+        let state_name = match state {
+            ThreadState::Stopped => "stopped",
+            ThreadState::Waiting => "waiting",
+            ThreadState::Running => "running",
+            ThreadState::Ready => "ready",
+        };
+        let micros = Thread::cpu_time(id).unwrap_or(0);
+        let class = if realtime { "rt" } else { "prio" };
+        let on = if state == ThreadState::Running {
+            hart.to_string()
+        } else {
+            String::from("-")
+        };
+        println!(
+            "{:>4} {:<12} {:<8} {:<5} {:>4} {:>4} {:>12} {:>6} {:>8}{}",
+            id.0,
+            name,
+            state_name,
+            class,
+            priority,
+            on,
+            micros / 1000,
+            micros * 100 / uptime,
+            switches,
+            if id == current { " *" } else { "" }
+        );
+        if let Some(stats) = Thread::periodic_stats(id) {
+            println!(
+                "     period {} us, budget {} us, {} deadline misses, {} overruns",
+                stats.period, stats.budget, stats.misses, stats.overruns
+            );
+        }
+    }
 }
@@ -0,0 +1,279 @@
+//! Rust side of the trap path. `asm_trap_vector` in `asm/trap.S` saves the
+//! interrupted context into a `TrapFrame` and calls `trap_handler`.
+//!
+//! Traps from user code arrive the same way, on the thread's kernel stack.
+//! An `ecall` is a syscall; any other exception kills the thread's process
+//! rather than the kernel.
+use crate::print;
+use crate::println;
+use crate::util::interrupt;
+use crate::util::process;
+use crate::util::syscall;
+use crate::util::thread::{self, Thread};
+use core::arch::asm;
+
+// scause's top bit separates interrupts from exceptions.
+const SCAUSE_INTERRUPT: usize = 1 << 63;
+
+// sstatus.FS, the floating point unit state.
+pub const SSTATUS_FS: usize = 0b11 << 13;
+const SSTATUS_FS_INITIAL: usize = 0b01 << 13;
+// sstatus.SPP: the trap came from S-mode (set) or U-mode (clear).
+pub const SSTATUS_SPP: usize = 1 << 8;
+// sstatus.SPIE: interrupts go back on with sret.
+const SSTATUS_SPIE: usize = 1 << 5;
+
+extern "C" {
+    fn user_entry(frame: *const TrapFrame) -> !;
+}
+
+const REGISTER_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Everything `asm_trap_vector` saves. Field order and size are shared with
+/// the assembly, so don't touch one without the other.
+#[repr(C)]
+pub struct TrapFrame {
+    pub regs: [usize; 32],
+    pub fregs: [u64; 32],
+    pub fcsr: usize,
+    pub sepc: usize,
+    pub sstatus: usize,
+}
+
+impl TrapFrame {
+    pub fn reg(&self, index: usize) -> usize {
+        self.regs[index]
+    }
+
+    pub fn set_reg(&mut self, index: usize, value: usize) {
+        // x0 is hardwired, writing it would be a lie.
+        if index != 0 {
+            self.regs[index] = value;
+        }
+    }
+
+    /// Whether the trap came from user code.
+    pub fn is_user(&self) -> bool {
+        self.sstatus & SSTATUS_SPP == 0
+    }
+
+    /// Step sepc over the instruction that trapped, handling the compressed
+    /// (16 bit) encodings.
+    pub fn skip_instruction(&mut self) {
+        let low = unsafe { (self.sepc as *const u16).read_volatile() };
+        self.sepc += if low & 0b11 == 0b11 { 4 } else { 2 };
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exception {
+    InstructionMisaligned = 0,
+    InstructionAccessFault = 1,
+    IllegalInstruction = 2,
+    Breakpoint = 3,
+    LoadMisaligned = 4,
+    LoadAccessFault = 5,
+    StoreMisaligned = 6,
+    StoreAccessFault = 7,
+    UserEcall = 8,
+    SupervisorEcall = 9,
+    MachineEcall = 11,
+    InstructionPageFault = 12,
+    LoadPageFault = 13,
+    StorePageFault = 15,
+}
+
+impl Exception {
+    pub fn from_code(code: usize) -> Option<Exception> {
+        Some(match code {
+            0 => Exception::InstructionMisaligned,
+            1 => Exception::InstructionAccessFault,
+            2 => Exception::IllegalInstruction,
+            3 => Exception::Breakpoint,
+            4 => Exception::LoadMisaligned,
+            5 => Exception::LoadAccessFault,
+            6 => Exception::StoreMisaligned,
+            7 => Exception::StoreAccessFault,
+            8 => Exception::UserEcall,
+            9 => Exception::SupervisorEcall,
+            11 => Exception::MachineEcall,
+            12 => Exception::InstructionPageFault,
+            13 => Exception::LoadPageFault,
+            15 => Exception::StorePageFault,
+            _ => return None,
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Exception::InstructionMisaligned => "instruction address misaligned",
+            Exception::InstructionAccessFault => "instruction access fault",
+            Exception::IllegalInstruction => "illegal instruction",
+            Exception::Breakpoint => "breakpoint",
+            Exception::LoadMisaligned => "load address misaligned",
+            Exception::LoadAccessFault => "load access fault",
+            Exception::StoreMisaligned => "store/AMO address misaligned",
+            Exception::StoreAccessFault => "store/AMO access fault",
+            Exception::UserEcall => "environment call from U-mode",
+            Exception::SupervisorEcall => "environment call from S-mode",
+            Exception::MachineEcall => "environment call from M-mode",
+            Exception::InstructionPageFault => "instruction page fault",
+            Exception::LoadPageFault => "load page fault",
+            Exception::StorePageFault => "store/AMO page fault",
+        }
+    }
+
+    // What stval holds for this exception, for the report.
+    fn tval_meaning(&self) -> &'static str {
+        match self {
+            Exception::IllegalInstruction => "instruction",
+            Exception::Breakpoint
+            | Exception::UserEcall
+            | Exception::SupervisorEcall
+            | Exception::MachineEcall => "stval",
+            _ => "address",
+        }
+    }
+}
+
+#[cfg(not(test))]
+#[no_mangle]
+extern "C" fn trap_handler(frame: &mut TrapFrame, scause: usize, stval: usize) {
+    let code = scause & !SCAUSE_INTERRUPT;
+    if scause & SCAUSE_INTERRUPT != 0 {
+        interrupt::handle(code, frame);
+        // The timer may have ended this thread's slice.
+        thread::preempt();
+        if frame.is_user() {
+            process::check();
+        }
+        return;
+    }
+
+    match Exception::from_code(code) {
+        Some(Exception::UserEcall) => {
+            // ecall has no compressed form.
+            frame.sepc += 4;
+            // A syscall may take a while or sleep, let interrupts in.
+            interrupt::enable_global();
+            syscall::dispatch(frame);
+            interrupt::disable_global();
+            thread::preempt();
+            process::check();
+        }
+        Some(Exception::Breakpoint) if !frame.is_user() => {
+            println!("[TRAP]: breakpoint at {:#X}", frame.sepc);
+            frame.skip_instruction();
+        }
+        Some(exception) if frame.is_user() => kill(frame, exception, stval),
+        Some(exception) => fatal(frame, exception, stval),
+        None => {
+            report(frame, scause, stval);
+            panic!("Unknown exception {}", code);
+        }
+    }
+}
+
+// A user thread did something it shouldn't. Only its process has to go.
+fn kill(frame: &TrapFrame, exception: Exception, stval: usize) -> ! {
+    report(frame, exception as usize, stval);
+    println!(
+        "[TRAP]: killing the process of user thread {} ({})",
+        Thread::current().0,
+        exception.name()
+    );
+    process::fault(exception)
+}
+
+fn fatal(frame: &TrapFrame, exception: Exception, stval: usize) -> ! {
+    report(frame, exception as usize, stval);
+    panic!("Unhandled {} at {:#X}", exception.name(), frame.sepc);
+}
+
+/// Leave the kernel for user code at `entry`, with `sp` as its stack and
+/// `args` in a0 and a1. The kernel stack we're on becomes the thread's way
+/// back in: traps from user code land on it from here down.
+pub fn enter_user(entry: usize, sp: usize, args: [usize; 2]) -> ! {
+    interrupt::disable_global();
+    let sstatus: usize;
+    unsafe {
+        asm!("csrr {0}, sstatus", out(reg) sstatus);
+    }
+    let mut frame = TrapFrame {
+        regs: [0; 32],
+        fregs: [0; 32],
+        fcsr: 0,
+        sepc: entry,
+        // U-mode with interrupts on and a clean FPU.
+        sstatus: (sstatus & !(SSTATUS_SPP | SSTATUS_FS)) | SSTATUS_SPIE | SSTATUS_FS_INITIAL,
+    };
+    frame.regs[2] = sp;
+    frame.regs[10] = args[0];
+    frame.regs[11] = args[1];
+    unsafe { user_entry(&frame) }
+}
+
+/// Print everything we know about a trap.
+pub fn report(frame: &TrapFrame, scause: usize, stval: usize) {
+    println!();
+    match Exception::from_code(scause) {
+        Some(exception) => {
+            println!("[TRAP]: {} (scause {})", exception.name(), scause);
+            println!("  sepc    {:#018X}", frame.sepc);
+            println!("  {:<7} {:#018X}", exception.tval_meaning(), stval);
+            if exception == Exception::IllegalInstruction {
+                decode_illegal(frame, stval);
+            }
+        }
+        None => {
+            println!("[TRAP]: scause {:#X}", scause);
+            println!("  sepc    {:#018X}", frame.sepc);
+            println!("  stval   {:#018X}", stval);
+        }
+    }
+    println!(
+        "  sstatus {:#018X} (SPP {}, FS {})",
+        frame.sstatus,
+        (frame.sstatus >> 8) & 0b1,
+        (frame.sstatus >> 13) & 0b11
+    );
+    for row in 0..8 {
+        for col in 0..4 {
+            let i = row * 4 + col;
+            print!("  {:>4} {:#018X}", REGISTER_NAMES[i], frame.regs[i]);
+        }
+        println!();
+    }
+}
+
+// Say a little more about why an instruction was illegal. QEMU reports the
+// offending bits in stval, but if it doesn't we read them from sepc.
+fn decode_illegal(frame: &TrapFrame, stval: usize) {
+    let inst = if stval != 0 {
+        stval as u32
+    } else {
+        unsafe { (frame.sepc as *const u32).read_unaligned() }
+    };
+    let opcode = inst & 0x7F;
+    if inst & 0b11 != 0b11 {
+        println!("  compressed instruction {:#06X}", inst as u16);
+        return;
+    }
+    let hint = match opcode {
+        0b111_0011 => "SYSTEM (CSR access from the wrong privilege level?)",
+        0b000_0111 | 0b010_0111 | 0b101_0011 | 0b100_0011 => {
+            if frame.sstatus & SSTATUS_FS == 0 {
+                "floating point with sstatus.FS off"
+            } else {
+                "floating point"
+            }
+        }
+        0b010_1111 => "atomic",
+        _ => "unknown opcode",
+    };
+    println!("  opcode  {:#09b}: {}", opcode, hint);
+}